@@ -0,0 +1,27 @@
+use std::process::Command;
+
+const LAYOUT_FEATURES: &[&str] = &["loader"];
+
+fn main() {
+    // Plugins use the server's Rust types through their own copy of this crate, so both must be built by the same compiler
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let version = Command::new(rustc)
+        .arg("--version")
+        .output()
+        .ok()
+        .and_then(|o| String::from_utf8(o.stdout).ok())
+        .map(|v| v.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    println!("cargo:rustc-env=VOXA_RUSTC_VERSION={version}");
+
+    // Features that change the layout of types plugins use, `loader` adds a field to `Server`.
+    // Other features only add code, plugins don't need to enable them.
+    let features: Vec<&str> = LAYOUT_FEATURES
+        .iter()
+        .copied()
+        .filter(|f| std::env::var_os(format!("CARGO_FEATURE_{}", f.to_uppercase())).is_some())
+        .collect();
+    println!("cargo:rustc-env=VOXA_FEATURES={}", features.join(","));
+    println!("cargo:rerun-if-env-changed=RUSTC");
+}
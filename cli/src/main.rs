@@ -1,6 +1,82 @@
-use std::path::PathBuf;
+use std::{io::BufRead, path::PathBuf, sync::Arc};
 
-use voxa_server::{ServerConfig, utils::vfs};
+use voxa_server::{Server, ServerConfig, logger, types::message::ServerMessage, utils::vfs};
+
+logger!(LOGGER "Console");
+
+/// Manage plugins from stdin while the server runs
+fn console(server: Arc<Server>) {
+    for line in std::io::stdin().lock().lines() {
+        let Ok(line) = line else {
+            return;
+        };
+
+        let mut words = line.split_whitespace();
+        let res = match (words.next(), words.next()) {
+            (None, _) => continue,
+            (Some("plugins"), None) => {
+                for p in server.plugin_list() {
+                    LOGGER.info(format!(
+                        "{} {} (priority {}{})",
+                        p.name,
+                        p.version.as_deref().unwrap_or("built-in"),
+                        p.priority,
+                        p.file.map(|f| format!(", {f}")).unwrap_or_default()
+                    ));
+                }
+                Ok(())
+            }
+            (Some("load"), Some(file)) => server
+                .load_plugin_file(file)
+                .map(|name| LOGGER.info(format!("Loaded plugin {name}"))),
+            (Some("unload"), Some(name)) => server.unload_plugin(name),
+            (Some("reload"), Some(name)) => server.reload_plugin(name),
+            (Some("bots"), None) => server.db.get_bots().map_err(Into::into).map(|bots| {
+                for b in bots {
+                    let permissions: Vec<String> =
+                        b.permissions.iter().map(|p| p.to_string()).collect();
+                    LOGGER.info(format!("{} ({}): {}", b.name, b.id, permissions.join(", ")));
+                }
+            }),
+            (Some("bot"), Some("create")) => {
+                match (words.next(), words.map(str::parse).collect()) {
+                    (Some(name), Ok(permissions)) => {
+                        // Printed rather than logged, to keep it out of the log files
+                        server
+                            .create_bot(name, permissions)
+                            .map(|(_, token)| println!("Token of bot {name}: {token}"))
+                    }
+                    (None, _) => {
+                        LOGGER.warn("Usage: bot create <name> [permission...]");
+                        Ok(())
+                    }
+                    (_, Err(e)) => {
+                        LOGGER.warn(format!("Invalid permission: {e}"));
+                        Ok(())
+                    }
+                }
+            }
+            (Some("bot"), Some("delete")) => match words.next() {
+                Some(name) => server.delete_bot(name),
+                None => {
+                    LOGGER.warn("Usage: bot delete <name>");
+                    Ok(())
+                }
+            },
+            _ => {
+                LOGGER.warn(
+                    "Commands: plugins, load <file>, unload <name>, reload <name>, \
+                    bots, bot create <name> [permission...], bot delete <name>",
+                );
+                Ok(())
+            }
+        };
+
+        if let Err(e) = res {
+            LOGGER.error(format!("{e:#}"));
+        }
+    }
+}
 
 fn main() -> voxa_server::Result<()> {
     let root = PathBuf::from("");
@@ -9,6 +85,23 @@ fn main() -> voxa_server::Result<()> {
     } else {
         vfs::read_config(&root.join("config.json"))?
     };
-    config.build(&root).run()?;
+    let server = config.build(&root);
+
+    // Stop gracefully on SIGINT/SIGTERM
+    ctrlc::set_handler({
+        let server = server.clone();
+        move || {
+            server.shutdown(Some(ServerMessage::TempMessage {
+                message: "The server is shutting down".to_string(),
+            }))
+        }
+    })?;
+
+    std::thread::spawn({
+        let server = server.clone();
+        move || console(server)
+    });
+
+    server.run()?;
     Ok(())
 }
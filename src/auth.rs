@@ -1,9 +1,17 @@
 use std::sync::Arc;
 
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
 use serde::Deserialize;
 
-use crate::{ErrorContext, logger};
-use crate::{Server, utils::client::Client};
+use crate::logger;
+use crate::{
+    Server,
+    types::data::{BotInfo, Permission},
+    utils::{client::Client, logger::redact_secrets},
+};
+
+/// Prefix of bot tokens, which are checked locally instead of by the Voxa cloud
+pub const BOT_TOKEN_PREFIX: &str = "voxa_bot_";
 
 logger!(LOGGER "Auth");
 
@@ -12,15 +20,158 @@ struct AuthApiRes {
     user_id: String,
 }
 
+/// Who a token belongs to
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub id: String,
+    /// Permissions of a bot account, `None` for users
+    pub bot: Option<Vec<Permission>>,
+}
+
+/// Authenticate a client with its token, returns its id
 pub fn auth(server: &Arc<Server>, client: &mut Client, token: &str) -> crate::Result<String> {
-    let mut res = ureq::get(format!(
-        "https://vxchat.netlify.app/api/auth?token={token}&key={}",
-        server.config.server_key
-    ))
-    .call()
-    .context("Failed to authenticate")?;
+    let identity = verify(server, token)?;
+    match identity.bot {
+        Some(permissions) => client.set_bot(&identity.id, permissions),
+        None => client.set_uuid(&identity.id),
+    }
+    Ok(identity.id)
+}
+
+/// Find who a token belongs to, bot tokens are checked locally and the others by the Voxa cloud
+pub fn verify(server: &Server, token: &str) -> crate::Result<Identity> {
+    if token.starts_with(BOT_TOKEN_PREFIX) {
+        return verify_bot(server, token);
+    }
+
+    let key = &server.config.server_key;
+    // The token and key are kept out of the error, which gets logged and sent to the client
+    let mut res = ureq::get("https://vxchat.netlify.app/api/auth")
+        .query("token", token)
+        .query("key", key)
+        .call()
+        .map_err(|e| match e {
+            ureq::Error::StatusCode(status) => {
+                anyhow::anyhow!("Failed to authenticate: status {status}")
+            }
+            e => anyhow::anyhow!(
+                "Failed to authenticate: {}",
+                redact_secrets(&e.to_string(), &[token, key])
+            ),
+        })?;
     let api_res: AuthApiRes = serde_json::from_str(&res.body_mut().read_to_string()?)?;
-    client.set_uuid(&api_res.user_id);
     LOGGER.info(format!("{} successfully authenticated", api_res.user_id));
-    Ok(api_res.user_id)
+    Ok(Identity {
+        id: api_res.user_id,
+        bot: None,
+    })
+}
+
+fn verify_bot(server: &Server, token: &str) -> crate::Result<Identity> {
+    let Some(bot) = server.db.get_bot_by_token_hash(&hash_token(token))? else {
+        anyhow::bail!("Failed to authenticate: unknown bot token");
+    };
+
+    LOGGER.info(format!("Bot {} successfully authenticated", bot.name));
+    Ok(Identity {
+        id: bot.id,
+        bot: Some(bot.permissions),
+    })
+}
+
+/// Hex encoded SHA-256 of a bot token, as stored in the database
+pub(crate) fn hash_token(token: &str) -> String {
+    ring::digest::digest(&ring::digest::SHA256, token.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Compare a presented token with the expected one. Both are hashed first,
+/// so the comparison doesn't depend on how much of the token matched.
+pub(crate) fn tokens_match(presented: &str, expected: &str) -> bool {
+    hash_token(presented) == hash_token(expected)
+}
+
+fn valid_bot_name(name: &str) -> bool {
+    (1..=32).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+impl Server {
+    /// Whether `client` was granted `permission`
+    pub fn has_permission(&self, client: &Client, permission: Permission) -> bool {
+        if let Some(permissions) = client.bot_permissions() {
+            return permissions.contains(&permission);
+        }
+
+        match client.get_uuid() {
+            Ok(uuid) if self.config.admins.contains(&uuid) => true,
+            Ok(_) => self.config.permissions.contains(&permission),
+            Err(_) => false,
+        }
+    }
+
+    /// Create a bot account, returns it with its token, which can't be recovered later
+    pub fn create_bot(
+        &self,
+        name: &str,
+        mut permissions: Vec<Permission>,
+    ) -> crate::Result<(BotInfo, String)> {
+        if !valid_bot_name(name) {
+            anyhow::bail!("Bot names must be 1 to 32 letters, digits, `-` or `_`");
+        }
+        if self.db.get_bot_by_name(name)?.is_some() {
+            anyhow::bail!("Bot {name} already exists");
+        }
+        permissions.sort_by_key(|p| Permission::ALL.iter().position(|a| a == p));
+        permissions.dedup();
+
+        let token = format!(
+            "{BOT_TOKEN_PREFIX}{}",
+            URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>())
+        );
+        let bot = BotInfo {
+            // Not derived from the name, a recreated bot doesn't take over the old one's messages
+            id: format!("bot:{:016x}", rand::random::<u64>()),
+            name: name.to_string(),
+            permissions,
+            created: chrono::Utc::now().timestamp(),
+        };
+        self.db.insert_bot(&bot, &hash_token(&token))?;
+
+        LOGGER.info(format!("Created bot {name}"));
+        Ok((bot, token))
+    }
+
+    /// Delete a bot account and disconnect its connections
+    pub fn delete_bot(&self, name: &str) -> crate::Result<()> {
+        let Some(bot) = self.db.get_bot_by_name(name)? else {
+            anyhow::bail!("Bot {name} does not exist");
+        };
+        self.db.delete_bot(name)?;
+
+        let connections: Vec<Client> = {
+            let mut clients = self.clients.lock().unwrap();
+            let connections = clients
+                .iter()
+                .filter(|c| c.get_uuid().is_ok_and(|u| u == bot.id))
+                .cloned()
+                .collect();
+            clients.retain(|c| !c.get_uuid().is_ok_and(|u| u == bot.id));
+            connections
+        };
+        for client in connections {
+            LOGGER.extract(
+                client.disconnect(1008, "Bot account deleted"),
+                "Failed to disconnect bot",
+            );
+        }
+
+        LOGGER.info(format!("Deleted bot {name}"));
+        Ok(())
+    }
 }
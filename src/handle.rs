@@ -0,0 +1,190 @@
+//! Stable API for plugins to act on the server
+
+use std::{sync::Arc, time::Duration};
+
+use crate::{
+    Server,
+    types::{
+        data::{Message, OnlineUser},
+        message::ServerMessage,
+    },
+    utils::{
+        commands::{Command, CommandContext, CommandResult},
+        plugin::catch_panic,
+        scheduler::TaskId,
+    },
+};
+
+/// A plugin's access to the server, get one with [`Server::handle`].
+///
+/// Keeps the server alive, so plugins should drop it in `on_shutdown`.
+#[derive(Clone)]
+pub struct ServerHandle {
+    server: Arc<Server>,
+    plugin: String,
+}
+
+impl Server {
+    /// Get a handle acting on behalf of the plugin named `plugin`
+    pub fn handle(self: &Arc<Self>, plugin: &str) -> ServerHandle {
+        ServerHandle {
+            server: self.clone(),
+            plugin: plugin.to_string(),
+        }
+    }
+}
+
+impl ServerHandle {
+    crate::logger!(LOGGER "Server Handle");
+
+    /// Name of the plugin the handle acts for
+    pub fn plugin(&self) -> &str {
+        &self.plugin
+    }
+
+    pub fn server(&self) -> &Arc<Server> {
+        &self.server
+    }
+
+    /// Send a message to every connected client
+    pub fn broadcast(&self, msg: ServerMessage) {
+        self.server.broadcast(msg);
+    }
+
+    /// Send an event of this plugin to every connected client
+    pub fn broadcast_event<T: serde::Serialize>(&self, event: &str, data: T) -> crate::Result<()> {
+        self.server
+            .broadcast_plugin_event(&self.plugin, event, data)
+    }
+
+    /// Send a message to every connection of the user `uuid`, returns how many received it
+    pub fn send_to_user(&self, uuid: &str, msg: ServerMessage) -> usize {
+        let clients: Vec<_> = self
+            .server
+            .clients
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|c| c.get_uuid().is_ok_and(|u| u == uuid))
+            .cloned()
+            .collect();
+
+        clients
+            .iter()
+            .filter(|c| {
+                Self::LOGGER
+                    .extract(c.send(&msg), format!("Failed to send to {uuid}"))
+                    .is_some()
+            })
+            .count()
+    }
+
+    /// The authenticated users with at least one connection, sorted by id
+    pub fn online_users(&self) -> Vec<OnlineUser> {
+        let mut users: Vec<OnlineUser> = self
+            .server
+            .clients
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|c| {
+                Some(OnlineUser {
+                    id: c.get_uuid().ok()?,
+                    bot: c.is_bot(),
+                })
+            })
+            .collect();
+        users.sort();
+        users.dedup();
+        users
+    }
+
+    /// Post a message to a channel as `plugin:<plugin>/<author>`, a bot identity of the plugin.
+    /// The message is flagged as sent by a bot.
+    ///
+    /// Clients receive `MessageCreate` and plugins get `on_message_created` like for user messages.
+    pub fn post_message(
+        &self,
+        channel_id: &str,
+        author: &str,
+        contents: &str,
+    ) -> crate::Result<Message> {
+        let author = format!("plugin:{}/{author}", self.plugin);
+        self.server.post_message(channel_id, &author, contents)
+    }
+
+    /// Up to `limit` messages of a channel older than `before` (or the newest ones), oldest first
+    pub fn history(
+        &self,
+        channel_id: &str,
+        before: Option<usize>,
+        limit: usize,
+    ) -> crate::Result<Vec<Message>> {
+        Ok(self
+            .server
+            .db
+            .get_channel_messages(channel_id, before, limit)?)
+    }
+
+    /// Register a chat command, failing if the name is taken. Its argument types
+    /// are checked before `f` runs, and the command is removed when the plugin is.
+    pub fn register_command<F>(&self, command: Command, f: F) -> crate::Result<()>
+    where
+        F: Fn(&CommandContext) -> CommandResult + Send + Sync + 'static,
+    {
+        self.server
+            .commands
+            .register(Some(&self.plugin), command, f)?;
+        self.broadcast(ServerMessage::Commands(self.server.commands.list()));
+        Ok(())
+    }
+
+    /// Remove a chat command of this plugin, returns whether it was registered
+    pub fn unregister_command(&self, name: &str) -> bool {
+        let removed = self
+            .server
+            .commands
+            .unregister(Some(&self.plugin), name)
+            .is_some();
+        if removed {
+            self.broadcast(ServerMessage::Commands(self.server.commands.list()));
+        }
+        removed
+    }
+
+    /// Run `f` once after `delay`
+    pub fn schedule<F>(&self, delay: Duration, f: F) -> TaskId
+    where
+        F: FnOnce(&ServerHandle) + Send + 'static,
+    {
+        let mut f = Some(f);
+        self.schedule_task(delay, None, move |h| {
+            if let Some(f) = f.take() {
+                f(h);
+            }
+        })
+    }
+
+    /// Run `f` every `interval`, starting one `interval` from now
+    pub fn schedule_every<F>(&self, interval: Duration, f: F) -> TaskId
+    where
+        F: FnMut(&ServerHandle) + Send + 'static,
+    {
+        self.schedule_task(interval, Some(interval), f)
+    }
+
+    /// Cancel a task scheduled by this plugin, returns whether it was still scheduled
+    pub fn cancel(&self, task: TaskId) -> bool {
+        self.server.scheduler.cancel(&self.plugin, task)
+    }
+
+    fn schedule_task<F>(&self, delay: Duration, interval: Option<Duration>, mut f: F) -> TaskId
+    where
+        F: FnMut(&ServerHandle) + Send + 'static,
+    {
+        let task = move |h: &ServerHandle| catch_panic(|| f(h));
+        self.server
+            .scheduler
+            .add(&self.plugin, delay, interval, Box::new(task))
+    }
+}
@@ -0,0 +1,107 @@
+//! Read-only REST API under `/api`, authenticated with `Authorization: Bearer <token>`
+
+use std::{net::IpAddr, sync::Arc};
+
+use serde::Serialize;
+
+use crate::{
+    Server, auth,
+    http::{LOGGER, Response},
+    utils::client::handshake::HttpRequest,
+};
+
+/// Messages returned when `limit` is not given
+const DEFAULT_LIMIT: usize = 50;
+/// Most messages returned per request
+const MAX_LIMIT: usize = 100;
+
+/// Only a bare status, the health check is public
+#[derive(Serialize)]
+struct Health {
+    status: &'static str,
+}
+
+pub fn route(
+    server: &Arc<Server>,
+    request: &HttpRequest,
+    ip: Option<IpAddr>,
+    path: &[&str],
+) -> Response {
+    if request.method != "GET" && request.method != "HEAD" {
+        return Response::error(405, "Method not allowed").header("Allow", "GET, HEAD");
+    }
+
+    // Open to load balancers and uptime checks that can't send a token
+    if path == ["health"] {
+        return health(server);
+    }
+
+    // Before authenticating, so invalid tokens can't call the cloud without limit
+    if let Some(limited) = server.http_rate_limit(ip, None) {
+        return limited;
+    }
+    let identity = match authenticate(server, request) {
+        Ok(identity) => identity,
+        Err(response) => return response,
+    };
+    if let Some(limited) = server.http_rate_limit(ip, Some(&identity.id)) {
+        return limited;
+    }
+
+    match path {
+        ["server"] => Response::json(200, &server.details()),
+        ["channels", channel_id, "messages"] => messages(server, request, channel_id),
+        _ => Response::error(404, "Not found"),
+    }
+}
+
+fn authenticate(server: &Server, request: &HttpRequest) -> Result<auth::Identity, Response> {
+    let unauthorized =
+        |message: &str| Response::error(401, message).header("WWW-Authenticate", "Bearer");
+    let Some(token) = request
+        .header("authorization")
+        .and_then(|h| h.strip_prefix("Bearer "))
+    else {
+        return Err(unauthorized("Missing bearer token"));
+    };
+
+    auth::verify(server, token.trim()).map_err(|e| {
+        server.metrics.auth_failed();
+        LOGGER.warn(format!("API authentication failed: {e:#}"));
+        unauthorized("Invalid token")
+    })
+}
+
+fn health(server: &Server) -> Response {
+    match server.db.ping() {
+        Ok(()) => Response::json(200, &Health { status: "ok" }),
+        Err(e) => {
+            LOGGER.error(format!("Health check failed: {e}"));
+            Response::json(503, &Health { status: "degraded" })
+        }
+    }
+}
+
+fn messages(server: &Server, request: &HttpRequest, channel_id: &str) -> Response {
+    if !server.config.channels.iter().any(|c| c.id == channel_id) {
+        return Response::error(404, format!("Channel {channel_id} not found"));
+    }
+
+    let before = match request.query("before").map(str::parse::<usize>) {
+        None => None,
+        Some(Ok(before)) => Some(before),
+        Some(Err(_)) => return Response::error(400, "before must be a message id"),
+    };
+    let limit = match request.query("limit").map(str::parse::<usize>) {
+        None => DEFAULT_LIMIT,
+        Some(Ok(limit)) if (1..=MAX_LIMIT).contains(&limit) => limit,
+        Some(_) => {
+            return Response::error(400, format!("limit must be between 1 and {MAX_LIMIT}"));
+        }
+    };
+
+    match server.db.get_channel_messages(channel_id, before, limit) {
+        Ok(messages) => Response::json(200, &messages),
+        Err(e) => Response::error(500, e),
+    }
+}
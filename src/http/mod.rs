@@ -0,0 +1,171 @@
+//! Plain HTTP requests served on the WebSocket port
+
+pub mod api;
+pub mod webhook;
+
+use std::{
+    fmt::Display,
+    hash::{DefaultHasher, Hash, Hasher},
+    net::IpAddr,
+    sync::Arc,
+    time::Duration,
+};
+
+use serde::Serialize;
+
+use crate::{
+    Server, auth,
+    utils::{
+        client::{
+            Stream,
+            handshake::{self, HttpRequest},
+        },
+        logger::redact_path,
+        ratelimit::RateLimit,
+    },
+};
+
+crate::logger!(LOGGER "HTTP");
+
+/// How long a request may take to arrive and its response to be sent
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn text(status: u16, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: body.into().into_bytes(),
+        }
+        .header("Content-Type", "text/plain; charset=utf-8")
+    }
+
+    pub fn json<T: Serialize>(status: u16, value: &T) -> Self {
+        match serde_json::to_vec(value) {
+            Ok(body) => Self {
+                status,
+                headers: Vec::new(),
+                body,
+            }
+            .header("Content-Type", "application/json"),
+            Err(e) => Self::error(500, e),
+        }
+    }
+
+    /// A `{ "error": message }` body
+    pub fn error(status: u16, message: impl Display) -> Self {
+        Self::json(status, &serde_json::json!({ "error": message.to_string() }))
+    }
+
+    pub fn header(mut self, name: &str, value: impl Display) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+impl Server {
+    /// Answer a request that is not a WebSocket upgrade, then close the connection
+    pub(crate) fn handle_http(
+        self: &Arc<Self>,
+        mut stream: Stream,
+        mut request: HttpRequest,
+    ) -> crate::Result<()> {
+        let ip = stream.peer_addr().ok().map(|a| a.ip());
+        let response = self.route_http(&mut request, ip);
+        if response.status >= 500 {
+            LOGGER.error(format!(
+                "{} {} failed with {}",
+                request.method,
+                redact_path(request.path()),
+                response.status
+            ));
+        }
+
+        let headers: Vec<(&str, &str)> = response
+            .headers
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        // HEAD gets the headers of a GET, without its body
+        let body = if request.method == "HEAD" {
+            &[][..]
+        } else {
+            &response.body
+        };
+        handshake::write_response(&mut stream, response.status, &headers, body)?;
+        Ok(())
+    }
+
+    /// Take a rate limit token from the bucket of `ip`, or of `user` once the request is
+    /// authenticated. Returns the response to send if none is left.
+    pub(crate) fn http_rate_limit(
+        &self,
+        ip: Option<IpAddr>,
+        user: Option<&str>,
+    ) -> Option<Response> {
+        let conn = rate_limit_conn(ip);
+        let limit = match user {
+            Some(user) => self.rate_limiter.check(conn, Some(user), None),
+            None => self.rate_limiter.check(conn, None, ip),
+        };
+        match limit {
+            RateLimit::Allowed => None,
+            RateLimit::Limited { retry_after } => Some(
+                Response::error(429, "Rate limited")
+                    .header("Retry-After", retry_after.as_secs_f64().ceil() as u64),
+            ),
+            RateLimit::Exceeded => Some(Response::error(429, "Rate limited")),
+        }
+    }
+
+    fn metrics_response(&self, request: &HttpRequest) -> Response {
+        if let Some(token) = &self.config.metrics.token {
+            let bearer = request
+                .header("authorization")
+                .and_then(|h| h.strip_prefix("Bearer "));
+            if bearer.is_none_or(|b| !auth::tokens_match(b.trim(), token)) {
+                self.metrics.auth_failed();
+                return Response::error(401, "Invalid token").header("WWW-Authenticate", "Bearer");
+            }
+        }
+
+        let clients = self.clients.lock().unwrap().len();
+        Response {
+            status: 200,
+            headers: Vec::new(),
+            body: self.metrics.render(clients).into_bytes(),
+        }
+        .header("Content-Type", "text/plain; version=0.0.4; charset=utf-8")
+    }
+
+    fn route_http(self: &Arc<Self>, request: &mut HttpRequest, ip: Option<IpAddr>) -> Response {
+        let path = request.path().to_string();
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        match (request.method.as_str(), segments.as_slice()) {
+            ("GET" | "HEAD", ["metrics"]) if self.config.metrics.enabled => {
+                self.metrics_response(request)
+            }
+            (_, ["api", rest @ ..]) => api::route(self, request, ip, rest),
+            ("POST", ["webhooks", id, token]) => webhook::post(self, request, ip, id, token),
+            (_, ["webhooks", _, _]) => {
+                Response::error(405, "Method not allowed").header("Allow", "POST")
+            }
+            // Health checks
+            ("GET" | "HEAD", _) => Response::text(200, "OK"),
+            _ => Response::error(404, "Not found"),
+        }
+    }
+}
+
+/// Rate limiter connection id of the HTTP requests from `ip`, so violations are counted per peer
+fn rate_limit_conn(ip: Option<IpAddr>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    ip.hash(&mut hasher);
+    hasher.finish()
+}
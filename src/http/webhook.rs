@@ -0,0 +1,88 @@
+//! Incoming webhooks, `POST /webhooks/<id>/<token>` with `{ "contents": ... }`
+//! posts a message to the webhook's channel
+
+use std::{net::IpAddr, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Server, auth,
+    http::{LOGGER, Response},
+    utils::client::handshake::HttpRequest,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IncomingWebhook {
+    /// Part of the URL, like the token
+    pub id: String,
+    pub token: String,
+    /// Channel the messages are posted to
+    pub channel_id: String,
+    /// Messages are sent by `webhook:<name>`
+    pub name: String,
+}
+
+impl IncomingWebhook {
+    /// User id the messages are sent by
+    pub fn author(&self) -> String {
+        format!("webhook:{}", self.name)
+    }
+}
+
+#[derive(Deserialize)]
+struct Payload {
+    contents: String,
+}
+
+pub fn post(
+    server: &Arc<Server>,
+    request: &mut HttpRequest,
+    ip: Option<IpAddr>,
+    id: &str,
+    token: &str,
+) -> Response {
+    // Before checking the token, so it can't be guessed without limit
+    if let Some(limited) = server.http_rate_limit(ip, None) {
+        return limited;
+    }
+
+    // Unknown ids and wrong tokens get the same answer, so ids can't be enumerated
+    let hook = server.config.incoming_webhooks.iter().find(|h| h.id == id);
+    let expected = hook.map_or("", |h| h.token.as_str());
+    let (Some(hook), true) = (hook, auth::tokens_match(token, expected)) else {
+        server.metrics.auth_failed();
+        return Response::error(404, "Webhook not found");
+    };
+
+    let author = hook.author();
+    if let Some(limited) = server.http_rate_limit(ip, Some(&author)) {
+        return limited;
+    }
+
+    if !request
+        .header("content-type")
+        .is_some_and(|t| t.starts_with("application/json"))
+    {
+        return Response::error(415, "Expected application/json");
+    }
+    let body = match request.read_body(server.config.limits.max_message_size) {
+        Ok(Some(body)) => body,
+        Ok(None) => return Response::error(413, "Body too large"),
+        Err(e) => return Response::error(400, e),
+    };
+    let payload: Payload = match serde_json::from_slice(&body) {
+        Ok(payload) => payload,
+        Err(e) => return Response::error(400, format!("Invalid JSON: {e}")),
+    };
+
+    match server.post_message(&hook.channel_id, &author, &payload.contents) {
+        Ok(msg) => {
+            LOGGER.info(format!(
+                "Webhook {id} posted message {} to {}",
+                msg.id, hook.channel_id
+            ));
+            Response::json(200, &msg)
+        }
+        Err(e) => Response::error(400, e),
+    }
+}
@@ -1,11 +1,19 @@
 use std::{
     collections::HashSet,
-    net::{TcpListener, TcpStream},
+    io,
+    net::TcpListener,
+    panic::{AssertUnwindSafe, catch_unwind},
     path::{Path, PathBuf},
-    sync::{Arc, Mutex},
+    sync::{
+        Arc, Mutex, PoisonError, RwLock, RwLockWriteGuard,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
 };
 
 pub mod auth;
+pub mod handle;
+pub mod http;
 pub mod macros;
 pub mod requests;
 pub mod types;
@@ -13,25 +21,68 @@ pub mod utils;
 
 pub use anyhow::Context as ErrorContext;
 pub use anyhow::Result;
+pub use handle::ServerHandle;
 
-use crate::{utils::client::Client, utils::plugin::DynPlugin};
+use crate::{
+    types::message::{ClientMessage, WsMessage},
+    utils::client::{Client, Stream},
+    utils::plugin::{DynPlugin, RequestAction, panic_message},
+};
+pub use log;
 pub use once_cell;
 
 #[derive(serde::Serialize, serde::Deserialize)]
+#[serde(default)]
 pub struct ServerConfig {
     pub server_name: String,
     pub server_id: String,
     pub server_key: String,
     pub port: u16,
     pub channels: Vec<types::data::Channel>,
+    pub rate_limit: utils::ratelimit::RateLimitConfig,
+    pub limits: utils::client::LimitsConfig,
+    pub compression: utils::deflate::CompressionConfig,
+    pub heartbeat: utils::client::HeartbeatConfig,
+    /// Serve `wss://` directly, requires the `tls` feature
+    pub tls: Option<utils::tls::TlsConfig>,
+    pub plugins: utils::plugin::PluginsConfig,
+    /// User ids with every permission
+    pub admins: Vec<String>,
+    /// Permissions of every other user
+    pub permissions: Vec<types::data::Permission>,
+    pub webhooks: utils::webhooks::WebhooksConfig,
+    /// Accepted at `POST /webhooks/<id>/<token>`
+    pub incoming_webhooks: Vec<http::webhook::IncomingWebhook>,
+    /// Served at `GET /metrics`
+    pub metrics: utils::metrics::MetricsConfig,
+    pub logging: utils::logger::LoggingConfig,
 }
 
 #[allow(dead_code)]
 pub struct Server {
     root: PathBuf,
     config: ServerConfig,
-    plugins: Mutex<Vec<DynPlugin>>,
+    /// Dispatch works on a snapshot, so a removed plugin lives until its running hooks return
+    plugins: RwLock<Vec<Arc<DynPlugin>>>,
+    /// Libraries the native plugins were loaded from, by plugin name
+    #[cfg(feature = "loader")]
+    native_plugins: Mutex<std::collections::HashMap<String, utils::loader::NativeFile>>,
+    /// Panics caught per plugin name
+    plugin_panics: Mutex<std::collections::HashMap<String, u32>>,
+    /// Threads waiting for removed plugins to return before shutting them down
+    retiring: Mutex<Vec<std::thread::JoinHandle<()>>>,
     clients: Mutex<HashSet<Client>>,
+    scheduler: utils::scheduler::Scheduler,
+    commands: utils::commands::Commands,
+    webhooks: utils::webhooks::Webhooks,
+    metrics: utils::metrics::Metrics,
+    /// Where every log goes, handed to native plugins
+    logs: Arc<utils::logger::Pipeline>,
+    rate_limiter: utils::ratelimit::RateLimiter,
+    /// Set once a shutdown was requested
+    stopping: AtomicBool,
+    /// Sent to every client before they are disconnected on shutdown
+    shutdown_notice: Mutex<Option<types::message::ServerMessage>>,
     pub db: utils::database::Database,
 }
 
@@ -39,10 +90,26 @@ impl Default for ServerConfig {
     fn default() -> Self {
         Self {
             port: 7080,
-            server_name: format!("Server Name"),
-            server_id: format!("offline-server"),
-            server_key: format!(""),
+            server_name: "Server Name".to_string(),
+            server_id: "offline-server".to_string(),
+            server_key: String::new(),
             channels: Vec::new(),
+            rate_limit: utils::ratelimit::RateLimitConfig::default(),
+            limits: utils::client::LimitsConfig::default(),
+            compression: utils::deflate::CompressionConfig::default(),
+            heartbeat: utils::client::HeartbeatConfig::default(),
+            tls: None,
+            plugins: utils::plugin::PluginsConfig::default(),
+            admins: Vec::new(),
+            permissions: vec![
+                types::data::Permission::SendMessages,
+                types::data::Permission::ManageOwnMessages,
+                types::data::Permission::PluginMessages,
+            ],
+            webhooks: utils::webhooks::WebhooksConfig::default(),
+            incoming_webhooks: Vec::new(),
+            metrics: utils::metrics::MetricsConfig::default(),
+            logging: utils::logger::LoggingConfig::default(),
         }
     }
 }
@@ -52,11 +119,29 @@ impl ServerConfig {
         Server::new_config(root, self)
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(s: &str) -> std::result::Result<Self, serde_json::Error> {
         serde_json::from_str(s)
     }
 }
 
+/// Call into a plugin, returning the message of a panic it caught or raised
+fn catch_plugin<R>(plugin: &DynPlugin, f: impl FnOnce() -> R) -> std::result::Result<R, String> {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(r) => plugin.take_panic().map_or(Ok(r), Err),
+        Err(payload) => Err(panic_message(&*payload)),
+    }
+}
+
+/// How long a removed plugin's running hooks get to return before `on_shutdown`
+const PLUGIN_DRAIN: Duration = Duration::from_secs(5);
+
+/// How long clients get to finish the close handshake on shutdown
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// How often the listener checks whether a shutdown was requested
+const ACCEPT_POLL: Duration = Duration::from_millis(100);
+
 impl Server {
     logger!(LOGGER "Server");
 
@@ -65,41 +150,192 @@ impl Server {
     }
 
     pub fn new_config(root: &Path, config: ServerConfig) -> Arc<Self> {
+        let logs = match utils::logger::Pipeline::new(config.logging.clone(), Some(root)) {
+            Ok(logs) => logs,
+            Err(e) => {
+                Self::LOGGER.error(format!("Failed to open the log file: {e}"));
+                utils::logger::Pipeline::new(config.logging.clone(), None)
+                    .expect("logging to stdout can't fail")
+            }
+        };
+        let logs = Arc::new(logs);
+        utils::logger::install(logs.clone());
+
+        let commands = utils::commands::Commands::default();
+        let help = utils::commands::Command::new("help", "List the commands or describe one")
+            .optional(
+                "command",
+                types::data::ArgKind::String,
+                "Command to describe",
+            );
+        commands
+            .register(None, help, utils::commands::help)
+            .expect("built-in commands are valid");
+
         Arc::new(Self {
-            db: utils::database::Database::new(&config).unwrap(),
-            plugins: Mutex::new(Vec::new()),
+            db: utils::database::Database::new(root).unwrap(),
+            plugins: RwLock::new(Vec::new()),
+            #[cfg(feature = "loader")]
+            native_plugins: Mutex::new(std::collections::HashMap::new()),
+            plugin_panics: Mutex::new(std::collections::HashMap::new()),
+            retiring: Mutex::new(Vec::new()),
+            rate_limiter: utils::ratelimit::RateLimiter::new(config.rate_limit.clone()),
+            webhooks: utils::webhooks::Webhooks::new(config.webhooks.clone()),
+            metrics: utils::metrics::Metrics::new(&config.channels),
+            logs,
             root: root.to_path_buf(),
             config,
             clients: Mutex::new(HashSet::new()),
+            scheduler: utils::scheduler::Scheduler::default(),
+            commands,
+            stopping: AtomicBool::new(false),
+            shutdown_notice: Mutex::new(None),
         })
     }
 
+    /// Read `plugins/<name>/config.json`, creating an empty one if it is missing
+    pub fn plugin_config(&self, name: &str) -> Result<utils::plugin::PluginConfig> {
+        utils::vfs::read_config(&self.root.join("plugins").join(name).join("config.json"))
+    }
+
+    /// Read the plugin's config file and initialize it
+    pub(crate) fn init_plugin(self: &Arc<Self>, plugin: &mut DynPlugin) -> Result<()> {
+        let config = self
+            .plugin_config(plugin.name())
+            .with_context(|| format!("Failed to read the config of plugin {}", plugin.name()))?;
+        let panic = match catch_unwind(AssertUnwindSafe(|| plugin.init(self, &config))) {
+            Ok(()) => plugin.take_panic(),
+            Err(payload) => Some(panic_message(&*payload)),
+        };
+        match panic {
+            None => Ok(()),
+            Some(msg) => anyhow::bail!(
+                "Plugin {} panicked while initializing: {msg}",
+                plugin.name()
+            ),
+        }
+    }
+
     pub fn run(self: &Arc<Self>) -> Result<()> {
         // Load plugins
         #[cfg(feature = "loader")]
-        utils::loader::load_plugins(
-            &mut *self.plugins.lock().unwrap(),
-            &self.root.join("./plugins"),
-        )?;
+        self.load_plugin_dir()?;
+        #[cfg(feature = "wasm")]
+        self.load_wasm_dir()?;
+
+        // Initialize plugins, highest priority first
+        {
+            let mut plugins = self.plugins_mut();
+            plugins.retain(|p| self.config.plugins.is_enabled(p.name()));
+            plugins.sort_by_key(|p| std::cmp::Reverse(p.priority()));
+            plugins.retain_mut(|plugin| {
+                // Nothing else holds the plugins before the server starts
+                let Some(plugin) = Arc::get_mut(plugin) else {
+                    return true;
+                };
+                match self.init_plugin(plugin) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        Self::LOGGER.error(format!("{e:#}, not loading it"));
+                        false
+                    }
+                }
+            });
+        }
 
-        // Initialize plugins
-        for plugin in self.plugins.lock().unwrap().iter_mut() {
-            plugin.init(self);
+        #[cfg(feature = "loader")]
+        if self.config.plugins.watch {
+            self.watch_plugins();
+        }
+
+        // Periodic plugin tick
+        if self.config.plugins.tick_interval > 0 {
+            let srv = self.clone();
+            std::thread::spawn(move || {
+                let interval = Duration::from_secs(srv.config.plugins.tick_interval);
+                while !srv.is_stopping() {
+                    std::thread::sleep(interval);
+                    srv.for_each_plugin(|p| p.on_tick(&srv));
+                }
+            });
+        }
+
+        // Tasks scheduled by plugins
+        std::thread::spawn({
+            let srv = self.clone();
+            move || {
+                srv.scheduler
+                    .run(|plugin| srv.handle(plugin), || srv.is_stopping())
+            }
+        });
+
+        // Outgoing webhook deliveries
+        if self.webhooks.enabled() {
+            let srv = self.clone();
+            std::thread::spawn(move || srv.run_webhooks());
+        }
+
+        // Set up TLS
+        #[cfg(feature = "tls")]
+        let tls = match &self.config.tls {
+            Some(config) => {
+                let acceptor = utils::tls::TlsAcceptor::new(&self.root, config)?;
+                acceptor.watch(std::time::Duration::from_secs(config.reload_interval));
+                Some(acceptor)
+            }
+            None => None,
+        };
+        #[cfg(not(feature = "tls"))]
+        if self.config.tls.is_some() {
+            anyhow::bail!("TLS is configured but the server was built without the `tls` feature");
         }
 
         // Start server
         let listener = TcpListener::bind(format!("0.0.0.0:{}", self.config.port))?;
-        Self::LOGGER.info(format!("Server listening at 0.0.0.0:{}", self.config.port));
+        Self::LOGGER.info(format!(
+            "Server listening at {}://0.0.0.0:{}",
+            if self.config.tls.is_some() {
+                "wss"
+            } else {
+                "ws"
+            },
+            self.config.port
+        ));
+
+        // Non-blocking, so the loop can notice a shutdown request
+        listener.set_nonblocking(true)?;
+
+        while !self.stopping.load(Ordering::SeqCst) {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(e) = stream.set_nonblocking(false) {
+                        Self::LOGGER.error(format!("Connection failed: {e}"));
+                        continue;
+                    }
 
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
                     std::thread::spawn({
                         let srv = self.clone();
+                        #[cfg(feature = "tls")]
+                        let tls = tls.clone();
 
                         move || {
+                            #[cfg(feature = "tls")]
+                            let stream: Stream = match &tls {
+                                Some(tls) => match tls.accept(stream) {
+                                    Ok(s) => s.into(),
+                                    Err(e) => {
+                                        Self::LOGGER.error(format!("TLS setup failed: {e}"));
+                                        return;
+                                    }
+                                },
+                                None => stream.into(),
+                            };
+                            #[cfg(not(feature = "tls"))]
+                            let stream: Stream = stream.into();
+
                             let Some(client) = Self::LOGGER
                                 .extract(srv.init_client(stream), "Failed to initialize client")
+                                .flatten()
                             else {
                                 return;
                             };
@@ -108,61 +344,396 @@ impl Server {
                                 srv.wrap_err(&client, srv.handle_client(&client)),
                                 "Client handler failed",
                             );
+                            srv.clients.lock().unwrap().remove(&client);
+                            srv.rate_limiter.disconnected(client.id());
+                            srv.update_presence(&client, "offline");
+                            if let Ok(user_id) = client.get_uuid() {
+                                srv.emit_webhook(
+                                    utils::webhooks::WebhookEvent::MemberLeave,
+                                    &utils::webhooks::MemberEvent {
+                                        user_id,
+                                        bot: client.is_bot(),
+                                    },
+                                );
+                            }
+                            srv.for_each_plugin(|p| p.on_disconnect(&client, &srv));
                         }
                     });
                 }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(ACCEPT_POLL);
+                }
                 Err(e) => {
                     Self::LOGGER.error(format!("Connection failed: {e}"));
                 }
             }
         }
 
+        drop(listener);
+        self.finish_shutdown();
         Ok(())
     }
 
+    /// Stop the server: `run` stops accepting connections, disconnects every client with
+    /// close code 1001 after sending them `notice`, and returns once cleanup is done
+    pub fn shutdown(&self, notice: Option<types::message::ServerMessage>) {
+        *self.shutdown_notice.lock().unwrap() = notice;
+        self.stopping.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether a shutdown was requested
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    fn finish_shutdown(self: &Arc<Self>) {
+        Self::LOGGER.info("Shutting down");
+
+        let notice = self.shutdown_notice.lock().unwrap().take();
+        let clients: Vec<Client> = self.clients.lock().unwrap().iter().cloned().collect();
+        for client in &clients {
+            if let Some(notice) = &notice {
+                let _ = client.send(notice);
+            }
+            let _ = client.send_close(1001, "Server shutting down");
+        }
+
+        // Give clients a moment to answer the close frame
+        let start = Instant::now();
+        while !self.clients.lock().unwrap().is_empty() && start.elapsed() < SHUTDOWN_GRACE {
+            std::thread::sleep(ACCEPT_POLL);
+        }
+        for client in self.clients.lock().unwrap().drain() {
+            let _ = client.disconnect(1001, "Server shutting down");
+        }
+
+        self.for_each_plugin(|p| p.on_shutdown(self));
+        let retiring = std::mem::take(&mut *self.retiring.lock().unwrap());
+        for thread in retiring {
+            let _ = thread.join();
+        }
+
+        Self::LOGGER.extract(self.db.flush(), "Failed to flush the database");
+        Self::LOGGER.info("Server stopped");
+    }
+
     pub fn add_plugin(self: &Arc<Self>, plugin: DynPlugin) {
-        self.plugins.lock().unwrap().push(plugin);
+        let mut plugins = self.plugins_mut();
+        let pos = plugins.partition_point(|p| p.priority() >= plugin.priority());
+        plugins.insert(pos, Arc::new(plugin));
+    }
+
+    /// Panics of the plugin named `name` since it was loaded, see `PluginsConfig::max_panics`
+    pub fn plugin_panics(&self, name: &str) -> u32 {
+        self.plugin_panics
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(name)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Describe every loaded plugin, in dispatch order
+    pub fn plugin_list(&self) -> Vec<types::data::PluginInfo> {
+        #[cfg(feature = "loader")]
+        let native = self.native_plugins.lock().unwrap();
+        self.plugins()
+            .iter()
+            .map(|p| {
+                #[cfg(feature = "loader")]
+                let file = native.get(p.name());
+                types::data::PluginInfo {
+                    name: p.name().to_string(),
+                    priority: p.priority(),
+                    #[cfg(feature = "loader")]
+                    version: file.map(|f| f.version.clone()),
+                    #[cfg(not(feature = "loader"))]
+                    version: None,
+                    #[cfg(feature = "loader")]
+                    file: file.and_then(|f| f.file_name()),
+                    #[cfg(not(feature = "loader"))]
+                    file: None,
+                }
+            })
+            .collect()
+    }
+
+    /// Remove a plugin, its `on_shutdown` hook is called on another thread once its running hooks returned
+    pub fn unload_plugin(self: &Arc<Self>, name: &str) -> Result<()> {
+        let plugin = {
+            let mut plugins = self.plugins_mut();
+            let Some(pos) = plugins.iter().position(|p| p.name() == name) else {
+                anyhow::bail!("Plugin {name} is not loaded");
+            };
+            plugins.remove(pos)
+        };
+
+        self.retire_plugin(plugin);
+        Self::LOGGER.info(format!("Unloaded plugin {name}"));
+        Ok(())
+    }
+
+    /// Let a plugin removed from the list clean up. Waiting for its running hooks happens
+    /// on a thread of its own, the plugin is dropped there, unloading its library.
+    fn retire_plugin(self: &Arc<Self>, plugin: Arc<DynPlugin>) {
+        let name = plugin.name().to_string();
+        #[cfg(feature = "loader")]
+        self.native_plugins.lock().unwrap().remove(&name);
+        self.plugin_panics
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&name);
+        self.scheduler.cancel_owner(&name);
+        let commands = self.commands.remove_owner(&name);
+        if !commands.is_empty() {
+            self.broadcast(types::message::ServerMessage::Commands(
+                self.commands.list(),
+            ));
+        }
+
+        let server = self.clone();
+        let thread = std::thread::spawn(move || {
+            // Running hooks and commands hold a reference
+            let start = Instant::now();
+            while (Arc::strong_count(&plugin) > 1
+                || commands.iter().any(|c| Arc::strong_count(c) > 1))
+                && start.elapsed() < PLUGIN_DRAIN
+            {
+                std::thread::sleep(ACCEPT_POLL);
+            }
+
+            if let Err(msg) = catch_plugin(&plugin, || plugin.on_shutdown(&server)) {
+                Self::LOGGER.error(format!("Plugin {name} panicked while shutting down: {msg}"));
+            }
+        });
+
+        let mut retiring = self.retiring.lock().unwrap();
+        retiring.retain(|t| !t.is_finished());
+        retiring.push(thread);
+    }
+
+    /// The loaded plugins in dispatch order, without holding the lock
+    fn plugins(&self) -> Vec<Arc<DynPlugin>> {
+        self.plugins
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Lock the plugin list for changes, recovering it if a thread panicked while holding the lock
+    fn plugins_mut(&self) -> RwLockWriteGuard<'_, Vec<Arc<DynPlugin>>> {
+        self.plugins.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Call into a plugin, catching panics. Returns `None` if it panicked, and adds
+    /// its name to `disable` once it panicked `PluginsConfig::max_panics` times.
+    fn call_plugin<R>(
+        &self,
+        plugin: &DynPlugin,
+        disable: &mut Vec<String>,
+        f: impl FnOnce(&DynPlugin) -> R,
+    ) -> Option<R> {
+        let start = Instant::now();
+        let res = catch_plugin(plugin, || f(plugin));
+        self.metrics.plugin_called(plugin.name(), start.elapsed());
+        let msg = match res {
+            Ok(r) => return Some(r),
+            Err(msg) => msg,
+        };
+
+        let name = plugin.name().to_string();
+        let mut panics = self
+            .plugin_panics
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let count = panics.entry(name.clone()).or_default();
+        *count += 1;
+        Self::LOGGER.error(format!("Plugin {name} panicked: {msg}"));
+
+        // Concurrent hooks may keep panicking until the plugin is removed, only disable it once
+        let max = self.config.plugins.max_panics;
+        if max > 0 && *count == max {
+            Self::LOGGER.error(format!("Disabling plugin {name} after {count} panics"));
+            disable.push(name);
+        }
+        None
+    }
+
+    /// Remove and retire the plugins in `disable`
+    fn disable_plugins(self: &Arc<Self>, disable: Vec<String>) {
+        for name in disable {
+            let plugin = {
+                let mut plugins = self.plugins_mut();
+                let pos = plugins.iter().position(|p| p.name() == name);
+                pos.map(|pos| plugins.remove(pos))
+            };
+            if let Some(plugin) = plugin {
+                self.retire_plugin(plugin);
+            }
+        }
+    }
+
+    /// Run `f` on every loaded plugin, a panic only skips the plugin that panicked
+    pub(crate) fn for_each_plugin(self: &Arc<Self>, mut f: impl FnMut(&DynPlugin)) {
+        let mut disable = Vec::new();
+        for plugin in self.plugins() {
+            self.call_plugin(&plugin, &mut disable, &mut f);
+        }
+        self.disable_plugins(disable);
+    }
+
+    /// Run `f` on the plugin named `name` only. Returns `None` if no such plugin is loaded,
+    /// and `Some(None)` if it panicked.
+    pub(crate) fn with_plugin<R>(
+        self: &Arc<Self>,
+        name: &str,
+        f: impl FnOnce(&DynPlugin) -> R,
+    ) -> Option<Option<R>> {
+        let plugin = self.plugins().into_iter().find(|p| p.name() == name)?;
+        let mut disable = Vec::new();
+        let res = self.call_plugin(&plugin, &mut disable, f);
+        self.disable_plugins(disable);
+        Some(res)
+    }
+
+    /// Send a message to every connected client
+    pub fn broadcast(self: &Arc<Self>, msg: types::message::ServerMessage) {
+        for c in self.clients.lock().unwrap().iter() {
+            let c = c.clone();
+            let server = self.clone();
+            let msg = msg.clone();
+            self.metrics.broadcast_queued();
+            std::thread::spawn(move || {
+                Self::LOGGER.extract(server.wrap_err(&c, c.send(msg)), "Failed to broadcast");
+                server.metrics.broadcast_sent();
+            });
+        }
+    }
+
+    /// Send an event of the plugin named `plugin` to every connected client
+    pub fn broadcast_plugin_event<T: serde::Serialize>(
+        self: &Arc<Self>,
+        plugin: &str,
+        event: &str,
+        data: T,
+    ) -> Result<()> {
+        self.broadcast(types::message::ServerMessage::Plugin {
+            plugin: plugin.to_string(),
+            event: event.to_string(),
+            data: serde_json::to_value(data)?,
+        });
+        Ok(())
+    }
+
+    /// Post a message flagged as sent by a bot to a channel as `author`,
+    /// broadcasting it and running the `on_message_created` hooks
+    pub(crate) fn post_message(
+        self: &Arc<Self>,
+        channel_id: &str,
+        author: &str,
+        contents: &str,
+    ) -> Result<types::data::Message> {
+        if !self.config.channels.iter().any(|c| c.id == channel_id) {
+            anyhow::bail!("Channel {channel_id} does not exist");
+        }
+        if contents.is_empty() {
+            anyhow::bail!("Message is empty");
+        }
+        if contents.chars().count() > self.config.limits.max_content_length {
+            anyhow::bail!(
+                "Message is longer than {} characters",
+                self.config.limits.max_content_length
+            );
+        }
+
+        let msg = self.db.insert_message(
+            channel_id,
+            author,
+            contents,
+            chrono::Utc::now().timestamp(),
+            true,
+        )?;
+
+        self.metrics.message_posted(channel_id);
+        self.broadcast(types::message::ServerMessage::MessageCreate(msg.clone()));
+        self.emit_webhook(utils::webhooks::WebhookEvent::MessageCreate, &msg);
+        self.for_each_plugin(|p| p.on_message_created(&msg, self));
+        Ok(msg)
     }
 
-    fn init_client(self: &Arc<Self>, stream: TcpStream) -> anyhow::Result<Client> {
+    /// Accept a WebSocket client, `None` if the connection was a plain HTTP request
+    fn init_client(self: &Arc<Self>, stream: Stream) -> anyhow::Result<Option<Client>> {
         Self::LOGGER.info(format!("New connection: {}", stream.peer_addr()?));
+        stream.set_read_timeout(Some(http::REQUEST_TIMEOUT))?;
+        stream.set_write_timeout(Some(http::REQUEST_TIMEOUT))?;
+        let request = utils::client::handshake::read_request(&stream)?;
+        if !request.is_websocket_upgrade() {
+            self.handle_http(stream, request)?;
+            return Ok(None);
+        }
+
         // Initialize client
-        let mut client = Client::new(stream)?;
-
-        // Initialize handshake
-        self.wrap_err(
-            &client,
-            client.send(types::handshake::ServerDetails {
-                name: self.config.server_name.clone(),
-                id: self.config.server_id.clone(),
-                version: format!("0.0.1"),
-                channels: self.config.channels.clone(),
-            }),
-        )?;
+        let mut client = Client::accept(stream, &request, &self.config)?;
+        self.for_each_plugin(|p| p.on_connect(&client, self));
+
+        if let Err(e) = self.client_handshake(&mut client) {
+            self.for_each_plugin(|p| p.on_disconnect(&client, self));
+            return Err(e);
+        }
 
-        match self.wrap_err(&client, client.read_t::<types::handshake::ClientDetails>())? {
+        Ok(Some(client))
+    }
+
+    /// What clients are told about the server before authenticating
+    pub fn details(&self) -> types::handshake::ServerDetails {
+        types::handshake::ServerDetails {
+            name: self.config.server_name.clone(),
+            id: self.config.server_id.clone(),
+            version: "0.0.1".to_string(),
+            channels: self.config.channels.clone(),
+            commands: self.commands.list(),
+        }
+    }
+
+    /// Exchange server and client details, then authenticate
+    fn client_handshake(self: &Arc<Self>, client: &mut Client) -> anyhow::Result<()> {
+        self.wrap_err(client, client.send(self.details()))?;
+
+        let mut authenticated = false;
+        match self.wrap_err(client, client.read_t::<types::handshake::ClientDetails>())? {
             Some(types::message::WsMessage::Message(types::handshake::ClientDetails {
                 auth_token,
                 last_message,
                 ..
             })) => {
-                let auth_res = auth::auth(self, &mut client, &auth_token);
-                let uuid = self.wrap_err(&client, auth_res)?;
+                let auth_res = auth::auth(self, client, &auth_token);
+                if auth_res.is_err() {
+                    self.metrics.auth_failed();
+                }
+                let uuid = self.wrap_err(client, auth_res)?;
                 self.wrap_err(
-                    &client,
+                    client,
                     client.send(types::message::ServerMessage::Authenticated {
                         uuid,
                         messages: if let Some(i) = last_message {
-                            self.wrap_err(&client, self.db.get_messages_after_id(i))?
+                            self.wrap_err(client, self.db.get_messages_after_id(i))?
                         } else {
-                            self.wrap_err(&client, self.db.get_messages_after_id(0))?
+                            self.wrap_err(client, self.db.get_messages_after_id(0))?
                         },
                     }),
                 )?;
+                authenticated = true;
+                self.emit_webhook(
+                    utils::webhooks::WebhookEvent::MemberJoin,
+                    &utils::webhooks::MemberEvent {
+                        user_id: client.get_uuid()?,
+                        bot: client.is_bot(),
+                    },
+                );
             }
             Some(v) => {
                 self.wrap_err(
-                    &client,
+                    client,
                     client.send(types::message::ResponseError::InvalidHandshake(format!(
                         "Invalid handshake: {v:?}"
                     ))),
@@ -174,22 +745,89 @@ impl Server {
         // Insert to the set of all connected clients
         self.clients.lock().unwrap().insert(client.clone());
 
-        Ok(client)
+        if authenticated {
+            self.update_presence(client, "online");
+            self.for_each_plugin(|p| p.on_authenticated(client, self));
+        }
+
+        Ok(())
+    }
+
+    /// Tell everyone the user of `client` came online or went offline,
+    /// unless another of their connections keeps them online
+    fn update_presence(self: &Arc<Self>, client: &Client, status: &str) {
+        let Ok(user_id) = client.get_uuid() else {
+            return;
+        };
+        let connected = self
+            .clients
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|c| c != client && c.get_uuid().is_ok_and(|u| u == user_id));
+        if connected {
+            return;
+        }
+
+        self.broadcast(types::message::ServerMessage::PresenceUpdate {
+            user_id,
+            status: status.to_string(),
+            bot: client.is_bot(),
+        });
     }
 
     fn handle_client(self: &Arc<Self>, client: &Client) -> anyhow::Result<()> {
         // The main req/res loop
-        'outer: loop {
-            let req = client.read()?;
-            if let Some(r) = &req {
-                for p in self.plugins.lock().unwrap().iter_mut() {
-                    if p.on_request(r, client, self) {
-                        continue 'outer;
-                    }
+        loop {
+            let Some(req) = client.read()? else {
+                return Ok(());
+            };
+            // Before the plugins, so their hooks aren't run for throttled requests either
+            if !self.wrap_err(client, self.rate_limit(client))? {
+                continue;
+            }
+
+            let start = Instant::now();
+            let kind = match &req {
+                WsMessage::Message(m) => m.name(),
+                WsMessage::String(_) => "text",
+                WsMessage::Binary(_) => "binary",
+            };
+            if let Some(req) = self.wrap_err(client, self.plugin_request(req, client))? {
+                self.wrap_err(client, self.call_request(&req, client))?;
+            }
+            self.metrics.request_handled(kind, start.elapsed());
+        }
+    }
+
+    /// Pass a request through every plugin, returns the request left for the server to handle
+    fn plugin_request(
+        self: &Arc<Self>,
+        mut req: WsMessage<ClientMessage>,
+        client: &Client,
+    ) -> anyhow::Result<Option<WsMessage<ClientMessage>>> {
+        let mut disable = Vec::new();
+        let mut action = None;
+        for p in self.plugins() {
+            // A plugin that panicked lets the request through
+            match self.call_plugin(&p, &mut disable, |p| p.on_request(&req, client, self)) {
+                None | Some(RequestAction::Continue) => {}
+                Some(RequestAction::Modify(r)) => req = r,
+                Some(a) => {
+                    action = Some(a);
+                    break;
                 }
+            }
+        }
+        self.disable_plugins(disable);
 
-                self.wrap_err(&client, self.call_request(r, &client))?;
+        match action {
+            Some(RequestAction::Reject(e)) => {
+                client.send(e)?;
+                Ok(None)
             }
+            Some(_) => Ok(None),
+            None => Ok(Some(req)),
         }
     }
 
@@ -200,7 +838,7 @@ impl Server {
         res: std::result::Result<T, E>,
     ) -> std::result::Result<T, E> {
         if let Err(e) = &res {
-            self.clients.lock().unwrap().remove(&client);
+            self.clients.lock().unwrap().remove(client);
             if client
                 .send(types::message::ResponseError::InternalError(e.to_string()))
                 .is_err()
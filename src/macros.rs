@@ -1,23 +1,34 @@
+/// Export a plugin from a `cdylib`, the plugin is named after the crate's package
 #[macro_export]
 macro_rules! export_plugin {
     ($p:expr) => {
         #[unsafe(no_mangle)]
-        pub extern "C" fn load_plugin() -> $crate::utils::plugin::DynPlugin {
-            $p
-        }
+        pub static VOXA_PLUGIN_DESCRIPTOR: $crate::utils::native::PluginDescriptor =
+            $crate::utils::native::PluginDescriptor::new(
+                $crate::utils::native::PluginManifest::new(
+                    concat!(env!("CARGO_PKG_NAME"), "\0"),
+                    concat!(env!("CARGO_PKG_VERSION"), "\0"),
+                ),
+                {
+                    extern "C" fn create() -> *mut ::std::ffi::c_void {
+                        $crate::utils::native::create(|| $p)
+                    }
+                    create
+                },
+            );
     };
 }
 
 #[macro_export]
 macro_rules! logger {
     (const $i:ident $name:expr) => {
-        pub const $i: $crate::once_cell::sync::Lazy<$crate::utils::logger::Logger> =
-            $crate::once_cell::sync::Lazy::new(|| $crate::utils::logger::Logger::new($name));
+        pub const $i: $crate::utils::logger::Logger =
+            $crate::utils::logger::Logger::new_static($name);
     };
 
     ($i:ident $name:expr) => {
-        pub const $i: $crate::once_cell::sync::Lazy<$crate::utils::logger::Logger> =
-            $crate::once_cell::sync::Lazy::new(|| $crate::utils::logger::Logger::new($name));
+        pub const $i: $crate::utils::logger::Logger =
+            $crate::utils::logger::Logger::new_static($name);
     };
 
     (const $name:expr) => {
@@ -25,6 +36,6 @@ macro_rules! logger {
     };
 
     ($name:expr) => {
-        $crate::logger::utils::Logger::new($name)
+        $crate::utils::logger::Logger::new($name)
     };
 }
@@ -0,0 +1,66 @@
+use std::sync::Arc;
+
+use crate::{
+    Server,
+    requests::permitted,
+    types::{
+        data::Permission,
+        message::{ResponseError, ServerMessage},
+    },
+    utils::client::Client,
+};
+
+crate::logger!(LOGGER "Bot Manager");
+
+pub fn create(
+    server: &Arc<Server>,
+    client: &Client,
+    name: &str,
+    permissions: &[Permission],
+) -> crate::Result<()> {
+    if !permitted(server, client, Permission::ManageBots)? {
+        return Ok(());
+    }
+
+    // Nobody can hand out permissions they don't have
+    if let Some(p) = permissions
+        .iter()
+        .find(|p| !server.has_permission(client, **p))
+    {
+        client.send(ResponseError::Unauthorized(format!(
+            "Can't grant permission {p} you don't have"
+        )))?;
+        return Ok(());
+    }
+
+    match server.create_bot(name, permissions.to_vec()) {
+        Ok((bot, token)) => client.send(ServerMessage::BotCreated { bot, token })?,
+        Err(e) => client.send(ResponseError::InvalidRequest(format!("{e:#}")))?,
+    }
+
+    Ok(())
+}
+
+pub fn list(server: &Arc<Server>, client: &Client) -> crate::Result<()> {
+    if !permitted(server, client, Permission::ManageBots)? {
+        return Ok(());
+    }
+
+    client.send(ServerMessage::BotList(server.db.get_bots()?))
+}
+
+pub fn delete(server: &Arc<Server>, client: &Client, name: &str) -> crate::Result<()> {
+    if !permitted(server, client, Permission::ManageBots)? {
+        return Ok(());
+    }
+
+    match server.delete_bot(name) {
+        Ok(()) => client.send(ServerMessage::BotList(server.db.get_bots()?))?,
+        Err(e) => {
+            LOGGER.warn(format!("Failed to delete bot {name}: {e:#}"));
+            client.send(ResponseError::NotFound(format!("{e:#}")))?
+        }
+    }
+
+    Ok(())
+}
@@ -1,62 +1,152 @@
 use std::sync::Arc;
 
-use crate::{Server, types, utils::client::Client};
+use crate::{
+    Server,
+    requests::permitted,
+    types::{
+        self,
+        data::Permission,
+        message::{ResponseError, ServerMessage},
+    },
+    utils::{client::Client, commands, webhooks::WebhookEvent},
+};
 
 crate::logger!(LOGGER "Message Manager");
 
+/// Check message contents, answering the client with an error if they are invalid
+fn validate_contents(client: &Client, contents: &str) -> crate::Result<bool> {
+    if contents.is_empty() {
+        client.send(ResponseError::InvalidRequest(
+            "Invalid message: empty message".to_string(),
+        ))?;
+
+        return Ok(false);
+    }
+
+    if contents.chars().count() > client.limits().max_content_length {
+        client.send(ResponseError::InvalidRequest(format!(
+            "Invalid message: longer than {} characters",
+            client.limits().max_content_length
+        )))?;
+
+        return Ok(false);
+    }
+
+    Ok(true)
+}
+
+/// Get a message the client is allowed to modify, answering the client with an error otherwise
+fn owned_message(
+    server: &Arc<Server>,
+    client: &Client,
+    message_id: usize,
+) -> crate::Result<Option<types::data::Message>> {
+    let Some(msg) = server.db.get_message_by_id(message_id)? else {
+        client.send(ResponseError::NotFound(format!(
+            "Message {message_id} not found"
+        )))?;
+        return Ok(None);
+    };
+
+    if msg.from != client.get_uuid()? {
+        client.send(ResponseError::Unauthorized(format!(
+            "Message {message_id} was sent by another user"
+        )))?;
+        return Ok(None);
+    }
+
+    Ok(Some(msg))
+}
+
 pub fn send(
     server: &Arc<Server>,
     client: &Client,
     channel_id: &str,
     contents: &str,
 ) -> crate::Result<()> {
-    LOGGER.info(format!("SendMessage to {channel_id}: {contents}"));
-
-    if contents.is_empty() {
-        client.send(types::message::ResponseError::InvalidRequest(format!(
-            "Invalid message: empty message"
-        )))?;
+    LOGGER.info(format!(
+        "SendMessage to {channel_id} ({} bytes)",
+        contents.len()
+    ));
+    LOGGER.payload("Contents", contents);
 
+    if !permitted(server, client, Permission::SendMessages)?
+        || !validate_contents(client, contents)?
+    {
         return Ok(());
     }
 
+    if let Some(command) = contents.strip_prefix('/') {
+        return commands::run(server, client, channel_id, command);
+    }
+
     let msg = server.db.insert_message(
-        &channel_id,
+        channel_id,
         &client.get_uuid()?,
-        &contents,
+        contents,
         chrono::Utc::now().timestamp(),
+        client.is_bot(),
     )?;
 
-    let server = server.clone();
-
-    for c in server.clients.lock().unwrap().iter() {
-        let c = c.clone();
-        let server = server.clone();
-        let msg = msg.clone();
-        std::thread::spawn(move || {
-            server
-                .wrap_err(
-                    &c,
-                    c.send(types::message::ServerMessage::MessageCreate(msg)),
-                )
-                .expect("Failed to broadcast");
-        });
-    }
+    server.metrics.message_posted(channel_id);
+    server.broadcast(ServerMessage::MessageCreate(msg.clone()));
+    server.emit_webhook(WebhookEvent::MessageCreate, &msg);
+    server.for_each_plugin(|p| p.on_message_created(&msg, server));
 
     Ok(())
 }
 
 pub fn edit(
-    _server: &Arc<Server>,
-    _client: &Client,
+    server: &Arc<Server>,
+    client: &Client,
     message_id: usize,
     new_contents: &str,
 ) -> crate::Result<()> {
-    LOGGER.info(format!("EditMessage {message_id}: {new_contents}"));
+    LOGGER.info(format!(
+        "EditMessage {message_id} ({} bytes)",
+        new_contents.len()
+    ));
+    LOGGER.payload("New contents", new_contents);
+
+    if !permitted(server, client, Permission::ManageOwnMessages)?
+        || !validate_contents(client, new_contents)?
+    {
+        return Ok(());
+    }
+
+    let Some(mut msg) = owned_message(server, client, message_id)? else {
+        return Ok(());
+    };
+
+    server.db.edit_message(message_id, new_contents)?;
+    msg.contents = new_contents.to_string();
+
+    server.broadcast(ServerMessage::MessageUpdate(msg.clone()));
+    server.emit_webhook(WebhookEvent::MessageUpdate, &msg);
+    server.for_each_plugin(|p| p.on_message_edited(&msg, server));
+
     Ok(())
 }
 
-pub fn delete(_server: &Arc<Server>, _client: &Client, message_id: usize) -> crate::Result<()> {
+pub fn delete(server: &Arc<Server>, client: &Client, message_id: usize) -> crate::Result<()> {
     LOGGER.info(format!("DeleteMessage {message_id}"));
+
+    if !permitted(server, client, Permission::ManageOwnMessages)? {
+        return Ok(());
+    }
+
+    let Some(msg) = owned_message(server, client, message_id)? else {
+        return Ok(());
+    };
+
+    server.db.delete_message(message_id)?;
+
+    server.broadcast(ServerMessage::MessageDelete {
+        channel_id: msg.channel_id.clone(),
+        message_id,
+    });
+    server.emit_webhook(WebhookEvent::MessageDelete, &msg);
+    server.for_each_plugin(|p| p.on_message_deleted(&msg, server));
+
     Ok(())
 }
@@ -1,14 +1,60 @@
+pub mod bot;
 pub mod message;
+pub mod plugin;
 
 use std::sync::Arc;
 
 use crate::{
     Server,
-    types::message::{ClientMessage, WsMessage},
-    utils::client::Client,
+    types::{
+        data::Permission,
+        message::{ClientMessage, ResponseError, WsMessage},
+    },
+    utils::{client::Client, ratelimit::RateLimit},
 };
 
+/// Check that the client was granted `permission`, answering it with an error otherwise
+pub(crate) fn permitted(
+    server: &Server,
+    client: &Client,
+    permission: Permission,
+) -> crate::Result<bool> {
+    if server.has_permission(client, permission) {
+        return Ok(true);
+    }
+
+    client.send(ResponseError::Unauthorized(format!(
+        "Missing permission {permission}"
+    )))?;
+    Ok(false)
+}
+
 impl Server {
+    /// Take a rate limit token for a request of `client`, returns whether it may be handled
+    pub(crate) fn rate_limit(&self, client: &Client) -> crate::Result<bool> {
+        match self
+            .rate_limiter
+            .check(client.id(), client.get_uuid().ok().as_deref(), client.ip())
+        {
+            RateLimit::Allowed => Ok(true),
+            RateLimit::Limited { retry_after } => {
+                client.send(ResponseError::RateLimited {
+                    retry_after: retry_after.as_millis().try_into().unwrap_or(u64::MAX),
+                })?;
+                Ok(false)
+            }
+            RateLimit::Exceeded => {
+                Self::LOGGER.warn(format!(
+                    "Client ({}) exceeded the rate limit, disconnecting",
+                    client.id()
+                ));
+                self.clients.lock().unwrap().remove(client);
+                client.disconnect(1008, "Rate limit exceeded")?;
+                Ok(false)
+            }
+        }
+    }
+
     pub fn call_request(
         self: &Arc<Self>,
         req: &WsMessage<ClientMessage>,
@@ -31,14 +77,42 @@ impl Server {
                 ClientMessage::DeleteMessage { message_id } => {
                     message::delete(self, client, *message_id)?
                 }
+
+                ClientMessage::Plugin { plugin, data } => {
+                    plugin::message(self, client, plugin, data)?
+                }
+
+                ClientMessage::ListPlugins => plugin::admin(self, client, plugin::Admin::List)?,
+
+                ClientMessage::LoadPlugin { file } => {
+                    plugin::admin(self, client, plugin::Admin::Load(file))?
+                }
+
+                ClientMessage::UnloadPlugin { name } => {
+                    plugin::admin(self, client, plugin::Admin::Unload(name))?
+                }
+
+                ClientMessage::ReloadPlugin { name } => {
+                    plugin::admin(self, client, plugin::Admin::Reload(name))?
+                }
+
+                ClientMessage::CreateBot { name, permissions } => {
+                    bot::create(self, client, name, permissions)?
+                }
+
+                ClientMessage::ListBots => bot::list(self, client)?,
+
+                ClientMessage::DeleteBot { name } => bot::delete(self, client, name)?,
             },
 
             WsMessage::Binary(b) => {
-                Self::LOGGER.info(format!("Binary message: {b:?}"));
+                Self::LOGGER.info(format!("Binary message of {} bytes", b.len()));
+                Self::LOGGER.payload("Binary message", format!("{b:?}"));
             }
 
             WsMessage::String(s) => {
-                Self::LOGGER.info(format!("String message: {s}"));
+                Self::LOGGER.info(format!("Unrecognized message of {} bytes", s.len()));
+                Self::LOGGER.payload("Unrecognized message", s);
             }
         }
 
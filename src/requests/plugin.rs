@@ -0,0 +1,97 @@
+use std::sync::Arc;
+
+use crate::{
+    Server,
+    requests::permitted,
+    types::{
+        data::Permission,
+        message::{ResponseError, ServerMessage},
+    },
+    utils::client::Client,
+};
+
+crate::logger!(LOGGER "Plugin Messages");
+
+/// Route a `ClientMessage::Plugin` request to the plugin it names
+pub fn message(
+    server: &Arc<Server>,
+    client: &Client,
+    plugin: &str,
+    data: &serde_json::Map<String, serde_json::Value>,
+) -> crate::Result<()> {
+    if !permitted(server, client, Permission::PluginMessages)? {
+        return Ok(());
+    }
+
+    let res = match server.with_plugin(plugin, |p| p.on_plugin_message(data, client, server)) {
+        Some(Some(res)) => res,
+        Some(None) => Err(ResponseError::InternalError(format!(
+            "Plugin {plugin} failed to handle the message"
+        ))),
+        None => {
+            LOGGER.warn(format!("Message for unknown plugin {plugin}"));
+            Err(ResponseError::NotFound(format!(
+                "Plugin {plugin} not found"
+            )))
+        }
+    };
+
+    if let Err(e) = res {
+        client.send(e)?;
+    }
+
+    Ok(())
+}
+
+/// A runtime plugin management request
+pub enum Admin<'a> {
+    List,
+    Load(&'a str),
+    Unload(&'a str),
+    Reload(&'a str),
+}
+
+/// Handle a plugin management request, answering with the new plugin list
+pub fn admin(server: &Arc<Server>, client: &Client, req: Admin) -> crate::Result<()> {
+    if !permitted(server, client, Permission::ManagePlugins)? {
+        return Ok(());
+    }
+    let user = client.get_uuid()?;
+
+    let res = match req {
+        Admin::List => Ok(()),
+        Admin::Load(file) => load(server, file),
+        Admin::Unload(name) => server.unload_plugin(name),
+        Admin::Reload(name) => reload(server, name),
+    };
+
+    match res {
+        Ok(()) => client.send(ServerMessage::PluginList(server.plugin_list()))?,
+        Err(e) => {
+            LOGGER.error(format!("Plugin request by {user} failed: {e:#}"));
+            client.send(ResponseError::InvalidRequest(format!("{e:#}")))?
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "loader")]
+fn load(server: &Arc<Server>, file: &str) -> crate::Result<()> {
+    server.load_plugin_file(file).map(|_| ())
+}
+
+#[cfg(feature = "loader")]
+fn reload(server: &Arc<Server>, name: &str) -> crate::Result<()> {
+    server.reload_plugin(name)
+}
+
+#[cfg(not(feature = "loader"))]
+fn load(_server: &Arc<Server>, _file: &str) -> crate::Result<()> {
+    anyhow::bail!("This server was built without plugin loading")
+}
+
+#[cfg(not(feature = "loader"))]
+fn reload(_server: &Arc<Server>, _name: &str) -> crate::Result<()> {
+    anyhow::bail!("This server was built without plugin loading")
+}
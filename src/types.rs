@@ -13,6 +13,112 @@ pub mod data {
         pub from: Author,
         pub contents: String,
         pub timestamp: i64,
+        /// Sent by a bot account or a plugin
+        #[serde(default)]
+        pub bot: bool,
+    }
+
+    /// What a client may do, users get `ServerConfig::permissions`, admins get all of them
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum Permission {
+        SendMessages,
+        /// Edit and delete own messages
+        ManageOwnMessages,
+        PluginMessages,
+        ManagePlugins,
+        ManageBots,
+    }
+
+    impl Permission {
+        pub const ALL: [Permission; 5] = [
+            Permission::SendMessages,
+            Permission::ManageOwnMessages,
+            Permission::PluginMessages,
+            Permission::ManagePlugins,
+            Permission::ManageBots,
+        ];
+    }
+
+    impl std::fmt::Display for Permission {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match serde_json::to_value(self) {
+                Ok(serde_json::Value::String(s)) => f.write_str(&s),
+                _ => write!(f, "{self:?}"),
+            }
+        }
+    }
+
+    impl std::str::FromStr for Permission {
+        type Err = serde_json::Error;
+
+        /// Parse the snake case name of a permission
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            serde_json::from_value(serde_json::Value::String(s.to_string()))
+        }
+    }
+
+    /// A server-local bot account, as listed to admins
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct BotInfo {
+        /// Author id of the bot's messages
+        pub id: Author,
+        pub name: String,
+        pub permissions: Vec<Permission>,
+        pub created: i64,
+    }
+
+    /// An authenticated user with at least one connection
+    #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+    pub struct OnlineUser {
+        pub id: Author,
+        pub bot: bool,
+    }
+
+    /// A loaded plugin, as listed to admins
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct PluginInfo {
+        pub name: String,
+        /// Package version, only known for native plugins
+        pub version: Option<String>,
+        pub priority: i32,
+        /// Library in the `plugins` directory, `None` for plugins built into the server
+        pub file: Option<String>,
+    }
+
+    /// A chat command, as advertised to clients for autocomplete
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct CommandInfo {
+        /// Name without the leading `/`
+        pub name: String,
+        pub description: String,
+        pub args: Vec<CommandArg>,
+        /// Permission needed to run the command, besides `send_messages`
+        pub permission: Option<Permission>,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct CommandArg {
+        pub name: String,
+        pub description: String,
+        pub kind: ArgKind,
+        pub required: bool,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum ArgKind {
+        String,
+        Integer,
+        Number,
+        /// `true`, `false`, `yes`, `no`, `on` or `off`
+        Boolean,
+        /// A user id, optionally prefixed with `@`
+        User,
+        /// A configured channel id, optionally prefixed with `#`
+        Channel,
+        /// The rest of the input, only valid as the last argument
+        Rest,
     }
 
     #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -33,7 +139,7 @@ pub mod data {
 pub mod handshake {
     use serde::{Deserialize, Serialize};
 
-    use crate::types::data::Channel;
+    use crate::types::data::{Channel, CommandInfo};
 
     #[derive(Debug, Clone, Serialize, Deserialize)]
     pub struct ServerDetails {
@@ -41,6 +147,9 @@ pub mod handshake {
         pub name: String,
         pub id: String,
         pub channels: Vec<Channel>,
+        /// Chat commands, sent as `/<name> <args>` messages
+        #[serde(default)]
+        pub commands: Vec<CommandInfo>,
     }
 
     #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -74,6 +183,56 @@ pub mod message {
 
         /// Delete a message (if allowed)
         DeleteMessage { message_id: usize },
+
+        /// A request for a plugin, `data` holds the remaining params
+        Plugin {
+            plugin: String,
+            #[serde(flatten)]
+            data: serde_json::Map<String, serde_json::Value>,
+        },
+
+        /// List loaded plugins (admin only)
+        ListPlugins,
+
+        /// Load a library from the `plugins` directory (admin only)
+        LoadPlugin { file: String },
+
+        /// Unload a plugin (admin only)
+        UnloadPlugin { name: String },
+
+        /// Reload a native plugin from its library (admin only)
+        ReloadPlugin { name: String },
+
+        /// Create a bot account, answered with its token (admin only)
+        CreateBot {
+            name: String,
+            permissions: Vec<data::Permission>,
+        },
+
+        /// List bot accounts (admin only)
+        ListBots,
+
+        /// Delete a bot account and disconnect it (admin only)
+        DeleteBot { name: String },
+    }
+
+    impl ClientMessage {
+        /// Value of the `type` field
+        pub fn name(&self) -> &'static str {
+            match self {
+                Self::SendMessage { .. } => "send_message",
+                Self::EditMessage { .. } => "edit_message",
+                Self::DeleteMessage { .. } => "delete_message",
+                Self::Plugin { .. } => "plugin",
+                Self::ListPlugins => "list_plugins",
+                Self::LoadPlugin { .. } => "load_plugin",
+                Self::UnloadPlugin { .. } => "unload_plugin",
+                Self::ReloadPlugin { .. } => "reload_plugin",
+                Self::CreateBot { .. } => "create_bot",
+                Self::ListBots => "list_bots",
+                Self::DeleteBot { .. } => "delete_bot",
+            }
+        }
     }
 
     /// Messages sent *from the server* to the client
@@ -106,6 +265,9 @@ pub mod message {
         PresenceUpdate {
             user_id: Author,
             status: String,
+            /// Whether the user is a bot account
+            #[serde(default)]
+            bot: bool,
         },
 
         /// Typing indicator
@@ -113,6 +275,28 @@ pub mod message {
             user_id: Author,
             channel_id: String,
         },
+
+        /// An event defined by a plugin
+        Plugin {
+            plugin: String,
+            event: String,
+            data: serde_json::Value,
+        },
+
+        /// Loaded plugins, the answer to admin plugin requests
+        PluginList(Vec<data::PluginInfo>),
+
+        /// A new bot account, `token` is only ever shown once
+        BotCreated {
+            bot: data::BotInfo,
+            token: String,
+        },
+
+        /// Bot accounts, the answer to `ListBots` and `DeleteBot`
+        BotList(Vec<data::BotInfo>),
+
+        /// The chat commands changed, replaces the list from `ServerDetails`
+        Commands(Vec<data::CommandInfo>),
     }
 
     #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -123,11 +307,16 @@ pub mod message {
         Unauthorized(String),
         NotFound(String),
         InternalError(String),
+        /// Too many requests, `retry_after` is in milliseconds
+        RateLimited {
+            retry_after: u64,
+        },
     }
 
     /// WebSocket wrapper
-    #[derive(Debug, Clone)]
-    pub enum WsMessage<T: Serialize + for<'de> Deserialize<'de>> {
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(bound(deserialize = "T: serde::de::DeserializeOwned"))]
+    pub enum WsMessage<T: Serialize + serde::de::DeserializeOwned> {
         Message(T),
         Binary(Vec<u8>),
         String(String),
@@ -1,77 +1,251 @@
 use std::{
     hash::{Hash, Hasher},
     io::{self, Read, Write},
-    net::{SocketAddr, TcpStream},
+    net::{IpAddr, Shutdown, SocketAddr, TcpStream},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 use anyhow::anyhow;
 use serde::{Deserialize, Serialize};
 
-use crate::types::message::{ClientMessage, WsMessage};
+use crate::{
+    ServerConfig,
+    types::{
+        data::Permission,
+        message::{ClientMessage, WsMessage},
+    },
+    utils::deflate::{self, CompressionConfig},
+};
 
 pub mod handshake {
     use base64::Engine;
     use base64::engine::general_purpose::STANDARD as Base64;
     use sha1::{Digest, Sha1};
     use std::collections::HashMap;
-    use std::io::{BufRead, BufReader, Write};
-    use std::net::TcpStream;
+    use std::io::{BufRead, BufReader, Read, Write};
+
+    use super::Stream;
+    use crate::utils::deflate::{self, CompressionConfig};
 
     const WS_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
 
-    pub fn handle_websocket_handshake(stream: &mut TcpStream) -> std::io::Result<()> {
-        let mut reader = BufReader::new(stream.try_clone()?);
-        let mut request_line = String::new();
-        reader.read_line(&mut request_line)?;
+    /// An HTTP request read from a new connection
+    pub struct HttpRequest {
+        pub method: String,
+        /// Path and query, like `/api/health?verbose=1`
+        pub target: String,
+        /// Header names are lowercase
+        pub headers: HashMap<String, String>,
+        /// Holds the unread body
+        reader: BufReader<Stream>,
+    }
 
-        // Trim CRLF to make sure comparisons are clean
-        let request_line = request_line.trim_end();
+    impl HttpRequest {
+        /// Path without the query
+        pub fn path(&self) -> &str {
+            self.target.split_once('?').map_or(&self.target, |(p, _)| p)
+        }
 
-        // Allow HEAD (used by Render for health checks)
-        if request_line.starts_with("HEAD") {
-            let response = "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n";
-            stream.write_all(response.as_bytes())?;
-            stream.flush()?;
-            return Ok(());
+        /// Value of a query parameter, not percent-decoded
+        pub fn query(&self, name: &str) -> Option<&str> {
+            let (_, query) = self.target.split_once('?')?;
+            query
+                .split('&')
+                .filter_map(|pair| pair.split_once('=').or(Some((pair, ""))))
+                .find_map(|(k, v)| (k == name).then_some(v))
         }
 
-        // Only proceed if it’s a GET
-        if !request_line.starts_with("GET") {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("Invalid HTTP method: {request_line}"),
-            ));
+        pub fn header(&self, name: &str) -> Option<&str> {
+            self.headers.get(name).map(String::as_str)
         }
 
+        pub fn is_websocket_upgrade(&self) -> bool {
+            self.header("upgrade")
+                .is_some_and(|v| v.eq_ignore_ascii_case("websocket"))
+        }
+
+        /// Read the `Content-Length` body, `None` if it is longer than `max` bytes
+        pub fn read_body(&mut self, max: usize) -> std::io::Result<Option<Vec<u8>>> {
+            let len = match self.header("content-length") {
+                Some(len) => len.parse::<usize>().map_err(|_| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid Content-Length")
+                })?,
+                None => 0,
+            };
+            if len > max {
+                return Ok(None);
+            }
+
+            let mut body = vec![0; len];
+            self.reader.read_exact(&mut body)?;
+            Ok(Some(body))
+        }
+    }
+
+    /// Most bytes the request line and headers may take together
+    pub const MAX_HEADER_BYTES: u64 = 16 * 1024;
+
+    /// Most header lines a request may have
+    pub const MAX_HEADERS: usize = 100;
+
+    /// Read the request line and headers. Requests over `MAX_HEADER_BYTES` or `MAX_HEADERS`
+    /// are answered with 431 and malformed ones with 400 before the error is returned.
+    pub fn read_request(stream: &Stream) -> std::io::Result<HttpRequest> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut budget = MAX_HEADER_BYTES;
+        let mut read_line = |line: &mut String| -> std::io::Result<Option<usize>> {
+            let bytes = (&mut reader).take(budget).read_line(line)?;
+            budget -= bytes as u64;
+            // Out of budget before the end of the line
+            Ok((budget > 0 || line.ends_with('\n')).then_some(bytes))
+        };
+
+        let mut request_line = String::new();
+        if read_line(&mut request_line)?.is_none() {
+            return reject(stream, 431, "Request line too long");
+        }
+
+        // Trim CRLF to make sure comparisons are clean
+        let mut parts = request_line.trim_end().split(' ');
+        let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+            return reject(
+                stream,
+                400,
+                &format!("Invalid HTTP request line: {}", request_line.trim_end()),
+            );
+        };
+        let (method, target) = (method.to_string(), target.to_string());
+
         // Read headers
         let mut headers = HashMap::new();
         let mut line = String::new();
+        let mut count = 0;
         loop {
             line.clear();
-            let bytes = reader.read_line(&mut line)?;
+            let Some(bytes) = read_line(&mut line)? else {
+                return reject(stream, 431, "Request headers too large");
+            };
             if bytes == 0 || line == "\r\n" {
                 break;
             }
+
+            count += 1;
+            if count > MAX_HEADERS {
+                return reject(stream, 431, "Too many request headers");
+            }
             if let Some((k, v)) = line.split_once(':') {
                 headers.insert(k.trim().to_lowercase(), v.trim().to_string());
             }
         }
 
-        // Check if it's actually a WebSocket upgrade request
-        let is_websocket_upgrade = headers
-            .get("upgrade")
-            .map(|v| v.eq_ignore_ascii_case("websocket"))
-            .unwrap_or(false);
+        Ok(HttpRequest {
+            method,
+            target,
+            headers,
+            reader,
+        })
+    }
 
-        if !is_websocket_upgrade {
+    /// Answer a request that can't be read with `status`, returning `message` as the error
+    fn reject<T>(stream: &Stream, status: u16, message: &str) -> std::io::Result<T> {
+        let _ = write_response(
+            &mut stream.try_clone()?,
+            status,
+            &[("Content-Type", "text/plain")],
+            message.as_bytes(),
+        );
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            message,
+        ))
+    }
+
+    /// Write a complete response and ask the peer to close the connection
+    pub fn write_response(
+        stream: &mut Stream,
+        status: u16,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> std::io::Result<()> {
+        let mut response = format!(
+            "HTTP/1.1 {status} {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+            reason(status),
+            body.len()
+        );
+        for (k, v) in headers {
+            response += &format!("{k}: {v}\r\n");
+        }
+        response += "\r\n";
+
+        stream.write_all(response.as_bytes())?;
+        stream.write_all(body)?;
+        stream.flush()
+    }
+
+    fn reason(status: u16) -> &'static str {
+        match status {
+            200 => "OK",
+            201 => "Created",
+            204 => "No Content",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            413 => "Payload Too Large",
+            415 => "Unsupported Media Type",
+            429 => "Too Many Requests",
+            431 => "Request Header Fields Too Large",
+            500 => "Internal Server Error",
+            503 => "Service Unavailable",
+            _ => "Unknown",
+        }
+    }
+
+    /// Answer the HTTP upgrade request, returns whether `permessage-deflate` was negotiated
+    pub fn handle_websocket_handshake(
+        stream: &mut Stream,
+        compression: &CompressionConfig,
+    ) -> std::io::Result<bool> {
+        let request = read_request(stream)?;
+
+        // Allow HEAD (used by Render for health checks)
+        if request.method == "HEAD" {
+            let response = "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n";
+            stream.write_all(response.as_bytes())?;
+            stream.flush()?;
+            return Ok(false);
+        }
+
+        // Only proceed if it’s a GET
+        if request.method != "GET" {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Invalid HTTP method: {}", request.method),
+            ));
+        }
+
+        if !request.is_websocket_upgrade() {
             // Not a WebSocket request — probably a normal HTTP GET (e.g. health check)
             let response =
                 "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 2\r\n\r\nOK";
             stream.write_all(response.as_bytes())?;
             stream.flush()?;
-            return Ok(());
+            return Ok(false);
         }
 
+        accept_websocket(stream, &request, compression)
+    }
+
+    /// Answer a WebSocket upgrade request, returns whether `permessage-deflate` was negotiated
+    pub fn accept_websocket(
+        stream: &mut Stream,
+        request: &HttpRequest,
+        compression: &CompressionConfig,
+    ) -> std::io::Result<bool> {
+        let headers = &request.headers;
+
         // Validate "Connection: Upgrade"
         if !headers
             .get("connection")
@@ -90,13 +264,13 @@ pub mod handshake {
         })?;
 
         // Validate version
-        if let Some(ver) = headers.get("sec-websocket-version") {
-            if ver.trim() != "13" {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    format!("Unsupported Sec-WebSocket-Version: {}", ver),
-                ));
-            }
+        if let Some(ver) = headers.get("sec-websocket-version")
+            && ver.trim() != "13"
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Unsupported Sec-WebSocket-Version: {}", ver),
+            ));
         }
 
         // Compute accept key
@@ -106,99 +280,397 @@ pub mod handshake {
         let hash = hasher.finalize();
         let accept_key = Base64.encode(hash);
 
+        // Negotiate extensions
+        let extensions = headers
+            .get("sec-websocket-extensions")
+            .filter(|_| compression.enabled)
+            .and_then(|v| deflate::negotiate(v));
+
         // Send response
         let response = format!(
             "HTTP/1.1 101 Switching Protocols\r\n\
              Upgrade: websocket\r\n\
              Connection: Upgrade\r\n\
-             Sec-WebSocket-Accept: {}\r\n\r\n",
-            accept_key
+             Sec-WebSocket-Accept: {}\r\n{}\r\n",
+            accept_key,
+            match &extensions {
+                Some(ext) => format!("Sec-WebSocket-Extensions: {ext}\r\n"),
+                None => String::new(),
+            }
         );
 
         stream.write_all(response.as_bytes())?;
         stream.flush()?;
-        Ok(())
+        Ok(extensions.is_some())
+    }
+}
+
+/// An encrypted transport, see `utils::tls`
+pub trait SecureStream: Read + Write + Send + Sync {
+    fn try_clone(&self) -> io::Result<Box<dyn SecureStream>>;
+    fn tcp(&self) -> &TcpStream;
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+}
+
+/// Underlying transport of a client, plain TCP or TLS.
+/// TLS streams are boxed, so the layout doesn't depend on the `tls` feature.
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(Box<dyn SecureStream>),
+}
+
+impl Stream {
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(match self {
+            Stream::Plain(s) => Stream::Plain(s.try_clone()?),
+            Stream::Tls(s) => Stream::Tls(s.try_clone()?),
+        })
+    }
+
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(s) => s,
+            Stream::Tls(s) => s.tcp(),
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp().peer_addr()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp().local_addr()
+    }
+
+    pub fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.tcp().set_read_timeout(dur)
+    }
+
+    pub fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.tcp().set_write_timeout(dur)
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Stream::Plain(s) => s.shutdown(how),
+            Stream::Tls(s) => s.shutdown(how),
+        }
     }
 }
 
-pub struct Client(TcpStream, Option<String>, u64);
+impl From<TcpStream> for Stream {
+    fn from(s: TcpStream) -> Self {
+        Stream::Plain(s)
+    }
+}
+
+#[cfg(feature = "tls")]
+impl From<crate::utils::tls::TlsStream> for Stream {
+    fn from(s: crate::utils::tls::TlsStream) -> Self {
+        Stream::Tls(Box::new(s))
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(s) => s.read(buf),
+            Stream::Tls(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(s) => s.write(buf),
+            Stream::Tls(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(s) => s.flush(),
+            Stream::Tls(s) => s.flush(),
+        }
+    }
+}
+
+/// Size limits enforced on incoming messages
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LimitsConfig {
+    /// Maximum payload size of a single frame, in bytes
+    pub max_frame_size: usize,
+    /// Maximum size of a message reassembled from fragments, in bytes
+    pub max_message_size: usize,
+    /// Maximum length of a chat message's contents, in characters
+    pub max_content_length: usize,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_frame_size: 1 << 20,
+            max_message_size: 4 << 20,
+            max_content_length: 4000,
+        }
+    }
+}
+
+/// Keepalive pings and dead peer detection
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HeartbeatConfig {
+    /// Seconds without any incoming frame before a ping is sent
+    pub interval: u64,
+    /// Seconds without any incoming frame before the peer is considered dead
+    pub timeout: u64,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval: 10,
+            timeout: 30,
+        }
+    }
+}
+
+/// Progress of the closing handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseState {
+    Open,
+    /// A close frame was sent, waiting for the peer to answer
+    Closing,
+    /// Close frames were exchanged, no more frames may be sent
+    Closed,
+}
+
+/// State shared by every clone of a client
+struct Shared {
+    /// Serializes frame writes, so frames from different threads never interleave
+    writer: Mutex<()>,
+    close: Mutex<CloseState>,
+    /// Last time any frame was received
+    last_seen: Mutex<Instant>,
+}
+
+pub struct Client {
+    stream: Stream,
+    uuid: Option<String>,
+    /// Permissions of a bot account, `None` for users
+    bot: Option<Vec<Permission>>,
+    id: u64,
+    limits: LimitsConfig,
+    compression: CompressionConfig,
+    heartbeat: HeartbeatConfig,
+    /// Whether `permessage-deflate` was negotiated
+    deflate: bool,
+    shared: Arc<Shared>,
+}
+
+/// Whether a close code may be sent by a peer, see RFC 6455 section 7.4
+fn valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1011 | 3000..=4999)
+}
 
 impl Client {
     /// Create a client
-    pub fn new(mut stream: TcpStream) -> crate::Result<Self> {
-        handshake::handle_websocket_handshake(&mut stream)?;
-        stream.set_read_timeout(Some(std::time::Duration::from_secs(10)))?;
-        stream.set_write_timeout(Some(std::time::Duration::from_secs(10)))?;
-        Ok(Client(stream, None, rand::random()))
+    pub fn new(stream: impl Into<Stream>, config: &ServerConfig) -> crate::Result<Self> {
+        let mut stream = stream.into();
+        let deflate = handshake::handle_websocket_handshake(&mut stream, &config.compression)?;
+        Self::with_stream(stream, deflate, config)
+    }
+
+    /// Create a client from an upgrade request that was already read
+    pub fn accept(
+        mut stream: Stream,
+        request: &handshake::HttpRequest,
+        config: &ServerConfig,
+    ) -> crate::Result<Self> {
+        let deflate = handshake::accept_websocket(&mut stream, request, &config.compression)?;
+        Self::with_stream(stream, deflate, config)
     }
 
-    /// Send a close frame and flush. `code` is a WebSocket close code (e.g., 1000 normal).
+    fn with_stream(stream: Stream, deflate: bool, config: &ServerConfig) -> crate::Result<Self> {
+        stream.set_read_timeout(Some(Duration::from_secs(config.heartbeat.interval.max(1))))?;
+        stream.set_write_timeout(Some(Duration::from_secs(10)))?;
+        Ok(Client {
+            stream,
+            uuid: None,
+            bot: None,
+            id: rand::random(),
+            limits: config.limits,
+            compression: config.compression,
+            heartbeat: config.heartbeat,
+            deflate,
+            shared: Arc::new(Shared {
+                writer: Mutex::new(()),
+                close: Mutex::new(CloseState::Open),
+                last_seen: Mutex::new(Instant::now()),
+            }),
+        })
+    }
+
+    /// Size limits of this connection
+    pub fn limits(&self) -> &LimitsConfig {
+        &self.limits
+    }
+
+    /// Progress of the closing handshake
+    pub fn close_state(&self) -> CloseState {
+        *self.shared.close.lock().unwrap()
+    }
+
+    /// Start the closing handshake. `code` is a WebSocket close code (e.g., 1000 normal).
+    ///
+    /// Does nothing if a close frame was already sent.
     pub fn send_close(&self, code: u16, reason: &str) -> crate::Result<()> {
-        let mut stream = self.0.try_clone()?;
+        {
+            let mut state = self.shared.close.lock().unwrap();
+            if *state != CloseState::Open {
+                return Ok(());
+            }
+            *state = CloseState::Closing;
+        }
 
+        self.write_close(code, reason)
+    }
+
+    fn write_close(&self, code: u16, reason: &str) -> crate::Result<()> {
         // control frames must be <= 125 bytes
         let mut payload = Vec::new();
         payload.extend_from_slice(&code.to_be_bytes());
         payload.extend_from_slice(reason.as_bytes());
         if payload.len() > 125 {
-            return Err(anyhow!("close reason too long").into());
+            return Err(anyhow!("close reason too long"));
         }
 
-        let mut frame = Vec::with_capacity(2 + payload.len());
-        frame.push(0x88); // FIN=1, opcode=0x8 (Close)
-        frame.push(payload.len() as u8); // server->client MUST NOT mask
-        frame.extend_from_slice(&payload);
+        // FIN=1, opcode=0x8 (Close)
+        self.write_frame(0x88, &payload)
+    }
 
-        stream.write_all(&frame)?;
-        stream.flush()?;
-        Ok(())
+    /// Send a close frame and shut the connection down, any pending read returns `Ok(None)`
+    pub fn disconnect(&self, code: u16, reason: &str) -> crate::Result<()> {
+        let res = self.send_close(code, reason);
+        *self.shared.close.lock().unwrap() = CloseState::Closed;
+        self.stream.shutdown(Shutdown::Both)?;
+        res
     }
 
-    /// Send a ping (no payload)
-    fn send_ping(&self) -> crate::Result<()> {
-        let mut stream = self.0.try_clone()?;
-        // FIN + opcode (ping = 0x89), payload length = 0x00
-        stream.write_all(&[0x89, 0x00])?;
-        stream.flush()?;
-        Ok(())
+    /// Send a ping, the peer must answer with a pong carrying the same payload
+    pub fn send_ping(&self, payload: &[u8]) -> crate::Result<()> {
+        if payload.len() > 125 {
+            return Err(anyhow!("ping payload too long"));
+        }
+        // FIN=1, opcode=0x9 (Ping)
+        self.write_frame(0x89, payload)
     }
 
-    /// Send a pong (no payload)
-    fn send_pong(&self) -> crate::Result<()> {
-        let mut stream = self.0.try_clone()?;
-        // FIN + opcode (pong = 0x8A), payload length = 0x00
-        stream.write_all(&[0x8A, 0x00])?;
-        stream.flush()?;
-        Ok(())
+    /// Send a pong, echoing the payload of the ping it answers
+    fn send_pong(&self, payload: &[u8]) -> crate::Result<()> {
+        // FIN=1, opcode=0xA (Pong)
+        self.write_frame(0x8A, payload)
     }
 
     /// Send a text/binary frame (server->client must NOT mask)
     pub fn send<T: Serialize>(&self, m: T) -> crate::Result<()> {
-        let mut stream = self.0.try_clone()?;
+        self.send_text(&serde_json::to_string(&m)?)
+    }
 
-        let payload = serde_json::to_string(&m)?;
-        let payload_bytes = payload.as_bytes();
-        let len = payload_bytes.len();
+    /// Send a raw text message
+    pub fn send_text(&self, text: &str) -> crate::Result<()> {
+        // opcode=0x1 (text)
+        self.send_data(0x1, text.as_bytes())
+    }
 
-        let mut header = Vec::new();
-        header.push(0x81); // FIN=1, opcode=0x1 (text)
+    /// Send a binary message
+    pub fn send_binary(&self, data: &[u8]) -> crate::Result<()> {
+        // opcode=0x2 (binary)
+        self.send_data(0x2, data)
+    }
+
+    fn send_data(&self, opcode: u8, payload: &[u8]) -> crate::Result<()> {
+        if self.close_state() != CloseState::Open {
+            return Err(anyhow!("Client ({}) connection is closing", self.id));
+        }
+
+        if self.deflate && payload.len() >= self.compression.threshold {
+            // FIN=1, RSV1=1 (compressed)
+            self.write_frame(0xC0 | opcode, &deflate::compress(payload)?)
+        } else {
+            // FIN=1
+            self.write_frame(0x80 | opcode, payload)
+        }
+    }
+
+    /// Write a single unmasked frame, `first` holds the FIN/RSV bits and opcode
+    fn write_frame(&self, first: u8, payload: &[u8]) -> crate::Result<()> {
+        let mut stream = self.stream.try_clone()?;
+        let len = payload.len();
+
+        let mut frame = Vec::with_capacity(len + 10);
+        frame.push(first);
 
         if len < 126 {
-            header.push(len as u8);
+            frame.push(len as u8);
         } else if len <= 65535 {
-            header.push(126);
-            header.extend_from_slice(&(len as u16).to_be_bytes());
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
         } else {
-            header.push(127);
-            header.extend_from_slice(&(len as u64).to_be_bytes());
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
         }
 
-        stream.write_all(&header)?;
-        stream.write_all(payload_bytes)?;
+        frame.extend_from_slice(payload);
+
+        let _guard = self.shared.writer.lock().unwrap();
+        stream.write_all(&frame)?;
         stream.flush()?;
         Ok(())
     }
 
+    /// Fail the connection with a close code, see RFC 6455 section 7.1.7
+    fn fail(&self, code: u16, reason: &str) {
+        let _ = self.disconnect(code, reason);
+    }
+
+    /// Shut a dead or stalled peer's connection down without a closing handshake
+    fn drop_peer(&self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+        *self.shared.close.lock().unwrap() = CloseState::Closed;
+    }
+
+    /// Fill `buf` with the rest of a frame, read timeouts are retried until `deadline`.
+    /// Returns `false` if the peer stalled past it, its connection is then dropped.
+    fn read_frame_part(
+        &self,
+        stream: &mut Stream,
+        buf: &mut [u8],
+        deadline: Instant,
+    ) -> io::Result<bool> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            match stream.read(&mut buf[filled..]) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    if Instant::now() >= deadline {
+                        self.drop_peer();
+                        return Ok(false);
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+
     /// Read a full WebSocket message, handling fragmentation and control frames.
     ///
     /// Returns:
@@ -208,119 +680,231 @@ impl Client {
     pub fn read_t<T: Serialize + for<'de> Deserialize<'de>>(
         &self,
     ) -> crate::Result<Option<WsMessage<T>>> {
-        let mut stream = self.0.try_clone()?;
+        let mut stream = self.stream.try_clone()?;
 
         let mut message_payload = Vec::new();
+        // Opcode of the first frame of the message being reassembled
+        let mut message_opcode = None;
+        // Whether the message being reassembled is compressed (RSV1 on its first frame)
+        let mut compressed = false;
 
         loop {
-            // read the 2-byte header
+            // read the 2-byte header, a timeout only means the peer is idle before its first byte
             let mut header = [0u8; 2];
-            if let Err(e) = stream.read_exact(&mut header) {
+            if let Err(e) = stream.read_exact(&mut header[..1]) {
                 if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut {
-                    self.send_ping()?;
+                    let idle = self.shared.last_seen.lock().unwrap().elapsed();
+                    if idle >= Duration::from_secs(self.heartbeat.timeout)
+                        || self.close_state() != CloseState::Open
+                    {
+                        // Dead peer, or no answer to our close frame
+                        self.drop_peer();
+                        return Ok(None);
+                    }
+
+                    self.send_ping(&[])?;
                     continue;
                 }
 
-                if e.kind() == io::ErrorKind::UnexpectedEof || e.kind() == io::ErrorKind::BrokenPipe
+                if e.kind() == io::ErrorKind::UnexpectedEof
+                    || e.kind() == io::ErrorKind::BrokenPipe
+                    || e.kind() == io::ErrorKind::ConnectionReset
+                    || self.close_state() == CloseState::Closed
                 {
+                    *self.shared.close.lock().unwrap() = CloseState::Closed;
                     return Ok(None);
                 }
                 return Err(e.into());
             }
 
+            *self.shared.last_seen.lock().unwrap() = Instant::now();
+
+            // The rest of the frame may stall for longer than the heartbeat interval
+            let deadline = Instant::now() + Duration::from_secs(self.heartbeat.timeout);
+            if !self.read_frame_part(&mut stream, &mut header[1..], deadline)? {
+                return Ok(None);
+            }
+
             let fin = header[0] & 0x80 != 0;
+            let rsv1 = header[0] & 0x40 != 0;
             let opcode = header[0] & 0x0F;
+
+            // RSV1 is only meaningful with permessage-deflate, RSV2/RSV3 are never used
+            if header[0] & 0x30 != 0 || (rsv1 && (!self.deflate || !matches!(opcode, 0x1 | 0x2))) {
+                self.fail(1002, "Unexpected reserved bits");
+                return Ok(None);
+            }
+
             let masked = header[1] & 0x80 != 0;
             let mut payload_len = (header[1] & 0x7F) as u64;
 
             // Extended payload lengths
             if payload_len == 126 {
                 let mut ext_len = [0u8; 2];
-                stream.read_exact(&mut ext_len)?;
+                if !self.read_frame_part(&mut stream, &mut ext_len, deadline)? {
+                    return Ok(None);
+                }
                 payload_len = u16::from_be_bytes(ext_len) as u64;
             } else if payload_len == 127 {
                 let mut ext_len = [0u8; 8];
-                stream.read_exact(&mut ext_len)?;
+                if !self.read_frame_part(&mut stream, &mut ext_len, deadline)? {
+                    return Ok(None);
+                }
                 payload_len = u64::from_be_bytes(ext_len);
             }
 
             // Mask key (client→server MUST be masked)
             let mut mask = [0u8; 4];
             if masked {
-                stream.read_exact(&mut mask)?;
+                if !self.read_frame_part(&mut stream, &mut mask, deadline)? {
+                    return Ok(None);
+                }
             } else {
-                let _ = self.send_close(1002, "Client frames must be masked");
+                self.fail(1002, "Client frames must be masked");
                 return Ok(None);
             }
 
             // Control frame checks
-            if matches!(opcode, 0x8 | 0x9 | 0xA) {
+            if opcode >= 0x8 {
                 if payload_len > 125 {
-                    let _ = self.send_close(1002, "Control frame too large");
+                    self.fail(1002, "Control frame too large");
                     return Ok(None);
                 }
                 if !fin {
-                    let _ = self.send_close(1002, "Control frames must not be fragmented");
+                    self.fail(1002, "Control frames must not be fragmented");
                     return Ok(None);
                 }
             }
 
+            // Size checks, before anything gets allocated
+            if payload_len > self.limits.max_frame_size as u64 {
+                self.fail(1009, "Frame too large");
+                return Ok(None);
+            }
+            if message_payload.len() as u64 + payload_len > self.limits.max_message_size as u64 {
+                self.fail(1009, "Message too large");
+                return Ok(None);
+            }
+
             // Read payload + unmask
             let mut payload = vec![0u8; payload_len as usize];
             if payload_len > 0 {
-                stream.read_exact(&mut payload)?;
-                for i in 0..payload.len() {
-                    payload[i] ^= mask[i % 4];
+                if !self.read_frame_part(&mut stream, &mut payload, deadline)? {
+                    return Ok(None);
+                }
+                for (i, b) in payload.iter_mut().enumerate() {
+                    *b ^= mask[i % 4];
                 }
             }
 
             match opcode {
-                0x0 | 0x1 | 0x2 => {
+                0x0..=0x2 => {
                     // Continuation / Text / Binary
+                    match (opcode, message_opcode) {
+                        (0x0, None) => {
+                            self.fail(1002, "Unexpected continuation frame");
+                            return Ok(None);
+                        }
+                        (0x1 | 0x2, Some(_)) => {
+                            self.fail(1002, "Expected continuation frame");
+                            return Ok(None);
+                        }
+                        (0x1 | 0x2, None) => {
+                            message_opcode = Some(opcode);
+                            compressed = rsv1;
+                        }
+                        _ => {}
+                    }
                     message_payload.extend(payload);
-                    if fin {
-                        break;
-                    } else {
+                    if !fin {
                         continue;
                     }
+
+                    // Data received after our close frame is discarded
+                    if self.close_state() != CloseState::Open {
+                        message_payload.clear();
+                        message_opcode = None;
+                        continue;
+                    }
+
+                    break;
                 }
                 0x8 => {
                     // Close
-                    let (code, reason) = if payload.len() >= 2 {
-                        let code = u16::from_be_bytes([payload[0], payload[1]]);
-                        let reason = if payload.len() > 2 {
-                            String::from_utf8_lossy(&payload[2..]).into_owned()
-                        } else {
-                            String::new()
+                    let echo = match payload.len() {
+                        0 => Ok((1000, String::new())),
+                        1 => Err((1002, "Invalid close frame")),
+                        _ => {
+                            let code = u16::from_be_bytes([payload[0], payload[1]]);
+                            match std::str::from_utf8(&payload[2..]) {
+                                _ if !valid_close_code(code) => Err((1002, "Invalid close code")),
+                                Ok(reason) => Ok((code, reason.to_string())),
+                                Err(_) => Err((1007, "Invalid UTF-8 in close reason")),
+                            }
+                        }
+                    };
+
+                    let mut state = self.shared.close.lock().unwrap();
+                    if *state == CloseState::Open {
+                        *state = CloseState::Closed;
+                        drop(state);
+                        let _ = match echo {
+                            Ok((code, reason)) => self.write_close(code, &reason),
+                            Err((code, reason)) => self.write_close(code, reason),
                         };
-                        (code, reason)
                     } else {
-                        (1000, String::new())
-                    };
-                    let _ = self.send_close(code, &reason);
+                        *state = CloseState::Closed;
+                    }
+
+                    // The server closes the TCP connection first
+                    let _ = self.stream.shutdown(Shutdown::Both);
                     return Ok(None);
                 }
                 0x9 => {
-                    self.send_pong()?;
+                    if self.close_state() == CloseState::Open {
+                        self.send_pong(&payload)?;
+                    }
                     continue;
                 }
                 0xA => {
                     continue;
                 }
                 _ => {
-                    let _ = self.send_close(1002, "Unsupported opcode");
+                    self.fail(1002, "Unsupported opcode");
                     return Ok(None);
                 }
             }
         }
 
+        if compressed {
+            message_payload =
+                match deflate::decompress(&message_payload, self.limits.max_message_size) {
+                    Ok(Some(p)) => p,
+                    Ok(None) => {
+                        self.fail(1009, "Message too large");
+                        return Ok(None);
+                    }
+                    Err(_) => {
+                        self.fail(1007, "Invalid compressed message");
+                        return Ok(None);
+                    }
+                };
+        }
+
+        if message_opcode == Some(0x2) {
+            return Ok(Some(WsMessage::Binary(message_payload)));
+        }
+
+        // Text frames must be valid UTF-8
+        let Ok(text) = String::from_utf8(message_payload) else {
+            self.fail(1007, "Invalid UTF-8 in text message");
+            return Ok(None);
+        };
+
         // Try parsing JSON into ClientMessage
-        let message = match String::from_utf8(message_payload.clone()) {
-            Ok(text) => match serde_json::from_str(&text) {
-                Ok(msg) => WsMessage::Message(msg),
-                Err(_) => WsMessage::String(text),
-            },
-            Err(_) => WsMessage::Binary(message_payload),
+        let message = match serde_json::from_str(&text) {
+            Ok(msg) => WsMessage::Message(msg),
+            Err(_) => WsMessage::String(text),
         };
 
         Ok(Some(message))
@@ -337,35 +921,66 @@ impl Client {
     }
 
     pub fn get_uuid(&self) -> crate::Result<String> {
-        match &self.1 {
+        match &self.uuid {
             Some(v) => Ok(v.clone()),
-            None => Err(anyhow!("Client ({}) UUID not set", self.2).into()),
+            None => Err(anyhow!("Client ({}) UUID not set", self.id)),
         }
     }
 
     pub fn set_uuid(&mut self, uuid: &str) {
-        self.1 = Some(uuid.to_string())
+        self.uuid = Some(uuid.to_string())
+    }
+
+    /// Authenticate as the bot account `id`
+    pub fn set_bot(&mut self, id: &str, permissions: Vec<Permission>) {
+        self.uuid = Some(id.to_string());
+        self.bot = Some(permissions);
+    }
+
+    pub fn is_bot(&self) -> bool {
+        self.bot.is_some()
+    }
+
+    /// Permissions granted to a bot account, `None` for users
+    pub fn bot_permissions(&self) -> Option<&[Permission]> {
+        self.bot.as_deref()
+    }
+
+    /// Unique id of this connection
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// IP address of the remote peer
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.stream.peer_addr().ok().map(|a| a.ip())
     }
 
     #[deprecated]
     pub fn addr(&self) -> crate::Result<SocketAddr> {
-        Ok(self.0.peer_addr().unwrap_or(self.0.local_addr()?))
+        Ok(self.stream.peer_addr().unwrap_or(self.stream.local_addr()?))
     }
 }
 
 impl Clone for Client {
     fn clone(&self) -> Self {
-        Client(
-            self.0.try_clone().expect("failed to clone TcpStream"),
-            self.1.clone(),
-            self.2.clone(),
-        )
+        Client {
+            stream: self.stream.try_clone().expect("failed to clone stream"),
+            uuid: self.uuid.clone(),
+            bot: self.bot.clone(),
+            id: self.id,
+            limits: self.limits,
+            compression: self.compression,
+            heartbeat: self.heartbeat,
+            deflate: self.deflate,
+            shared: self.shared.clone(),
+        }
     }
 }
 
 impl PartialEq for Client {
     fn eq(&self, other: &Self) -> bool {
-        self.2 == other.2
+        self.id == other.id
     }
 }
 
@@ -373,6 +988,6 @@ impl Eq for Client {}
 
 impl Hash for Client {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        self.2.hash(state);
+        self.id.hash(state);
     }
 }
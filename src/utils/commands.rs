@@ -0,0 +1,380 @@
+//! Chat commands, sent as `/<name> <args>` messages and answered with `TempMessage`
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, PoisonError, RwLock},
+};
+
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+
+use crate::{
+    Server,
+    types::{
+        data::{ArgKind, Channel, CommandArg, CommandInfo, Permission},
+        message::ServerMessage,
+    },
+    utils::{client::Client, plugin::catch_panic},
+};
+
+crate::logger!(LOGGER "Commands");
+
+/// What a command answers, `Err` for usage errors
+pub type CommandResult = Result<String, String>;
+
+/// A command handler, `Err` holds the message of a panic it caught
+pub(crate) type Handler = dyn Fn(&CommandContext) -> Result<CommandResult, String> + Send + Sync;
+
+/// Describes a command before it is registered
+pub struct Command {
+    info: CommandInfo,
+}
+
+impl Command {
+    pub fn new(name: &str, description: &str) -> Self {
+        Self {
+            info: CommandInfo {
+                name: name.to_string(),
+                description: description.to_string(),
+                args: Vec::new(),
+                permission: None,
+            },
+        }
+    }
+
+    /// Add a required argument
+    pub fn arg(mut self, name: &str, kind: ArgKind, description: &str) -> Self {
+        self.push_arg(name, kind, description, true);
+        self
+    }
+
+    /// Add an optional argument, it may only be followed by other optional ones
+    pub fn optional(mut self, name: &str, kind: ArgKind, description: &str) -> Self {
+        self.push_arg(name, kind, description, false);
+        self
+    }
+
+    /// Only let clients with `permission` run the command
+    pub fn permission(mut self, permission: Permission) -> Self {
+        self.info.permission = Some(permission);
+        self
+    }
+
+    fn push_arg(&mut self, name: &str, kind: ArgKind, description: &str, required: bool) {
+        self.info.args.push(CommandArg {
+            name: name.to_string(),
+            description: description.to_string(),
+            kind,
+            required,
+        });
+    }
+
+    fn validate(&self) -> crate::Result<()> {
+        let info = &self.info;
+        if !(1..=32).contains(&info.name.len())
+            || !info
+                .name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+        {
+            anyhow::bail!(
+                "Command names must be 1 to 32 lowercase letters, digits, `-` or `_`, got {}",
+                info.name
+            );
+        }
+
+        for (i, arg) in info.args.iter().enumerate() {
+            if arg.kind == ArgKind::Rest && i + 1 != info.args.len() {
+                anyhow::bail!(
+                    "Argument {} of /{} must be the last one",
+                    arg.name,
+                    info.name
+                );
+            }
+            if arg.required && i > 0 && !info.args[i - 1].required {
+                anyhow::bail!(
+                    "Required argument {} of /{} follows an optional one",
+                    arg.name,
+                    info.name
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A command invocation
+pub struct CommandContext<'a> {
+    pub server: &'a Arc<Server>,
+    pub client: &'a Client,
+    /// Channel the command was sent to
+    pub channel_id: &'a str,
+    /// Parsed arguments by name, missing optional ones are left out
+    pub args: Map<String, Value>,
+}
+
+impl CommandContext<'_> {
+    /// Get an argument, `None` if it is missing or has another type
+    pub fn arg<T: DeserializeOwned>(&self, name: &str) -> Option<T> {
+        serde_json::from_value(self.args.get(name)?.clone()).ok()
+    }
+}
+
+struct Registered {
+    info: CommandInfo,
+    /// Plugin that registered the command, `None` for built-in ones
+    owner: Option<String>,
+    handler: Arc<Handler>,
+}
+
+/// Registry of the chat commands
+#[derive(Default)]
+pub struct Commands {
+    map: RwLock<BTreeMap<String, Registered>>,
+}
+
+impl Commands {
+    /// Register a command, failing if its name is taken
+    pub(crate) fn register<F>(
+        &self,
+        owner: Option<&str>,
+        command: Command,
+        f: F,
+    ) -> crate::Result<()>
+    where
+        F: Fn(&CommandContext) -> CommandResult + Send + Sync + 'static,
+    {
+        command.validate()?;
+
+        let handler = move |ctx: &CommandContext| catch_panic(|| f(ctx));
+
+        let mut map = self.map.write().unwrap_or_else(PoisonError::into_inner);
+        let name = command.info.name.clone();
+        if map.contains_key(&name) {
+            anyhow::bail!("Command /{name} is already registered");
+        }
+        map.insert(
+            name,
+            Registered {
+                info: command.info,
+                owner: owner.map(str::to_string),
+                handler: Arc::new(handler),
+            },
+        );
+        Ok(())
+    }
+
+    /// Remove a command registered by `owner`, returns its handler, which may still be running
+    pub(crate) fn unregister(&self, owner: Option<&str>, name: &str) -> Option<Arc<Handler>> {
+        let mut map = self.map.write().unwrap_or_else(PoisonError::into_inner);
+        if map.get(name)?.owner.as_deref() != owner {
+            return None;
+        }
+        map.remove(name).map(|r| r.handler)
+    }
+
+    /// Remove every command of a plugin, returns their handlers
+    pub(crate) fn remove_owner(&self, owner: &str) -> Vec<Arc<Handler>> {
+        let mut map = self.map.write().unwrap_or_else(PoisonError::into_inner);
+        map.extract_if(.., |_, r| r.owner.as_deref() == Some(owner))
+            .map(|(_, r)| r.handler)
+            .collect()
+    }
+
+    /// Every command, ordered by name
+    pub fn list(&self) -> Vec<CommandInfo> {
+        let map = self.map.read().unwrap_or_else(PoisonError::into_inner);
+        map.values().map(|r| r.info.clone()).collect()
+    }
+
+    fn get(&self, name: &str) -> Option<(CommandInfo, Arc<Handler>)> {
+        let map = self.map.read().unwrap_or_else(PoisonError::into_inner);
+        map.get(name).map(|r| (r.info.clone(), r.handler.clone()))
+    }
+}
+
+/// Usage line of a command, like `/kick <user> [reason...]`
+pub fn usage(info: &CommandInfo) -> String {
+    let mut usage = format!("/{}", info.name);
+    for arg in &info.args {
+        let rest = if arg.kind == ArgKind::Rest { "..." } else { "" };
+        if arg.required {
+            usage += &format!(" <{}{rest}>", arg.name);
+        } else {
+            usage += &format!(" [{}{rest}]", arg.name);
+        }
+    }
+    usage
+}
+
+/// Split the command name off the contents of a message after the `/`,
+/// the name is empty if whitespace follows the `/`
+pub fn split_name(input: &str) -> (&str, &str) {
+    input.split_once(char::is_whitespace).unwrap_or((input, ""))
+}
+
+/// Split off the next word, a `"quoted string"` may contain spaces and `\"`
+pub fn next_word(input: &mut &str) -> Option<String> {
+    *input = input.trim_start();
+    if input.is_empty() {
+        return None;
+    }
+
+    let Some(quoted) = input.strip_prefix('"') else {
+        let end = input.find(char::is_whitespace).unwrap_or(input.len());
+        let (word, rest) = input.split_at(end);
+        *input = rest;
+        return Some(word.to_string());
+    };
+
+    let mut word = String::new();
+    let mut chars = quoted.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                if let Some((_, c)) = chars.next() {
+                    word.push(c);
+                }
+            }
+            '"' => {
+                *input = &quoted[i + 1..];
+                return Some(word);
+            }
+            c => word.push(c),
+        }
+    }
+
+    // Unterminated quotes take the rest of the input
+    *input = "";
+    Some(word)
+}
+
+fn parse_arg(channels: &[Channel], arg: &CommandArg, word: String) -> Result<Value, String> {
+    let invalid = |what: &str| format!("{} must be {what}, got {word}", arg.name);
+    Ok(match arg.kind {
+        ArgKind::String | ArgKind::Rest => Value::String(word),
+        ArgKind::Integer => word
+            .parse::<i64>()
+            .map_err(|_| invalid("a whole number"))?
+            .into(),
+        ArgKind::Number => word
+            .parse::<f64>()
+            .ok()
+            .filter(|n| n.is_finite())
+            .ok_or_else(|| invalid("a number"))?
+            .into(),
+        ArgKind::Boolean => match word.to_lowercase().as_str() {
+            "true" | "yes" | "on" => true.into(),
+            "false" | "no" | "off" => false.into(),
+            _ => return Err(invalid("true or false")),
+        },
+        ArgKind::User => word.strip_prefix('@').unwrap_or(&word).into(),
+        ArgKind::Channel => {
+            let id = word.strip_prefix('#').unwrap_or(&word);
+            if !channels.iter().any(|c| c.id == id) {
+                return Err(invalid("a channel"));
+            }
+            id.into()
+        }
+    })
+}
+
+/// Parse the arguments of a command from the input after its name,
+/// `Channel` arguments must name one of `channels`
+pub fn parse_args(
+    channels: &[Channel],
+    info: &CommandInfo,
+    mut input: &str,
+) -> Result<Map<String, Value>, String> {
+    let mut args = Map::new();
+    for arg in &info.args {
+        let word = if arg.kind == ArgKind::Rest {
+            Some(input.trim().to_string()).filter(|s| !s.is_empty())
+        } else {
+            next_word(&mut input)
+        };
+
+        match word {
+            Some(word) => {
+                args.insert(arg.name.clone(), parse_arg(channels, arg, word)?);
+            }
+            None if arg.required => return Err(format!("Missing argument {}", arg.name)),
+            None => break,
+        }
+    }
+
+    if !input.trim().is_empty() && info.args.last().is_none_or(|a| a.kind != ArgKind::Rest) {
+        return Err("Too many arguments".to_string());
+    }
+
+    Ok(args)
+}
+
+/// Run the command in `input`, the contents of a message after the `/`
+pub fn run(
+    server: &Arc<Server>,
+    client: &Client,
+    channel_id: &str,
+    input: &str,
+) -> crate::Result<()> {
+    let reply = |message: String| client.send(ServerMessage::TempMessage { message });
+
+    let (name, rest) = split_name(input);
+    let Some((info, handler)) = server.commands.get(name) else {
+        return reply(format!("Unknown command /{name}, see /help"));
+    };
+
+    if let Some(permission) = info.permission
+        && !server.has_permission(client, permission)
+    {
+        return reply(format!("/{name} needs the {permission} permission"));
+    }
+
+    let args = match parse_args(&server.config.channels, &info, rest) {
+        Ok(args) => args,
+        Err(e) => return reply(format!("{e}\nUsage: {}", usage(&info))),
+    };
+
+    let ctx = CommandContext {
+        server,
+        client,
+        channel_id,
+        args,
+    };
+    match handler(&ctx) {
+        Ok(Ok(message)) if message.is_empty() => Ok(()),
+        Ok(Ok(message) | Err(message)) => reply(message),
+        Err(panic) => {
+            LOGGER.error(format!("Command /{name} panicked: {panic}"));
+            reply(format!("Command /{name} failed"))
+        }
+    }
+}
+
+/// The built-in `/help` command
+pub(crate) fn help(ctx: &CommandContext) -> CommandResult {
+    let commands = ctx.server.commands.list();
+    if let Some(name) = ctx.arg::<String>("command") {
+        let name = name.trim_start_matches('/');
+        let Some(info) = commands.iter().find(|c| c.name == name) else {
+            return Err(format!("Unknown command /{name}"));
+        };
+
+        let mut help = format!("{}\n{}", usage(info), info.description);
+        for arg in &info.args {
+            help += &format!("\n  {}: {}", arg.name, arg.description);
+        }
+        return Ok(help);
+    }
+
+    let lines: Vec<String> = commands
+        .iter()
+        .filter(|c| {
+            c.permission
+                .is_none_or(|p| ctx.server.has_permission(ctx.client, p))
+        })
+        .map(|c| format!("{} - {}", usage(c), c.description))
+        .collect();
+    Ok(lines.join("\n"))
+}
@@ -1,12 +1,89 @@
-use crate::{ServerConfig, types::data::Message};
-use rusqlite::{Connection, Result, params};
+use std::{
+    sync::{
+        Arc, Mutex, MutexGuard, PoisonError,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Instant,
+};
 
-pub struct Database(Connection);
+use crate::{
+    types::data::{BotInfo, Message},
+    utils::{metrics, webhooks::QueuedWebhook},
+};
+use rusqlite::{
+    Connection, OpenFlags, OptionalExtension, Result,
+    fallible_iterator::FallibleIterator,
+    hooks::{AuthAction, AuthContext, Authorization},
+    params,
+    trace::{TraceEvent, TraceEventCodes},
+};
+
+/// A SQLite connection, shared by the connection, webhook and scheduler threads
+pub struct Database {
+    conn: Mutex<Connection>,
+    /// Lets a restricted connection run the server's own statements, see `trusted`
+    trusted: Arc<AtomicBool>,
+    /// Table prefix of a restricted connection
+    prefix: Option<String>,
+    /// What the authorizer of a restricted connection saw of the statement being prepared
+    prepared: Arc<Mutex<Prepared>>,
+}
+
+impl From<Connection> for Database {
+    fn from(conn: Connection) -> Self {
+        Self {
+            conn: Mutex::new(conn),
+            trusted: Arc::new(AtomicBool::new(false)),
+            prefix: None,
+            prepared: Arc::default(),
+        }
+    }
+}
+
+/// Schema access granted to the statement being prepared on a restricted connection
+#[derive(Default, Clone, Copy, PartialEq)]
+enum SchemaAccess {
+    #[default]
+    None,
+    /// Creating an owned table looks up its row
+    RowId,
+    /// Altering or dropping an owned table rewrites the schema
+    Full,
+}
+
+/// State of the statement being prepared on a restricted connection, reset before each one
+#[derive(Default)]
+struct Prepared {
+    schema: SchemaAccess,
+    /// Owned table of an ALTER TABLE, whose new name the authorizer can't see
+    altered: Option<String>,
+    /// Owned table being dropped, which deletes its row of `sqlite_sequence` if it has one
+    dropping: Option<String>,
+}
 
 // General use case
 impl Database {
-    pub fn new(_config: &ServerConfig) -> Option<Self> {
-        let conn = Connection::open("main.db").ok()?;
+    /// Lock the connection, recovering it if a thread panicked while holding it
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Open or create `main.db` in the server root
+    pub fn new(root: &std::path::Path) -> Option<Self> {
+        Self::open(root.join("main.db"))
+    }
+
+    /// Open or create the database at `path`
+    pub fn open(path: impl AsRef<std::path::Path>) -> Option<Self> {
+        let conn = Connection::open(path).ok()?;
+        conn.trace_v2(
+            TraceEventCodes::SQLITE_TRACE_PROFILE,
+            Some(|event| {
+                if let TraceEvent::Profile(stmt, duration) = event {
+                    metrics::observe_query(&stmt.sql(), duration);
+                }
+            }),
+        );
 
         conn.execute(
             "CREATE TABLE IF NOT EXISTS chat (
@@ -14,13 +91,649 @@ impl Database {
                   channel_id  TEXT NOT NULL,
                   user_id     TEXT NOT NULL,
                   contents    TEXT NOT NULL,
-                  timestamp   INTEGER NOT NULL
+                  timestamp   INTEGER NOT NULL,
+                  bot         INTEGER NOT NULL DEFAULT 0
+                )",
+            [],
+        )
+        .ok()?;
+
+        // Databases created before bot accounts lack the `bot` column
+        let has_bot: bool = conn
+            .query_row(
+                "SELECT COUNT(*) > 0 FROM pragma_table_info('chat') WHERE name = 'bot'",
+                [],
+                |row| row.get(0),
+            )
+            .ok()?;
+        if !has_bot {
+            conn.execute(
+                "ALTER TABLE chat ADD COLUMN bot INTEGER NOT NULL DEFAULT 0",
+                [],
+            )
+            .ok()?;
+        }
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS bots (
+                  id          TEXT PRIMARY KEY,
+                  name        TEXT NOT NULL UNIQUE,
+                  token_hash  TEXT NOT NULL UNIQUE,
+                  permissions TEXT NOT NULL,
+                  created     INTEGER NOT NULL
+                )",
+            [],
+        )
+        .ok()?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS webhook_queue (
+                  id           INTEGER PRIMARY KEY AUTOINCREMENT,
+                  target       TEXT NOT NULL DEFAULT '',
+                  url          TEXT NOT NULL,
+                  event        TEXT NOT NULL,
+                  body         TEXT NOT NULL,
+                  attempts     INTEGER NOT NULL DEFAULT 0,
+                  next_attempt INTEGER NOT NULL
+                )",
+            [],
+        )
+        .ok()?;
+
+        // Queues created before target ids only know the url
+        let has_target: bool = conn
+            .query_row(
+                "SELECT COUNT(*) > 0 FROM pragma_table_info('webhook_queue') WHERE name = 'target'",
+                [],
+                |row| row.get(0),
+            )
+            .ok()?;
+        if !has_target {
+            conn.execute(
+                "ALTER TABLE webhook_queue ADD COLUMN target TEXT NOT NULL DEFAULT ''",
+                [],
+            )
+            .ok()?;
+        }
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS plugin_migrations (
+                  plugin      TEXT NOT NULL,
+                  version     INTEGER NOT NULL,
+                  PRIMARY KEY (plugin, version)
                 )",
             [],
         )
         .ok()?;
 
-        Some(Database(conn))
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS plugin_kv (
+                  plugin      TEXT NOT NULL,
+                  key         TEXT NOT NULL,
+                  value       TEXT NOT NULL,
+                  PRIMARY KEY (plugin, key)
+                )",
+            [],
+        )
+        .ok()?;
+
+        Some(Database::from(conn))
+    }
+
+    /// Check that the database file answers queries
+    pub fn ping(&self) -> Result<()> {
+        // `SELECT 1` alone doesn't read the file
+        self.conn()
+            .query_row("SELECT COUNT(*) FROM sqlite_master", [], |_| Ok(()))
+    }
+
+    /// Write any pending changes to disk
+    pub fn flush(&self) -> Result<()> {
+        self.conn().cache_flush()
+    }
+
+    /// Open a second connection to the same database that can only read `chat`
+    /// and tables named `prefix*`
+    pub fn read_only(&self, prefix: &str) -> Result<Self> {
+        let path = self.conn().path().unwrap_or_default().to_string();
+        let conn = Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        conn.pragma_update(None, "query_only", true)?;
+
+        let prefix = prefix.to_lowercase();
+        conn.authorizer(Some(move |ctx: AuthContext<'_>| {
+            let allowed = match ctx.action {
+                AuthAction::Select | AuthAction::Recursive | AuthAction::Function { .. } => true,
+                AuthAction::Read { table_name, .. } => {
+                    ctx.database_name == Some("main")
+                        && (table_name == "chat" || table_name.to_lowercase().starts_with(&prefix))
+                }
+                _ => false,
+            };
+
+            if allowed {
+                Authorization::Allow
+            } else {
+                Authorization::Deny
+            }
+        }));
+        Ok(Database::from(conn))
+    }
+
+    /// Interrupt statements still running at `deadline`, until it is set to `None`
+    pub fn set_deadline(&self, deadline: Option<Instant>) {
+        // Checked every 1000 virtual machine instructions
+        self.conn().progress_handler(
+            1000,
+            deadline.map(|deadline| move || Instant::now() >= deadline),
+        );
+    }
+
+    /// Open a second connection to the same database that can only use tables named `prefix*`
+    pub fn restricted(&self, prefix: &str) -> Result<Self> {
+        let path = self.conn().path().unwrap_or_default().to_string();
+        let conn = Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+
+        let mut db = Database::from(conn);
+        let prefix = prefix.to_lowercase();
+        db.prefix = Some(prefix.clone());
+        let trusted = db.trusted.clone();
+        let prepared = db.prepared.clone();
+        db.conn().authorizer(Some(move |ctx: AuthContext<'_>| {
+            if trusted.load(Ordering::SeqCst) {
+                return Authorization::Allow;
+            }
+
+            let owned = |table: &str| table.to_lowercase().starts_with(&prefix);
+            let schema = |table: &str| {
+                matches!(
+                    table,
+                    "sqlite_master" | "sqlite_schema" | "sqlite_temp_master" | "sqlite_temp_schema"
+                )
+            };
+            let mut prepared = prepared.lock().unwrap_or_else(PoisonError::into_inner);
+
+            // SQLite refuses direct writes to the schema, so writing it only happens while tables
+            // and indexes are created or dropped. Reading it reveals the tables of other plugins,
+            // and is only allowed to the statements changing an owned table.
+            let allowed = match ctx.action {
+                AuthAction::Insert { table_name }
+                | AuthAction::Update { table_name, .. }
+                | AuthAction::Delete { table_name }
+                    if schema(table_name) =>
+                {
+                    true
+                }
+                AuthAction::Read {
+                    table_name,
+                    column_name,
+                } if schema(table_name) => match prepared.schema {
+                    SchemaAccess::Full => true,
+                    SchemaAccess::RowId => column_name == "ROWID",
+                    SchemaAccess::None => false,
+                },
+                // The database of ALTER TABLE is part of the action
+                AuthAction::AlterTable {
+                    database_name,
+                    table_name,
+                } => {
+                    let allowed = database_name == "main" && owned(table_name);
+                    if allowed {
+                        prepared.altered = Some(table_name.to_string());
+                        prepared.schema = SchemaAccess::Full;
+                    }
+                    allowed
+                }
+                _ if ctx.database_name.is_some_and(|db| db != "main") => false,
+                // Dropping a table with AUTOINCREMENT deletes its row, renaming it renames the row
+                AuthAction::Delete {
+                    table_name: "sqlite_sequence",
+                } => prepared.dropping.is_some(),
+                AuthAction::Update {
+                    table_name: "sqlite_sequence",
+                    ..
+                } => prepared.altered.is_some(),
+                AuthAction::Read {
+                    table_name: "sqlite_sequence",
+                    ..
+                } => prepared.dropping.is_some() || prepared.altered.is_some(),
+                AuthAction::Delete { table_name }
+                    if prepared.dropping.as_deref() == Some(table_name) =>
+                {
+                    true
+                }
+                AuthAction::Select
+                | AuthAction::Recursive
+                | AuthAction::Function { .. }
+                | AuthAction::Transaction { .. }
+                | AuthAction::Savepoint { .. } => true,
+                AuthAction::Read { table_name, .. }
+                | AuthAction::Insert { table_name }
+                | AuthAction::Update { table_name, .. }
+                | AuthAction::Delete { table_name } => owned(table_name),
+                AuthAction::DropTable { table_name } if owned(table_name) => {
+                    prepared.dropping = Some(table_name.to_string());
+                    prepared.schema = SchemaAccess::Full;
+                    true
+                }
+                AuthAction::DropIndex { table_name, .. } if owned(table_name) => {
+                    prepared.schema = SchemaAccess::Full;
+                    true
+                }
+                AuthAction::CreateTable { table_name } if owned(table_name) => {
+                    if prepared.schema == SchemaAccess::None {
+                        prepared.schema = SchemaAccess::RowId;
+                    }
+                    true
+                }
+                AuthAction::CreateIndex { table_name, .. } | AuthAction::Analyze { table_name } => {
+                    owned(table_name)
+                }
+                AuthAction::Reindex { index_name } => owned(index_name),
+                _ => false,
+            };
+
+            if allowed {
+                Authorization::Allow
+            } else {
+                Authorization::Deny
+            }
+        }));
+
+        Ok(db)
+    }
+
+    /// Forget what the authorizer saw of the previous statement, call before preparing one
+    fn begin_statement(&self) {
+        if self.prefix.is_some() {
+            *self.prepared.lock().unwrap_or_else(PoisonError::into_inner) = Prepared::default();
+        }
+    }
+
+    /// Run a statement just prepared on `conn` with `run`. The authorizer can't see the new
+    /// name of an ALTER TABLE ... RENAME TO, so the altered table is looked up by its root
+    /// page afterwards, and the statement is undone if the table left the prefix.
+    fn run_checked<R>(&self, conn: &Connection, run: impl FnOnce() -> Result<R>) -> Result<R> {
+        let altered = self
+            .prepared
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .altered
+            .take();
+        let (Some(prefix), Some(table)) = (&self.prefix, altered) else {
+            return run();
+        };
+
+        let rootpage: Option<i64> = self.trusted(|| {
+            conn.execute_batch("SAVEPOINT voxa_alter")?;
+            conn.query_row(
+                "SELECT rootpage FROM sqlite_master WHERE type = 'table' AND name = ?1",
+                params![table],
+                |row| row.get(0),
+            )
+            .optional()
+        })?;
+        let res = run();
+        let renamed: Option<String> = match (&res, rootpage) {
+            (Ok(_), Some(rootpage)) => self.trusted(|| {
+                conn.query_row(
+                    "SELECT name FROM sqlite_master WHERE type = 'table' AND rootpage = ?1",
+                    params![rootpage],
+                    |row| row.get(0),
+                )
+                .optional()
+            })?,
+            _ => None,
+        };
+        let escaped = renamed.is_some_and(|name| !name.to_lowercase().starts_with(prefix));
+
+        self.trusted(|| {
+            if res.is_err() || escaped {
+                conn.execute_batch("ROLLBACK TO voxa_alter")?;
+            }
+            conn.execute_batch("RELEASE voxa_alter")
+        })?;
+        if escaped {
+            return Err(rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_AUTH),
+                Some(format!(
+                    "{table} can only be renamed to a name starting with {prefix}"
+                )),
+            ));
+        }
+        res
+    }
+
+    /// Run statements of the server itself on a restricted connection
+    fn trusted<R>(&self, f: impl FnOnce() -> R) -> R {
+        self.trusted.store(true, Ordering::SeqCst);
+        let res = f();
+        self.trusted.store(false, Ordering::SeqCst);
+        res
+    }
+
+    /// Apply the `migrations` of `plugin` not applied yet, each in its own transaction.
+    /// Returns how many were applied.
+    pub fn migrate(&self, plugin: &str, migrations: &[impl AsRef<str>]) -> Result<usize> {
+        let mut conn = self.conn();
+        let applied = self.trusted(|| {
+            conn.query_row(
+                "SELECT COUNT(*) FROM plugin_migrations WHERE plugin = ?1",
+                params![plugin],
+                |row| row.get::<_, i64>(0),
+            )
+        })? as usize;
+
+        for (version, sql) in migrations.iter().enumerate().skip(applied) {
+            let tx = conn.transaction()?;
+            let mut batch = rusqlite::Batch::new(&tx, sql.as_ref());
+            loop {
+                self.begin_statement();
+                let Some(mut stmt) = batch.next()? else {
+                    break;
+                };
+                // Like `execute_batch`, statements returning rows are only stepped once
+                self.run_checked(&tx, || stmt.raw_query().next().map(|_| ()))?;
+            }
+            self.trusted(|| {
+                tx.execute(
+                    "INSERT INTO plugin_migrations (plugin, version) VALUES (?1, ?2)",
+                    params![plugin, version as i64],
+                )
+            })?;
+            tx.commit()?;
+        }
+
+        Ok(migrations.len().saturating_sub(applied))
+    }
+
+    /// Run a statement, returning the number of changed rows
+    pub fn execute_json(&self, sql: &str, params: &[serde_json::Value]) -> Result<usize> {
+        let conn = self.conn();
+        self.begin_statement();
+        let mut stmt = conn.prepare(sql)?;
+        self.run_checked(&conn, || {
+            stmt.execute(rusqlite::params_from_iter(json_params(params)))
+        })
+    }
+
+    /// Run a query, returning at most `max_rows` rows as JSON objects keyed by column name
+    pub fn query_json(
+        &self,
+        sql: &str,
+        params: &[serde_json::Value],
+        max_rows: usize,
+    ) -> Result<Vec<serde_json::Map<String, serde_json::Value>>> {
+        use rusqlite::types::ValueRef;
+        use serde_json::Value as Json;
+
+        let conn = self.conn();
+        self.begin_statement();
+        let mut stmt = conn.prepare(sql)?;
+        let columns: Vec<String> = stmt.column_names().iter().map(|c| c.to_string()).collect();
+        self.run_checked(&conn, || {
+            let mut rows = stmt.query(rusqlite::params_from_iter(json_params(params)))?;
+
+            let mut out = Vec::new();
+            while out.len() < max_rows
+                && let Some(row) = rows.next()?
+            {
+                let mut obj = serde_json::Map::new();
+                for (i, name) in columns.iter().enumerate() {
+                    let value = match row.get_ref(i)? {
+                        ValueRef::Null => Json::Null,
+                        ValueRef::Integer(i) => Json::from(i),
+                        ValueRef::Real(f) => Json::from(f),
+                        ValueRef::Text(t) => Json::from(String::from_utf8_lossy(t)),
+                        ValueRef::Blob(b) => Json::from(b),
+                    };
+                    obj.insert(name.clone(), value);
+                }
+                out.push(obj);
+            }
+
+            Ok(out)
+        })
+    }
+}
+
+/// Convert JSON values to SQLite parameters, arrays and objects are stored as JSON text
+fn json_params(params: &[serde_json::Value]) -> Vec<rusqlite::types::Value> {
+    use rusqlite::types::Value;
+    use serde_json::Value as Json;
+
+    params
+        .iter()
+        .map(|p| match p {
+            Json::Null => Value::Null,
+            Json::Bool(b) => Value::Integer(*b as i64),
+            Json::Number(n) => match n.as_i64() {
+                Some(i) => Value::Integer(i),
+                None => Value::Real(n.as_f64().unwrap_or_default()),
+            },
+            Json::String(s) => Value::Text(s.clone()),
+            other => Value::Text(other.to_string()),
+        })
+        .collect()
+}
+
+// For plugin key-value storage, values are JSON text
+impl Database {
+    pub fn kv_get(&self, plugin: &str, key: &str) -> Result<Option<String>> {
+        self.conn()
+            .query_row(
+                "SELECT value FROM plugin_kv WHERE plugin = ?1 AND key = ?2",
+                params![plugin, key],
+                |row| row.get(0),
+            )
+            .optional()
+    }
+
+    pub fn kv_set(&self, plugin: &str, key: &str, value: &str) -> Result<()> {
+        self.conn().execute(
+            "INSERT INTO plugin_kv (plugin, key, value) VALUES (?1, ?2, ?3)
+            ON CONFLICT (plugin, key) DO UPDATE SET value = excluded.value",
+            params![plugin, key, value],
+        )?;
+
+        Ok(())
+    }
+
+    /// Returns whether the key existed
+    pub fn kv_delete(&self, plugin: &str, key: &str) -> Result<bool> {
+        let n = self.conn().execute(
+            "DELETE FROM plugin_kv WHERE plugin = ?1 AND key = ?2",
+            params![plugin, key],
+        )?;
+
+        Ok(n > 0)
+    }
+
+    /// Get the entries whose key starts with `prefix`, ordered by key
+    pub fn kv_list(&self, plugin: &str, prefix: &str) -> Result<Vec<(String, String)>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT key, value
+         FROM plugin_kv
+         WHERE plugin = ?1 AND substr(key, 1, length(?2)) = ?2
+         ORDER BY key ASC",
+        )?;
+
+        let rows = stmt.query_map(params![plugin, prefix], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+
+        let mut entries = Vec::new();
+        for row in rows {
+            entries.push(row?);
+        }
+
+        Ok(entries)
+    }
+}
+
+fn message_row(row: &rusqlite::Row) -> Result<Message> {
+    Ok(Message {
+        id: row.get::<_, i64>(0)?,
+        channel_id: row.get::<_, String>(1)?,
+        from: row.get::<_, String>(2)?,
+        contents: row.get::<_, String>(3)?,
+        timestamp: row.get::<_, i64>(4)?,
+        bot: row.get::<_, bool>(5)?,
+    })
+}
+
+fn bot_row(row: &rusqlite::Row) -> Result<BotInfo> {
+    let permissions = row.get::<_, String>(2)?;
+    Ok(BotInfo {
+        id: row.get::<_, String>(0)?,
+        name: row.get::<_, String>(1)?,
+        permissions: serde_json::from_str(&permissions).map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(2, rusqlite::types::Type::Text, e.into())
+        })?,
+        created: row.get::<_, i64>(3)?,
+    })
+}
+
+// For outgoing webhook deliveries, times are unix milliseconds
+impl Database {
+    pub fn enqueue_webhook(
+        &self,
+        target: &str,
+        url: &str,
+        event: &str,
+        body: &str,
+        now: i64,
+    ) -> Result<()> {
+        self.conn().execute(
+            "INSERT INTO webhook_queue (target, url, event, body, next_attempt)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![target, url, event, body, now],
+        )?;
+
+        Ok(())
+    }
+
+    /// Get up to `limit` deliveries due at `now`, oldest first
+    pub fn due_webhooks(&self, now: i64, limit: usize) -> Result<Vec<QueuedWebhook>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT id, target, url, event, body, attempts
+         FROM webhook_queue
+         WHERE next_attempt <= ?1
+         ORDER BY id ASC
+         LIMIT ?2",
+        )?;
+
+        let rows = stmt.query_map(params![now, limit as i64], |row| {
+            Ok(QueuedWebhook {
+                id: row.get::<_, i64>(0)?,
+                target: row.get::<_, String>(1)?,
+                url: row.get::<_, String>(2)?,
+                event: row.get::<_, String>(3)?,
+                body: row.get::<_, String>(4)?,
+                attempts: row.get::<_, u32>(5)?,
+            })
+        })?;
+
+        let mut deliveries = Vec::new();
+        for row in rows {
+            deliveries.push(row?);
+        }
+
+        Ok(deliveries)
+    }
+
+    /// Number of deliveries waiting to be sent
+    pub fn webhook_queue_len(&self) -> Result<usize> {
+        self.conn()
+            .query_row("SELECT COUNT(*) FROM webhook_queue", [], |row| {
+                row.get::<_, i64>(0)
+            })
+            .map(|n| n as usize)
+    }
+
+    pub fn delete_webhook(&self, id: i64) -> Result<()> {
+        self.conn()
+            .execute("DELETE FROM webhook_queue WHERE id = ?1", params![id])?;
+
+        Ok(())
+    }
+
+    /// Record a failed attempt and when to try again
+    pub fn retry_webhook(&self, id: i64, attempts: u32, next_attempt: i64) -> Result<()> {
+        self.conn().execute(
+            "UPDATE webhook_queue
+                SET attempts = ?2, next_attempt = ?3
+                WHERE id = ?1",
+            params![id, attempts, next_attempt],
+        )?;
+
+        Ok(())
+    }
+}
+
+// For bot accounts, only a hash of their token is stored
+impl Database {
+    pub fn insert_bot(&self, bot: &BotInfo, token_hash: &str) -> Result<()> {
+        let permissions = serde_json::to_string(&bot.permissions)
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(e.into()))?;
+        self.conn().execute(
+            "INSERT INTO bots (id, name, token_hash, permissions, created)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![bot.id, bot.name, token_hash, permissions, bot.created],
+        )?;
+
+        Ok(())
+    }
+
+    /// Returns whether the bot existed
+    pub fn delete_bot(&self, name: &str) -> Result<bool> {
+        let n = self
+            .conn()
+            .execute("DELETE FROM bots WHERE name = ?1", params![name])?;
+
+        Ok(n > 0)
+    }
+
+    pub fn get_bot_by_name(&self, name: &str) -> Result<Option<BotInfo>> {
+        self.conn()
+            .query_row(
+                "SELECT id, name, permissions, created FROM bots WHERE name = ?1",
+                params![name],
+                bot_row,
+            )
+            .optional()
+    }
+
+    pub fn get_bot_by_token_hash(&self, token_hash: &str) -> Result<Option<BotInfo>> {
+        self.conn()
+            .query_row(
+                "SELECT id, name, permissions, created FROM bots WHERE token_hash = ?1",
+                params![token_hash],
+                bot_row,
+            )
+            .optional()
+    }
+
+    pub fn get_bots(&self) -> Result<Vec<BotInfo>> {
+        let conn = self.conn();
+        let mut stmt =
+            conn.prepare("SELECT id, name, permissions, created FROM bots ORDER BY name ASC")?;
+
+        let rows = stmt.query_map([], bot_row)?;
+
+        let mut bots = Vec::new();
+        for row in rows {
+            bots.push(row?);
+        }
+
+        Ok(bots)
     }
 }
 
@@ -33,14 +746,17 @@ impl Database {
         user_id: &str,
         contents: &str,
         timestamp: i64,
+        bot: bool,
     ) -> Result<Message> {
-        self.0.execute(
-            "INSERT INTO chat (channel_id, user_id, contents, timestamp)
-            VALUES (?1, ?2, ?3, ?4)",
-            params![channel_id, user_id, contents, timestamp],
+        // One lock for both, so the id is the one of this insert
+        let conn = self.conn();
+        conn.execute(
+            "INSERT INTO chat (channel_id, user_id, contents, timestamp, bot)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![channel_id, user_id, contents, timestamp, bot],
         )?;
 
-        let id = self.0.last_insert_rowid();
+        let id = conn.last_insert_rowid();
 
         Ok(Message {
             id,
@@ -48,21 +764,22 @@ impl Database {
             from: user_id.to_string(),
             contents: contents.to_string(),
             timestamp,
+            bot,
         })
     }
 
     /// Delete a message from the DB
     pub fn delete_message(&self, message_id: usize) -> Result<()> {
-        self.0
+        self.conn()
             .execute("DELETE FROM chat WHERE id = ?1;", params![message_id])?;
 
         Ok(())
     }
 
-    /// Delete a message from the DB
+    /// Edit the contents of a message in the DB
     pub fn edit_message(&self, message_id: usize, contents: &str) -> Result<()> {
-        self.0.execute(
-            "UPDATE table_name
+        self.conn().execute(
+            "UPDATE chat
                 SET contents = ?2
                 WHERE id = ?1;
                 ",
@@ -73,54 +790,30 @@ impl Database {
     }
 
     /// Get a message by its ID
-    pub fn get_message_by_id(&self, message_id: &str) -> Result<Option<Message>> {
-        let mut stmt = self.0.prepare(
-            "SELECT id, channel_id, user_id, contents, timestamp
+    pub fn get_message_by_id(&self, message_id: usize) -> Result<Option<Message>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT id, channel_id, user_id, contents, timestamp, bot
          FROM chat
          WHERE id = ?1",
         )?;
 
-        let mut rows = stmt.query_map(params![message_id], |row| {
-            Ok((
-                row.get::<_, i64>(0)?,    // id
-                row.get::<_, String>(1)?, // channel_id
-                row.get::<_, u32>(2)?,    // user_id
-                row.get::<_, String>(3)?, // contents
-                row.get::<_, i64>(4)?,    // timestamp
-            ))
-        })?;
+        let mut rows = stmt.query_map(params![message_id], message_row)?;
 
-        if let Some(row) = rows.next() {
-            let (id, channel_id, user_id, contents, timestamp) = row?;
-            return Ok(Some(Message {
-                id,
-                channel_id,
-                from: user_id.to_string(),
-                contents,
-                timestamp,
-            }));
-        }
-        Ok(None)
+        rows.next().transpose()
     }
 
     /// Get all messages with an ID greater than the given one
     pub fn get_messages_after_id(&self, message_id: usize) -> Result<Vec<Message>> {
-        let mut stmt = self.0.prepare(
-            "SELECT id, channel_id, user_id, contents, timestamp
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT id, channel_id, user_id, contents, timestamp, bot
          FROM chat
          WHERE id > ?1
          ORDER BY id ASC",
         )?;
 
-        let rows = stmt.query_map(params![message_id], |row| {
-            Ok(Message {
-                id: row.get::<_, i64>(0)?,
-                channel_id: row.get::<_, String>(1)?,
-                from: row.get::<_, String>(2)?,
-                contents: row.get::<_, String>(3)?,
-                timestamp: row.get::<_, i64>(4)?,
-            })
-        })?;
+        let rows = stmt.query_map(params![message_id], message_row)?;
 
         let mut messages = Vec::new();
         for row in rows {
@@ -129,7 +822,32 @@ impl Database {
 
         Ok(messages)
     }
-}
 
-unsafe impl Send for Database {}
-unsafe impl Sync for Database {}
+    /// Get up to `limit` messages of a channel with an ID lower than `before`, oldest first
+    pub fn get_channel_messages(
+        &self,
+        channel_id: &str,
+        before: Option<usize>,
+        limit: usize,
+    ) -> Result<Vec<Message>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT id, channel_id, user_id, contents, timestamp, bot
+         FROM chat
+         WHERE channel_id = ?1 AND id < ?2
+         ORDER BY id DESC
+         LIMIT ?3",
+        )?;
+
+        let before = before.map_or(i64::MAX, |b| b as i64);
+        let rows = stmt.query_map(params![channel_id, before, limit as i64], message_row)?;
+
+        let mut messages = Vec::new();
+        for row in rows {
+            messages.push(row?);
+        }
+        messages.reverse();
+
+        Ok(messages)
+    }
+}
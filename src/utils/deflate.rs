@@ -0,0 +1,127 @@
+//! `permessage-deflate` WebSocket extension (RFC 7692)
+//!
+//! Both directions run without context takeover, so every message is compressed
+//! on its own and no compression state is kept between messages.
+
+use anyhow::anyhow;
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use serde::{Deserialize, Serialize};
+
+/// Trailer removed from compressed messages, see RFC 7692 section 7.2.1
+const TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    /// Messages smaller than this many bytes are sent uncompressed
+    pub threshold: usize,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            threshold: 1024,
+        }
+    }
+}
+
+/// Pick a `permessage-deflate` offer from a `Sec-WebSocket-Extensions` header.
+///
+/// Returns the value of the response header if an offer was accepted.
+pub fn negotiate(header: &str) -> Option<String> {
+    header.split(',').find(|offer| accepts(offer)).map(|_| {
+        "permessage-deflate; server_no_context_takeover; client_no_context_takeover".into()
+    })
+}
+
+fn accepts(offer: &str) -> bool {
+    let mut params = offer.split(';').map(str::trim);
+    if params.next() != Some("permessage-deflate") {
+        return false;
+    }
+
+    params.all(|param| {
+        let (name, value) = match param.split_once('=') {
+            Some((n, v)) => (n.trim(), Some(v.trim().trim_matches('"'))),
+            None => (param, None),
+        };
+
+        match (name, value) {
+            ("server_no_context_takeover" | "client_no_context_takeover", None) => true,
+            // The client may use any window size, we always inflate with the largest one
+            ("client_max_window_bits", None) => true,
+            ("client_max_window_bits", Some(v)) => valid_window_bits(v),
+            // Smaller windows for our side are not supported by the deflate backend
+            ("server_max_window_bits", Some(v)) => v == "15",
+            _ => false,
+        }
+    })
+}
+
+fn valid_window_bits(v: &str) -> bool {
+    matches!(v.parse::<u8>(), Ok(8..=15))
+}
+
+/// Compress a whole message payload
+pub fn compress(data: &[u8]) -> crate::Result<Vec<u8>> {
+    let mut compress = Compress::new(Compression::default(), false);
+    let mut out = Vec::with_capacity(data.len() / 2 + 64);
+
+    loop {
+        if out.len() == out.capacity() {
+            out.reserve(out.capacity());
+        }
+
+        let consumed = compress.total_in() as usize;
+        compress.compress_vec(&data[consumed..], &mut out, FlushCompress::Sync)?;
+
+        // The flush is complete once all input is consumed and output space is left over
+        if compress.total_in() as usize == data.len() && out.len() < out.capacity() {
+            break;
+        }
+    }
+
+    if out.ends_with(&TAIL) {
+        out.truncate(out.len() - TAIL.len());
+    }
+    Ok(out)
+}
+
+/// Decompress a whole message payload, returns `None` once the output exceeds `limit` bytes
+pub fn decompress(data: &[u8], limit: usize) -> crate::Result<Option<Vec<u8>>> {
+    let mut decompress = Decompress::new(false);
+    let mut input = Vec::with_capacity(data.len() + TAIL.len());
+    input.extend_from_slice(data);
+    input.extend_from_slice(&TAIL);
+
+    let mut out = Vec::with_capacity((data.len() * 4).clamp(64, limit.max(64)));
+    loop {
+        if out.len() == out.capacity() {
+            out.reserve(out.capacity());
+        }
+
+        let (before_in, before_out) = (decompress.total_in(), decompress.total_out());
+        let status = decompress.decompress_vec(
+            &input[before_in as usize..],
+            &mut out,
+            FlushDecompress::Sync,
+        )?;
+
+        if out.len() > limit {
+            return Ok(None);
+        }
+
+        let done = decompress.total_in() as usize == input.len() && out.len() < out.capacity();
+        if status == Status::StreamEnd || done {
+            break;
+        }
+
+        if decompress.total_in() == before_in && decompress.total_out() == before_out {
+            return Err(anyhow!("Invalid compressed message"));
+        }
+    }
+
+    Ok(Some(out))
+}
@@ -1,41 +1,524 @@
-use std::path::Path;
+use std::{
+    collections::HashMap,
+    ffi::{CStr, c_void},
+    mem::ManuallyDrop,
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, SystemTime},
+};
 
-use libloading::{Library, Symbol};
+use anyhow::{Context, bail};
+use libloading::Library;
+use serde::de::DeserializeOwned;
 
-use crate::{logger, utils::plugin::DynPlugin, utils::vfs};
+use crate::{
+    Server, logger,
+    types::{
+        data::Message,
+        message::{ClientMessage, ResponseError, WsMessage},
+    },
+    utils::{
+        client::Client,
+        native::{
+            CRATE_VERSION, DESCRIPTOR_SYMBOL, DESCRIPTOR_VERSION, FEATURES, PLUGIN_API_VERSION,
+            PluginBuffer, PluginDescriptor, PluginSlice, PluginVTable, RUSTC_VERSION,
+        },
+        plugin::{DynPlugin, Plugin, PluginConfig, PluginsConfig, RequestAction},
+        vfs,
+    },
+};
 
 logger! {
     const LOGGER "Loader"
 }
 
-pub fn load_plugin(path: &Path) -> anyhow::Result<DynPlugin> {
+/// How often the `plugins` directory is scanned when `PluginsConfig::watch` is set
+const WATCH_POLL: Duration = Duration::from_secs(1);
+
+/// Numbers the copies libraries are loaded from
+static COPIES: AtomicUsize = AtomicUsize::new(0);
+
+/// The library a native plugin was loaded from
+#[derive(Debug, Clone)]
+pub struct NativeFile {
+    pub path: PathBuf,
+    pub version: String,
+}
+
+impl NativeFile {
+    pub fn file_name(&self) -> Option<String> {
+        Some(self.path.file_name()?.to_string_lossy().into_owned())
+    }
+}
+
+/// A plugin created by a dynamic library, called through the library's `PluginVTable`.
+///
+/// The plugin is handed back to the library to be destroyed, and the library
+/// is only unloaded afterwards, so no plugin code outlives its library.
+///
+/// Libraries are loaded from a temporary copy, so the original can be rebuilt
+/// while loaded and a reload never gets the cached old library.
+pub struct NativePlugin {
+    name: String,
+    version: String,
+    plugin: *mut c_void,
+    vtable: PluginVTable,
+    lib: ManuallyDrop<Library>,
+    copy: PathBuf,
+}
+
+// The library's plugin is `Send + Sync`, the pointer is owned exclusively by this wrapper
+unsafe impl Send for NativePlugin {}
+unsafe impl Sync for NativePlugin {}
+
+impl NativePlugin {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    /// Decode the JSON result of a call, `None` if the plugin panicked
+    fn result<T: DeserializeOwned>(&self, buffer: PluginBuffer) -> Option<T> {
+        let bytes = take(&self.vtable, buffer)?;
+        serde_json::from_slice(&bytes)
+            .map_err(|e| LOGGER.error(format!("Plugin {} returned invalid JSON: {e}", self.name)))
+            .ok()
+    }
+}
+
+/// Copy a buffer returned by a library and release it, `None` if it holds no value
+fn take(vtable: &PluginVTable, buffer: PluginBuffer) -> Option<Vec<u8>> {
+    if buffer.ptr.is_null() {
+        return None;
+    }
+    let bytes = unsafe { std::slice::from_raw_parts(buffer.ptr, buffer.len) }.to_vec();
+    unsafe { (vtable.free)(buffer) };
+    Some(bytes)
+}
+
+/// The opaque handles passed to the library
+fn handle<T>(value: &T) -> *const c_void {
+    (value as *const T).cast()
+}
+
+/// Encode an argument for the library, every type passed serializes
+fn json(value: &impl serde::Serialize) -> Vec<u8> {
+    serde_json::to_vec(value).expect("plugin arguments serialize to JSON")
+}
+
+impl Drop for NativePlugin {
+    fn drop(&mut self) {
+        unsafe {
+            (self.vtable.destroy)(self.plugin);
+            ManuallyDrop::drop(&mut self.lib);
+        }
+        let _ = std::fs::remove_file(&self.copy);
+    }
+}
+
+fn manifest_str(ptr: *const std::os::raw::c_char, field: &str) -> crate::Result<String> {
+    if ptr.is_null() {
+        bail!("Plugin manifest is missing its {field}");
+    }
+    Ok(unsafe { CStr::from_ptr(ptr) }
+        .to_string_lossy()
+        .into_owned())
+}
+
+/// Check that a plugin was built against this exact server build, returns its name and version
+///
+/// # Safety
+/// The manifest's strings must be null or NUL terminated
+pub unsafe fn verify(desc: &PluginDescriptor) -> crate::Result<(String, String)> {
+    if desc.abi_version != DESCRIPTOR_VERSION {
+        bail!(
+            "Unsupported plugin descriptor version {} (expected {DESCRIPTOR_VERSION})",
+            desc.abi_version
+        );
+    }
+
+    let m = &desc.manifest;
+    let name = manifest_str(m.name, "name")?;
+    let version = manifest_str(m.version, "version")?;
+
+    if m.api_version != PLUGIN_API_VERSION {
+        bail!(
+            "Plugin {name} {version} requires plugin API {} but the server provides {PLUGIN_API_VERSION}",
+            m.api_version
+        );
+    }
+
+    let crate_version = manifest_str(m.crate_version, "crate version")?;
+    if crate_version.as_bytes() != CRATE_VERSION.to_bytes() {
+        bail!(
+            "Plugin {name} {version} was built against voxa-server {crate_version} but the server is {}",
+            CRATE_VERSION.to_string_lossy()
+        );
+    }
+
+    let rustc_version = manifest_str(m.rustc_version, "compiler version")?;
+    if rustc_version.as_bytes() != RUSTC_VERSION.to_bytes() {
+        bail!(
+            "Plugin {name} {version} was built with {rustc_version} but the server with {}",
+            RUSTC_VERSION.to_string_lossy()
+        );
+    }
+
+    let features = manifest_str(m.features, "features")?;
+    if features.as_bytes() != FEATURES.to_bytes() {
+        bail!(
+            "Plugin {name} {version} was built with voxa-server features [{features}] but the server with [{}]",
+            FEATURES.to_string_lossy()
+        );
+    }
+
+    Ok((name, version))
+}
+
+/// Copy a library to a unique temporary file to load it from
+fn shadow_copy(path: &Path) -> crate::Result<PathBuf> {
+    let dir = std::env::temp_dir().join("voxa-plugins");
+    vfs::dir(&dir)?;
+    let copy = dir.join(format!(
+        "{}-{}-{}",
+        std::process::id(),
+        COPIES.fetch_add(1, Ordering::Relaxed),
+        path.file_name().unwrap_or_default().to_string_lossy()
+    ));
+    std::fs::copy(path, &copy).with_context(|| format!("Failed to copy {path:?}"))?;
+    Ok(copy)
+}
+
+/// Load the plugin in a library, `None` if it is disabled in the config
+pub fn load_plugin(path: &Path, config: &PluginsConfig) -> anyhow::Result<Option<NativePlugin>> {
+    LOGGER.info(format!("Loading plugin: {:?}", path));
+    let copy = shadow_copy(path)?;
+    match unsafe { open(&copy, config) } {
+        Ok(Some(plugin)) => {
+            LOGGER.info(format!("Loaded plugin {} {}", plugin.name, plugin.version));
+            Ok(Some(plugin))
+        }
+        res => {
+            let _ = std::fs::remove_file(&copy);
+            res
+        }
+    }
+}
+
+unsafe fn open(copy: &Path, config: &PluginsConfig) -> crate::Result<Option<NativePlugin>> {
     unsafe {
-        LOGGER.info(format!("Loading plugin: {:?}", path));
-        let lib = Library::new(path)?;
-        let func: Symbol<extern "C" fn() -> DynPlugin> = lib.get(b"load_plugin").unwrap();
-        Ok(func())
-    }
-}
-
-pub fn load_plugins(arr: &mut Vec<DynPlugin>, path: &Path) -> crate::Result<()> {
-    LOGGER.info("Loading plugins");
-    vfs::dir(path)?;
-    if path.is_dir() {
-        for entry in std::fs::read_dir(path)? {
-            let entry = entry?;
-            let path = entry.path();
-            if path.extension().and_then(|s| s.to_str()) == Some("dylib") {
-                match load_plugin(&path) {
-                    Ok(plugin) => {
-                        arr.push(plugin);
+        let lib = Library::new(copy)?;
+        let desc = *lib
+            .get::<*const PluginDescriptor>(DESCRIPTOR_SYMBOL)
+            .context("Not a voxa plugin, no plugin descriptor exported")?;
+        let desc = &*desc;
+
+        let (name, version) = verify(desc)?;
+        if !config.is_enabled(&name) {
+            LOGGER.info(format!("Skipping disabled plugin {name} {version}"));
+            return Ok(None);
+        }
+
+        let vtable = desc.vtable;
+        let plugin = (vtable.create)();
+        if plugin.is_null() {
+            let panic = take(&vtable, (vtable.take_panic)()).unwrap_or_default();
+            let msg = String::from_utf8_lossy(&panic);
+            bail!("Plugin {name} {version} panicked while creating an instance: {msg}");
+        }
+
+        Ok(Some(NativePlugin {
+            name,
+            version,
+            plugin,
+            vtable,
+            lib: ManuallyDrop::new(lib),
+            copy: copy.to_path_buf(),
+        }))
+    }
+}
+
+fn is_library(path: &Path) -> bool {
+    path.is_file()
+        && path.extension().and_then(|s| s.to_str()) == Some(std::env::consts::DLL_EXTENSION)
+}
+
+/// Modification times of the libraries in `dir`
+fn scan(dir: &Path) -> HashMap<PathBuf, SystemTime> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return HashMap::new();
+    };
+
+    entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| is_library(p))
+        .filter_map(|p| {
+            let modified = std::fs::metadata(&p).and_then(|m| m.modified()).ok()?;
+            Some((p, modified))
+        })
+        .collect()
+}
+
+impl Server {
+    fn plugin_dir(&self) -> PathBuf {
+        self.root.join("plugins")
+    }
+
+    fn record_native(&self, plugin: &NativePlugin, path: &Path) {
+        self.native_plugins.lock().unwrap().insert(
+            plugin.name().to_string(),
+            NativeFile {
+                path: path.to_path_buf(),
+                version: plugin.version().to_string(),
+            },
+        );
+    }
+
+    /// Load every library in the `plugins` directory, they are initialized by `run`
+    pub(crate) fn load_plugin_dir(&self) -> crate::Result<()> {
+        LOGGER.info("Loading plugins");
+        let path = self.plugin_dir();
+        vfs::dir(&path)?;
+        if !path.is_dir() {
+            LOGGER.error(format!("{:?} is not a directory", path));
+            return Ok(());
+        }
+
+        for entry in std::fs::read_dir(&path)? {
+            let path = entry?.path();
+            if !is_library(&path) {
+                continue;
+            }
+
+            match load_plugin(&path, &self.config.plugins) {
+                Ok(Some(plugin)) => {
+                    self.record_native(&plugin, &path);
+                    self.plugins_mut().push(Arc::new(Box::new(plugin)));
+                }
+                Ok(None) => {}
+                Err(e) => LOGGER.error(format!("Failed to load plugin {:?}: {:#}", path, e)),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Load and initialize a library from the `plugins` directory, returns the plugin's name
+    pub fn load_plugin_file(self: &Arc<Self>, file: &str) -> crate::Result<String> {
+        if Path::new(file).file_name().and_then(|f| f.to_str()) != Some(file) {
+            bail!("{file:?} is not a file name");
+        }
+
+        let path = self.plugin_dir().join(file);
+        if !is_library(&path) {
+            bail!("{file} is not a plugin library");
+        }
+
+        let Some(plugin) = load_plugin(&path, &self.config.plugins)? else {
+            bail!("The plugin in {file} is disabled");
+        };
+
+        let name = plugin.name().to_string();
+        if self.plugins().iter().any(|p| p.name() == name) {
+            bail!("Plugin {name} is already loaded");
+        }
+
+        self.start_native(plugin, &path)?;
+        Ok(name)
+    }
+
+    /// Replace a native plugin with a fresh instance from its library.
+    /// The old plugin keeps running if the library fails to load.
+    pub fn reload_plugin(self: &Arc<Self>, name: &str) -> crate::Result<()> {
+        let Some(file) = self.native_plugins.lock().unwrap().get(name).cloned() else {
+            bail!("Plugin {name} was not loaded from a library");
+        };
+
+        let Some(plugin) = load_plugin(&file.path, &self.config.plugins)? else {
+            bail!("Plugin {name} is disabled");
+        };
+
+        self.unload_plugin(name)?;
+        self.start_native(plugin, &file.path)
+    }
+
+    fn start_native(self: &Arc<Self>, plugin: NativePlugin, path: &Path) -> crate::Result<()> {
+        self.record_native(&plugin, path);
+        let mut plugin: DynPlugin = Box::new(plugin);
+        if let Err(e) = self.init_plugin(&mut plugin) {
+            self.native_plugins.lock().unwrap().remove(plugin.name());
+            return Err(e);
+        }
+
+        let name = plugin.name().to_string();
+        self.add_plugin(plugin);
+        LOGGER.info(format!("Started plugin {name}"));
+        Ok(())
+    }
+
+    /// Poll the `plugins` directory, loading new libraries, reloading changed ones
+    /// and unloading removed ones. Changes are applied once a file stopped changing.
+    pub(crate) fn watch_plugins(self: &Arc<Self>) {
+        let server = self.clone();
+        std::thread::spawn(move || {
+            let dir = server.plugin_dir();
+            let mut applied = scan(&dir);
+            let mut last = applied.clone();
+
+            while !server.is_stopping() {
+                std::thread::sleep(WATCH_POLL);
+                let now = scan(&dir);
+
+                for (path, modified) in &now {
+                    if last.get(path) == Some(modified) && applied.get(path) != Some(modified) {
+                        applied.insert(path.clone(), *modified);
+                        if let Err(e) = server.apply_change(path) {
+                            LOGGER.error(format!("Failed to apply change to {path:?}: {e:#}"));
+                        }
                     }
-                    Err(e) => LOGGER.error(format!("Failed to load plugin {:?}: {}", path, e)),
                 }
+
+                applied.retain(|path, _| {
+                    if now.contains_key(path) {
+                        return true;
+                    }
+                    if let Some(name) = server.native_name(path) {
+                        LOGGER.extract(server.unload_plugin(&name), "Failed to unload plugin");
+                    }
+                    false
+                });
+
+                last = now;
+            }
+        });
+    }
+
+    fn native_name(&self, path: &Path) -> Option<String> {
+        self.native_plugins
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(_, f)| f.path == path)
+            .map(|(name, _)| name.clone())
+    }
+
+    fn apply_change(self: &Arc<Self>, path: &Path) -> crate::Result<()> {
+        match self.native_name(path) {
+            Some(name) => self.reload_plugin(&name),
+            None => {
+                let file = path.file_name().unwrap_or_default().to_string_lossy();
+                self.load_plugin_file(&file).map(|_| ())
             }
         }
-    } else {
-        LOGGER.error(format!("{:?} is not a directory", path));
+    }
+}
+
+/// Forwards every hook to the library's plugin, the manifest name takes precedence
+impl Plugin for NativePlugin {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn init(&mut self, server: &Arc<Server>, config: &PluginConfig) {
+        let config = json(config);
+        unsafe { (self.vtable.init)(self.plugin, handle(server), PluginSlice::of(&config)) }
+    }
+
+    fn priority(&self) -> i32 {
+        unsafe { (self.vtable.priority)(self.plugin) }
+    }
+
+    fn on_request(
+        &self,
+        req: &WsMessage<ClientMessage>,
+        client: &Client,
+        server: &Arc<Server>,
+    ) -> RequestAction {
+        let req = json(req);
+        let action = unsafe {
+            (self.vtable.on_request)(
+                self.plugin,
+                PluginSlice::of(&req),
+                handle(client),
+                handle(server),
+            )
+        };
+        self.result(action).unwrap_or(RequestAction::Continue)
+    }
+
+    fn on_plugin_message(
+        &self,
+        data: &serde_json::Map<String, serde_json::Value>,
+        client: &Client,
+        server: &Arc<Server>,
+    ) -> Result<(), ResponseError> {
+        let data = json(data);
+        let res = unsafe {
+            (self.vtable.on_plugin_message)(
+                self.plugin,
+                PluginSlice::of(&data),
+                handle(client),
+                handle(server),
+            )
+        };
+        self.result(res).unwrap_or_else(|| {
+            Err(ResponseError::InternalError(format!(
+                "Plugin {} failed to handle the message",
+                self.name
+            )))
+        })
+    }
+
+    fn on_connect(&self, client: &Client, server: &Arc<Server>) {
+        unsafe { (self.vtable.on_connect)(self.plugin, handle(client), handle(server)) }
+    }
+
+    fn on_authenticated(&self, client: &Client, server: &Arc<Server>) {
+        unsafe { (self.vtable.on_authenticated)(self.plugin, handle(client), handle(server)) }
+    }
+
+    fn on_disconnect(&self, client: &Client, server: &Arc<Server>) {
+        unsafe { (self.vtable.on_disconnect)(self.plugin, handle(client), handle(server)) }
+    }
+
+    fn on_message_created(&self, message: &Message, server: &Arc<Server>) {
+        let message = json(message);
+        unsafe {
+            (self.vtable.on_message_created)(self.plugin, PluginSlice::of(&message), handle(server))
+        }
+    }
+
+    fn on_message_edited(&self, message: &Message, server: &Arc<Server>) {
+        let message = json(message);
+        unsafe {
+            (self.vtable.on_message_edited)(self.plugin, PluginSlice::of(&message), handle(server))
+        }
     }
 
-    Ok(())
+    fn on_message_deleted(&self, message: &Message, server: &Arc<Server>) {
+        let message = json(message);
+        unsafe {
+            (self.vtable.on_message_deleted)(self.plugin, PluginSlice::of(&message), handle(server))
+        }
+    }
+
+    fn on_tick(&self, server: &Arc<Server>) {
+        unsafe { (self.vtable.on_tick)(self.plugin, handle(server)) }
+    }
+
+    fn on_shutdown(&self, server: &Arc<Server>) {
+        unsafe { (self.vtable.on_shutdown)(self.plugin, handle(server)) }
+    }
+
+    fn take_panic(&self) -> Option<String> {
+        let msg = take(&self.vtable, (self.vtable.take_panic)())?;
+        Some(String::from_utf8_lossy(&msg).into_owned())
+    }
 }
@@ -1,26 +1,523 @@
-use std::fmt::Display;
+//! Leveled logging to stdout and rotated files, the `log` and `tracing` facades write here too
+
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt::{self, Display},
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex, PoisonError, RwLock,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    fn color(self) -> &'static str {
+        match self {
+            Level::Trace => "\x1b[90m",
+            Level::Debug => "\x1b[36m",
+            Level::Info => "\x1b[32m",
+            Level::Warn => "\x1b[33m",
+            Level::Error => "\x1b[31m",
+        }
+    }
+}
+
+impl Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Level::Trace => "TRACE",
+            Level::Debug => "DEBUG",
+            Level::Info => "INFO",
+            Level::Warn => "WARN",
+            Level::Error => "ERROR",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// `<timestamp> LEVEL (Logger) › message`
+    Pretty,
+    /// One JSON object per line
+    Json,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoggingConfig {
+    /// Least severe level written
+    pub level: Level,
+    /// Levels by logger name or `log`/`tracing` target, like `{ "Auth": "debug", "rustls": "warn" }`.
+    /// A target also matches the entries of its parent modules.
+    pub levels: HashMap<String, Level>,
+    pub format: LogFormat,
+    /// Color the levels of pretty stdout lines
+    pub color: bool,
+    /// Also write to `logs/voxa.log` under the server root
+    pub file: bool,
+    /// Size in bytes after which the log file is rotated
+    pub max_file_size: u64,
+    /// Rotated files kept next to `voxa.log`, `voxa.log.1` being the newest
+    pub max_files: u32,
+    /// Write message contents and raw client payloads at debug level.
+    /// Only meant for debugging, they are redacted otherwise.
+    pub log_payloads: bool,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: Level::Info,
+            levels: HashMap::new(),
+            format: LogFormat::Pretty,
+            color: true,
+            file: true,
+            max_file_size: 10 << 20,
+            max_files: 5,
+            log_payloads: false,
+        }
+    }
+}
+
+/// A log line before formatting
+pub struct Record<'a> {
+    pub level: Level,
+    /// Logger name or facade target
+    pub logger: &'a str,
+    pub message: &'a str,
+    /// Key-value pairs of `tracing` events
+    pub fields: &'a [(String, String)],
+}
+
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+}
+
+impl LogFile {
+    fn open(dir: &Path) -> std::io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join("voxa.log");
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { path, file, size })
+    }
+
+    fn write(&mut self, line: &str, max_size: u64, max_files: u32) -> std::io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > max_size {
+            self.rotate(max_files)?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Shift `voxa.log.<n>` to `voxa.log.<n + 1>`, dropping the oldest, and start a new file
+    fn rotate(&mut self, max_files: u32) -> std::io::Result<()> {
+        let numbered = |n: u32| PathBuf::from(format!("{}.{n}", self.path.display()));
+        if max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(numbered(max_files));
+            for n in (1..max_files).rev() {
+                let _ = fs::rename(numbered(n), numbered(n + 1));
+            }
+            fs::rename(&self.path, numbered(1))?;
+        }
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// Filters, formats and writes log records
+pub struct Pipeline {
+    config: LoggingConfig,
+    file: Option<Mutex<LogFile>>,
+}
+
+impl Pipeline {
+    /// A pipeline writing to stdout, and to `<root>/logs` if the config asks for it
+    pub fn new(config: LoggingConfig, root: Option<&Path>) -> crate::Result<Self> {
+        let file = match root {
+            Some(root) if config.file => Some(Mutex::new(LogFile::open(&root.join("logs"))?)),
+            _ => None,
+        };
+        Ok(Self { config, file })
+    }
+
+    /// Least severe level written for `logger`
+    pub fn level(&self, logger: &str) -> Level {
+        if let Some(level) = self.config.levels.get(logger) {
+            return *level;
+        }
+
+        // The closest parent module of a facade target
+        let mut target = logger;
+        while let Some((parent, _)) = target.rsplit_once("::") {
+            if let Some(level) = self.config.levels.get(parent) {
+                return *level;
+            }
+            target = parent;
+        }
+        self.config.level
+    }
+
+    pub fn enabled(&self, logger: &str, level: Level) -> bool {
+        level >= self.level(logger)
+    }
+
+    /// Whether message contents and raw payloads may be logged
+    pub fn log_payloads(&self) -> bool {
+        self.config.log_payloads
+    }
+
+    /// Least severe level written by any logger
+    fn min_level(&self) -> Level {
+        self.config
+            .levels
+            .values()
+            .copied()
+            .chain([self.config.level])
+            .min()
+            .unwrap_or(Level::Info)
+    }
+
+    pub fn write(&self, record: &Record) {
+        if !self.enabled(record.logger, record.level) {
+            return;
+        }
+
+        let timestamp = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+        let line = match self.config.format {
+            LogFormat::Json => json_line(&timestamp, record),
+            LogFormat::Pretty => pretty_line(&timestamp, record, false),
+        };
+
+        if self.config.color && self.config.format == LogFormat::Pretty {
+            print!("{}", pretty_line(&timestamp, record, true));
+        } else {
+            print!("{line}");
+        }
+
+        if let Some(file) = &self.file {
+            let mut file = file.lock().unwrap_or_else(PoisonError::into_inner);
+            if let Err(e) = file.write(&line, self.config.max_file_size, self.config.max_files) {
+                println!("Failed to write the log file: {e}");
+            }
+        }
+    }
+}
+
+fn pretty_line(timestamp: &str, record: &Record, color: bool) -> String {
+    let mut line = if color {
+        format!(
+            "\x1b[90m{timestamp}\x1b[0m {}{}\x1b[0m ({}) › {}",
+            record.level.color(),
+            record.level,
+            record.logger,
+            record.message
+        )
+    } else {
+        format!(
+            "{timestamp} {} ({}) › {}",
+            record.level, record.logger, record.message
+        )
+    };
+    for (k, v) in record.fields {
+        line += &format!(" {k}={v}");
+    }
+    line.push('\n');
+    line
+}
+
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    timestamp: &'a str,
+    level: Level,
+    logger: &'a str,
+    message: &'a str,
+    #[serde(skip_serializing_if = "serde_json::Map::is_empty")]
+    fields: serde_json::Map<String, serde_json::Value>,
+}
+
+fn json_line(timestamp: &str, record: &Record) -> String {
+    let line = JsonLine {
+        timestamp,
+        level: record.level,
+        logger: record.logger,
+        message: record.message,
+        fields: record
+            .fields
+            .iter()
+            .map(|(k, v)| (k.clone(), serde_json::Value::String(v.clone())))
+            .collect(),
+    };
+    let mut line = serde_json::to_string(&line).unwrap_or_default();
+    line.push('\n');
+    line
+}
+
+/// Pipeline of this copy of the crate, a native plugin's copy gets the server's
+static PIPELINE: RwLock<Option<Arc<Pipeline>>> = RwLock::new(None);
+
+static DEFAULT: once_cell::sync::Lazy<Arc<Pipeline>> = once_cell::sync::Lazy::new(|| {
+    let config = LoggingConfig {
+        file: false,
+        ..Default::default()
+    };
+    Arc::new(Pipeline { config, file: None })
+});
+
+/// The installed pipeline, or one printing info and above to stdout
+pub fn pipeline() -> Arc<Pipeline> {
+    PIPELINE
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+        .unwrap_or_else(|| DEFAULT.clone())
+}
+
+/// The scheme and host of `url`, without credentials, path or query which may hold secrets
+pub fn redact_url(url: &str) -> String {
+    let (scheme, rest) = url.split_once("://").unwrap_or(("", url));
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    let host = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    let redacted = if authority.len() < rest.len() {
+        "/…"
+    } else {
+        ""
+    };
+    match scheme {
+        "" => format!("{host}{redacted}"),
+        _ => format!("{scheme}://{host}{redacted}"),
+    }
+}
+
+/// `path` with the token of an incoming webhook URL masked
+pub fn redact_path(path: &str) -> String {
+    match path
+        .strip_prefix("/webhooks/")
+        .and_then(|p| p.split_once('/'))
+    {
+        Some((id, _)) => format!("/webhooks/{id}/***"),
+        None => path.to_string(),
+    }
+}
+
+/// `text` with every occurrence of the non-empty `secrets` masked
+pub fn redact_secrets(text: &str, secrets: &[&str]) -> String {
+    secrets
+        .iter()
+        .filter(|s| !s.is_empty())
+        .fold(text.to_string(), |text, secret| text.replace(secret, "***"))
+}
+
+/// Send every log of this copy of the crate, and of the `log` and `tracing` facades, to `pipeline`
+pub fn install(pipeline: Arc<Pipeline>) {
+    let max_level = match pipeline.min_level() {
+        Level::Trace => log::LevelFilter::Trace,
+        Level::Debug => log::LevelFilter::Debug,
+        Level::Info => log::LevelFilter::Info,
+        Level::Warn => log::LevelFilter::Warn,
+        Level::Error => log::LevelFilter::Error,
+    };
+    *PIPELINE.write().unwrap_or_else(PoisonError::into_inner) = Some(pipeline);
+
+    // Both facades only take the first logger, which reads the pipeline on every record
+    let _ = log::set_logger(&LogBridge);
+    log::set_max_level(max_level);
+    let _ = tracing_core::dispatcher::set_global_default(tracing_core::Dispatch::new(
+        TracingBridge::default(),
+    ));
+}
+
+/// Forwards `log` records
+struct LogBridge;
+
+fn from_log(level: log::Level) -> Level {
+    match level {
+        log::Level::Trace => Level::Trace,
+        log::Level::Debug => Level::Debug,
+        log::Level::Info => Level::Info,
+        log::Level::Warn => Level::Warn,
+        log::Level::Error => Level::Error,
+    }
+}
+
+impl log::Log for LogBridge {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        pipeline().enabled(metadata.target(), from_log(metadata.level()))
+    }
+
+    fn log(&self, record: &log::Record) {
+        pipeline().write(&Record {
+            level: from_log(record.level()),
+            logger: record.target(),
+            message: &record.args().to_string(),
+            fields: &[],
+        });
+    }
+
+    fn flush(&self) {}
+}
+
+/// Forwards `tracing` events, spans are not recorded
+#[derive(Default)]
+struct TracingBridge {
+    next_span: AtomicU64,
+}
+
+fn from_tracing(level: &tracing_core::Level) -> Level {
+    match *level {
+        tracing_core::Level::TRACE => Level::Trace,
+        tracing_core::Level::DEBUG => Level::Debug,
+        tracing_core::Level::INFO => Level::Info,
+        tracing_core::Level::WARN => Level::Warn,
+        _ => Level::Error,
+    }
+}
+
+/// Collects the `message` and other fields of an event
+#[derive(Default)]
+struct EventVisitor {
+    message: String,
+    fields: Vec<(String, String)>,
+}
+
+impl tracing_core::field::Visit for EventVisitor {
+    fn record_str(&mut self, field: &tracing_core::Field, value: &str) {
+        if field.name() == "message" {
+            self.message = value.to_string();
+        } else {
+            self.fields
+                .push((field.name().to_string(), value.to_string()));
+        }
+    }
+
+    fn record_debug(&mut self, field: &tracing_core::Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.message = format!("{value:?}");
+        } else {
+            self.fields
+                .push((field.name().to_string(), format!("{value:?}")));
+        }
+    }
+}
+
+impl tracing_core::Subscriber for TracingBridge {
+    fn enabled(&self, metadata: &tracing_core::Metadata<'_>) -> bool {
+        pipeline().enabled(metadata.target(), from_tracing(metadata.level()))
+    }
+
+    fn new_span(&self, _: &tracing_core::span::Attributes<'_>) -> tracing_core::span::Id {
+        tracing_core::span::Id::from_u64(self.next_span.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+
+    fn record(&self, _: &tracing_core::span::Id, _: &tracing_core::span::Record<'_>) {}
+
+    fn record_follows_from(&self, _: &tracing_core::span::Id, _: &tracing_core::span::Id) {}
+
+    fn event(&self, event: &tracing_core::Event<'_>) {
+        let mut visitor = EventVisitor::default();
+        event.record(&mut visitor);
+        let metadata = event.metadata();
+        pipeline().write(&Record {
+            level: from_tracing(metadata.level()),
+            logger: metadata.target(),
+            message: &visitor.message,
+            fields: &visitor.fields,
+        });
+    }
+
+    fn enter(&self, _: &tracing_core::span::Id) {}
+
+    fn exit(&self, _: &tracing_core::span::Id) {}
+}
 
 pub struct Logger {
-    name: String,
+    name: Cow<'static, str>,
 }
 
 impl Logger {
     pub fn new<T: Display>(name: T) -> Self {
         Logger {
-            name: name.to_string(),
+            name: Cow::Owned(name.to_string()),
         }
     }
 
+    /// Create a logger from a static name, usable in `const` items
+    pub const fn new_static(name: &'static str) -> Self {
+        Logger {
+            name: Cow::Borrowed(name),
+        }
+    }
+
+    /// Whether messages of `level` are written
+    pub fn enabled(&self, level: Level) -> bool {
+        pipeline().enabled(&self.name, level)
+    }
+
+    pub fn log<T: Display>(&self, level: Level, message: T) {
+        let pipeline = pipeline();
+        if pipeline.enabled(&self.name, level) {
+            pipeline.write(&Record {
+                level,
+                logger: &self.name,
+                message: &message.to_string(),
+                fields: &[],
+            });
+        }
+    }
+
+    /// Log a message content or raw payload at debug level, if `log_payloads` is enabled
+    pub fn payload<T: Display>(&self, label: &str, payload: T) {
+        if pipeline().log_payloads() {
+            self.debug(format!("{label}: {payload}"));
+        }
+    }
+
+    pub fn trace<T: Display>(&self, message: T) {
+        self.log(Level::Trace, message);
+    }
+
+    pub fn debug<T: Display>(&self, message: T) {
+        self.log(Level::Debug, message);
+    }
+
     pub fn info<T: Display>(&self, message: T) {
-        println!("\x1b[32mINFO\x1b[0m ({}) › {}", self.name, message);
+        self.log(Level::Info, message);
     }
 
     pub fn warn<T: Display>(&self, message: T) {
-        println!("\x1b[33mWARN\x1b[0m ({}) › {}", self.name, message);
+        self.log(Level::Warn, message);
     }
 
     pub fn error<T: Display>(&self, message: T) {
-        println!("\x1b[31mERROR\x1b[0m ({}) › {}", self.name, message);
+        self.log(Level::Error, message);
     }
 
     pub fn extract<T, E: Display, D: Display>(&self, v: Result<T, E>, m: D) -> Option<T> {
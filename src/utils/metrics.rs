@@ -0,0 +1,258 @@
+//! Counters and latency histograms, exported at `GET /metrics` in the Prometheus text format
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        Mutex, PoisonError,
+        atomic::{AtomicI64, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::types::data::Channel;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MetricsConfig {
+    /// Off by default, the metrics tell how busy each channel is
+    pub enabled: bool,
+    /// Bearer token scrapers must send, `/metrics` is public if unset
+    pub token: Option<String>,
+}
+
+/// Channel label of messages posted to channels that are not configured
+const OTHER_CHANNEL: &str = "other";
+
+/// Upper bounds of the latency buckets, in seconds
+const BUCKETS: [f64; 12] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0,
+];
+
+#[derive(Default, Clone)]
+struct Histogram {
+    /// Observations per bucket, not cumulative
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, secs: f64) {
+        if let Some(i) = BUCKETS.iter().position(|b| secs <= *b) {
+            self.buckets[i] += 1;
+        }
+        self.count += 1;
+        self.sum += secs;
+    }
+}
+
+/// Histograms by label value
+#[derive(Default)]
+struct Histograms(Mutex<BTreeMap<String, Histogram>>);
+
+impl Histograms {
+    fn observe(&self, label: &str, duration: Duration) {
+        let mut map = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        match map.get_mut(label) {
+            Some(h) => h.observe(duration.as_secs_f64()),
+            None => {
+                let mut h = Histogram::default();
+                h.observe(duration.as_secs_f64());
+                map.insert(label.to_string(), h);
+            }
+        }
+    }
+
+    fn snapshot(&self) -> BTreeMap<String, Histogram> {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+/// Latencies of the statements run on the main database, by statement kind.
+/// Process wide, the SQLite trace callback is a plain function.
+static DB_QUERIES: once_cell::sync::Lazy<Histograms> =
+    once_cell::sync::Lazy::new(Histograms::default);
+
+/// Record a statement of the main database, see `Database::open`
+pub(crate) fn observe_query(sql: &str, duration: Duration) {
+    let kind = sql
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_lowercase();
+    let kind = match kind.as_str() {
+        "select" | "insert" | "update" | "delete" | "create" | "alter" | "pragma" => &kind,
+        _ => "other",
+    };
+    DB_QUERIES.observe(kind, duration);
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    /// Chat messages posted, by configured channel or `OTHER_CHANNEL`
+    messages: Mutex<BTreeMap<String, u64>>,
+    /// Handling time of client requests, by `ClientMessage` type
+    requests: Histograms,
+    auth_failures: AtomicU64,
+    /// Time spent in plugin hooks, by plugin
+    plugin_hooks: Histograms,
+    /// Broadcast sends started and not finished yet
+    broadcast_pending: AtomicI64,
+}
+
+impl Metrics {
+    /// Metrics labelling messages with one of `channels`, so clients can't add labels
+    pub fn new(channels: &[Channel]) -> Self {
+        let messages = channels
+            .iter()
+            .map(|c| c.id.as_str())
+            .chain([OTHER_CHANNEL])
+            .map(|id| (id.to_string(), 0))
+            .collect();
+        Self {
+            messages: Mutex::new(messages),
+            ..Self::default()
+        }
+    }
+
+    pub fn message_posted(&self, channel_id: &str) {
+        let mut messages = self.messages.lock().unwrap_or_else(PoisonError::into_inner);
+        match messages.get_mut(channel_id) {
+            Some(count) => *count += 1,
+            None => *messages.entry(OTHER_CHANNEL.to_string()).or_default() += 1,
+        }
+    }
+
+    pub fn request_handled(&self, kind: &str, duration: Duration) {
+        self.requests.observe(kind, duration);
+    }
+
+    pub fn auth_failed(&self) {
+        self.auth_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn plugin_called(&self, plugin: &str, duration: Duration) {
+        self.plugin_hooks.observe(plugin, duration);
+    }
+
+    pub fn broadcast_queued(&self) {
+        self.broadcast_pending.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn broadcast_sent(&self) {
+        self.broadcast_pending.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Render every metric, `clients` is the number of open connections
+    pub fn render(&self, clients: usize) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "voxa_connected_clients",
+            "gauge",
+            "Open WebSocket connections",
+        );
+        let _ = writeln!(out, "voxa_connected_clients {clients}");
+
+        header(
+            &mut out,
+            "voxa_messages_total",
+            "counter",
+            "Chat messages posted, by channel",
+        );
+        let messages = self
+            .messages
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        for (channel, count) in messages {
+            let _ = writeln!(
+                out,
+                "voxa_messages_total{{channel=\"{}\"}} {count}",
+                escape(&channel)
+            );
+        }
+
+        header(
+            &mut out,
+            "voxa_auth_failures_total",
+            "counter",
+            "Failed authentications of clients and API requests",
+        );
+        let _ = writeln!(
+            out,
+            "voxa_auth_failures_total {}",
+            self.auth_failures.load(Ordering::Relaxed)
+        );
+
+        header(
+            &mut out,
+            "voxa_broadcast_pending",
+            "gauge",
+            "Broadcast sends waiting for a client",
+        );
+        let _ = writeln!(
+            out,
+            "voxa_broadcast_pending {}",
+            self.broadcast_pending.load(Ordering::Relaxed)
+        );
+
+        histograms(
+            &mut out,
+            "voxa_request_duration_seconds",
+            "Time to handle client requests, by type",
+            "type",
+            &self.requests,
+        );
+        histograms(
+            &mut out,
+            "voxa_plugin_hook_duration_seconds",
+            "Time spent in plugin hooks, by plugin",
+            "plugin",
+            &self.plugin_hooks,
+        );
+        histograms(
+            &mut out,
+            "voxa_db_query_duration_seconds",
+            "Time to run database statements, by kind",
+            "statement",
+            &DB_QUERIES,
+        );
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}");
+}
+
+fn histograms(out: &mut String, name: &str, help: &str, label: &str, histograms: &Histograms) {
+    header(out, name, "histogram", help);
+    for (value, h) in histograms.snapshot() {
+        let labels = format!("{label}=\"{}\"", escape(&value));
+        let mut cumulative = 0;
+        for (bound, count) in BUCKETS.iter().zip(h.buckets) {
+            cumulative += count;
+            let _ = writeln!(out, "{name}_bucket{{{labels},le=\"{bound}\"}} {cumulative}");
+        }
+        let _ = writeln!(out, "{name}_bucket{{{labels},le=\"+Inf\"}} {}", h.count);
+        let _ = writeln!(out, "{name}_sum{{{labels}}} {}", h.sum);
+        let _ = writeln!(out, "{name}_count{{{labels}}} {}", h.count);
+    }
+}
+
+/// Escape a label value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -1,7 +1,17 @@
 pub mod client;
+pub mod commands;
 pub mod database;
+pub mod deflate;
 #[cfg(feature = "loader")]
 pub mod loader;
 pub mod logger;
+pub mod metrics;
+pub mod native;
 pub mod plugin;
+pub mod ratelimit;
+pub mod scheduler;
+pub mod storage;
+pub mod tls;
 pub mod vfs;
+pub mod wasm;
+pub mod webhooks;
@@ -0,0 +1,361 @@
+//! The C ABI between the server and native plugin libraries.
+//!
+//! A library exports a `PluginDescriptor` with `export_plugin!`. Its plugin never leaves the
+//! library: the server calls it through the `extern "C"` functions of the descriptor's vtable,
+//! which pass requests, messages and results as JSON and catch the plugin's panics.
+//!
+//! The server and its clients are passed as opaque handles, which the plugin uses through its
+//! own copy of this crate. That copy must match the server's, see `PluginManifest`.
+
+use std::{
+    cell::RefCell,
+    ffi::{CStr, c_void},
+    mem::ManuallyDrop,
+    os::raw::c_char,
+    sync::Arc,
+};
+
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::{
+    Server,
+    utils::{
+        client::Client,
+        plugin::{DynPlugin, PluginConfig, catch_panic},
+    },
+};
+
+/// Layout version of `PluginDescriptor`, the only field read before it is verified
+pub const DESCRIPTOR_VERSION: u32 = 3;
+
+/// Version of the `Plugin` trait, bumped whenever it changes
+pub const PLUGIN_API_VERSION: u32 = 4;
+
+/// Version of this crate, a plugin must be built against the one the server runs
+pub const CRATE_VERSION: &CStr = cstr(concat!(env!("CARGO_PKG_VERSION"), "\0"));
+
+/// Compiler that built this crate, a plugin must be built with the one that built the server
+pub const RUSTC_VERSION: &CStr = cstr(concat!(env!("VOXA_RUSTC_VERSION"), "\0"));
+
+/// Enabled features of this crate that change the layout of types plugins use,
+/// a plugin must enable the same ones as the server. `tls` and `wasm` don't.
+pub const FEATURES: &CStr = cstr(concat!(env!("VOXA_FEATURES"), "\0"));
+
+/// Name of the static a dynamically loaded plugin exports, see `export_plugin!`
+pub const DESCRIPTOR_SYMBOL: &[u8] = b"VOXA_PLUGIN_DESCRIPTOR\0";
+
+#[doc(hidden)]
+pub const fn cstr(s: &'static str) -> &'static CStr {
+    match CStr::from_bytes_with_nul(s.as_bytes()) {
+        Ok(s) => s,
+        Err(_) => panic!("string must end with a single NUL byte"),
+    }
+}
+
+/// Describes a plugin before any of its code runs, all strings are NUL terminated.
+///
+/// The handles a plugin receives point to this crate's Rust types, so it must be built
+/// against the server's version and layout-changing features, with the same compiler.
+#[repr(C)]
+pub struct PluginManifest {
+    pub name: *const c_char,
+    pub version: *const c_char,
+    /// `PLUGIN_API_VERSION` the plugin was built with
+    pub api_version: u32,
+    /// `CRATE_VERSION` the plugin was built with
+    pub crate_version: *const c_char,
+    /// `RUSTC_VERSION` the plugin was built with
+    pub rustc_version: *const c_char,
+    /// `FEATURES` the plugin was built with
+    pub features: *const c_char,
+}
+
+impl PluginManifest {
+    /// The manifest of a plugin built against this crate, `name` and `version` end with NUL
+    pub const fn new(name: &'static str, version: &'static str) -> Self {
+        Self {
+            name: cstr(name).as_ptr(),
+            version: cstr(version).as_ptr(),
+            api_version: PLUGIN_API_VERSION,
+            crate_version: CRATE_VERSION.as_ptr(),
+            rustc_version: RUSTC_VERSION.as_ptr(),
+            features: FEATURES.as_ptr(),
+        }
+    }
+}
+
+/// Bytes borrowed for the duration of a call
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct PluginSlice {
+    pub ptr: *const u8,
+    pub len: usize,
+}
+
+impl PluginSlice {
+    pub fn of(bytes: &[u8]) -> Self {
+        Self {
+            ptr: bytes.as_ptr(),
+            len: bytes.len(),
+        }
+    }
+
+    /// # Safety
+    /// `ptr` must point to `len` bytes that live as long as `'a`
+    pub unsafe fn as_bytes<'a>(self) -> &'a [u8] {
+        if self.ptr.is_null() {
+            return &[];
+        }
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+/// Bytes allocated by a library, handed back to its `free` function once read.
+/// A null `ptr` means there is no value.
+#[repr(C)]
+pub struct PluginBuffer {
+    pub ptr: *mut u8,
+    pub len: usize,
+    pub cap: usize,
+}
+
+impl PluginBuffer {
+    pub const NONE: Self = Self {
+        ptr: std::ptr::null_mut(),
+        len: 0,
+        cap: 0,
+    };
+
+    fn new(bytes: Vec<u8>) -> Self {
+        let mut bytes = ManuallyDrop::new(bytes);
+        Self {
+            ptr: bytes.as_mut_ptr(),
+            len: bytes.len(),
+            cap: bytes.capacity(),
+        }
+    }
+
+    fn json(value: &impl Serialize) -> Self {
+        Self::new(serde_json::to_vec(value).expect("plugin results serialize to JSON"))
+    }
+}
+
+/// Hooks about a client, `client` is a `*const Client` and `server` a `*const Arc<Server>`
+pub type ClientHook =
+    unsafe extern "C" fn(plugin: *const c_void, client: *const c_void, server: *const c_void);
+
+/// Hooks about a message, `message` is the JSON of a `Message`
+pub type MessageHook =
+    unsafe extern "C" fn(plugin: *const c_void, message: PluginSlice, server: *const c_void);
+
+/// Hooks about the server
+pub type ServerHook = unsafe extern "C" fn(plugin: *const c_void, server: *const c_void);
+
+/// The functions of a native plugin. `plugin` is the pointer returned by `create`,
+/// the other handles are described by `ClientHook`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct PluginVTable {
+    /// Create the plugin, null if it panicked
+    pub create: extern "C" fn() -> *mut c_void,
+    pub destroy: unsafe extern "C" fn(plugin: *mut c_void),
+    /// `config` is the JSON of the plugin's `PluginConfig`
+    pub init: unsafe extern "C" fn(plugin: *mut c_void, server: *const c_void, config: PluginSlice),
+    pub priority: unsafe extern "C" fn(plugin: *const c_void) -> i32,
+    /// Takes the JSON of a `WsMessage<ClientMessage>`, returns the one of a `RequestAction`
+    pub on_request: unsafe extern "C" fn(
+        plugin: *const c_void,
+        request: PluginSlice,
+        client: *const c_void,
+        server: *const c_void,
+    ) -> PluginBuffer,
+    /// Takes the JSON of the data, returns the one of a `Result<(), ResponseError>`
+    pub on_plugin_message: unsafe extern "C" fn(
+        plugin: *const c_void,
+        data: PluginSlice,
+        client: *const c_void,
+        server: *const c_void,
+    ) -> PluginBuffer,
+    pub on_connect: ClientHook,
+    pub on_authenticated: ClientHook,
+    pub on_disconnect: ClientHook,
+    pub on_message_created: MessageHook,
+    pub on_message_edited: MessageHook,
+    pub on_message_deleted: MessageHook,
+    pub on_tick: ServerHook,
+    pub on_shutdown: ServerHook,
+    /// Message of the panic caught by the last call on this thread
+    pub take_panic: extern "C" fn() -> PluginBuffer,
+    /// Release a buffer returned by the other functions
+    pub free: unsafe extern "C" fn(buffer: PluginBuffer),
+}
+
+/// Entry point of a dynamically loaded plugin, exported by `export_plugin!`.
+/// Nothing but `abi_version` is read before it matches `DESCRIPTOR_VERSION`.
+#[repr(C)]
+pub struct PluginDescriptor {
+    /// `DESCRIPTOR_VERSION` the plugin was built with
+    pub abi_version: u32,
+    pub manifest: PluginManifest,
+    pub vtable: PluginVTable,
+}
+
+// Only holds pointers to static strings and functions
+unsafe impl Sync for PluginDescriptor {}
+
+impl PluginDescriptor {
+    /// The descriptor of a plugin built against this crate, with its functions compiled into the library
+    pub const fn new(manifest: PluginManifest, create: extern "C" fn() -> *mut c_void) -> Self {
+        Self {
+            abi_version: DESCRIPTOR_VERSION,
+            manifest,
+            vtable: PluginVTable {
+                create,
+                destroy,
+                init,
+                priority,
+                on_request,
+                on_plugin_message,
+                on_connect,
+                on_authenticated,
+                on_disconnect,
+                on_message_created,
+                on_message_edited,
+                on_message_deleted,
+                on_tick,
+                on_shutdown,
+                take_panic,
+                free,
+            },
+        }
+    }
+}
+
+thread_local! {
+    /// Panic caught by the last call into this copy of the crate on this thread
+    static PANIC: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Run `f`, recording its panic for `take_panic`. A library links its own copy of `std`,
+/// so its panics can't unwind into the server.
+fn guard<R>(f: impl FnOnce() -> R) -> Option<R> {
+    catch_panic(f)
+        .map_err(|msg| PANIC.with_borrow_mut(|p| *p = Some(msg)))
+        .ok()
+}
+
+fn decode<T: DeserializeOwned>(bytes: PluginSlice) -> T {
+    serde_json::from_slice(unsafe { bytes.as_bytes() }).expect("the server sent invalid JSON")
+}
+
+/// Box the plugin returned by `new` for `PluginVTable::create`, null if it panicked
+#[doc(hidden)]
+pub fn create(new: impl FnOnce() -> DynPlugin) -> *mut c_void {
+    guard(|| Box::into_raw(Box::new(new())).cast()).unwrap_or(std::ptr::null_mut())
+}
+
+unsafe fn plugin<'a>(plugin: *const c_void) -> &'a DynPlugin {
+    unsafe { &*plugin.cast() }
+}
+
+unsafe fn server<'a>(server: *const c_void) -> &'a Arc<Server> {
+    unsafe { &*server.cast() }
+}
+
+unsafe fn client<'a>(client: *const c_void) -> &'a Client {
+    unsafe { &*client.cast() }
+}
+
+unsafe extern "C" fn destroy(plugin: *mut c_void) {
+    guard(|| drop(unsafe { Box::from_raw(plugin.cast::<DynPlugin>()) }));
+}
+
+unsafe extern "C" fn init(plugin: *mut c_void, server: *const c_void, config: PluginSlice) {
+    let (plugin, server) = unsafe { (&mut *plugin.cast::<DynPlugin>(), self::server(server)) };
+    // The library's copy of the logger and facades writes to the server's pipeline
+    crate::utils::logger::install(server.logs.clone());
+    guard(|| plugin.init(server, &decode::<PluginConfig>(config)));
+}
+
+unsafe extern "C" fn priority(plugin: *const c_void) -> i32 {
+    guard(|| unsafe { self::plugin(plugin) }.priority()).unwrap_or(0)
+}
+
+unsafe extern "C" fn on_request(
+    plugin: *const c_void,
+    request: PluginSlice,
+    client: *const c_void,
+    server: *const c_void,
+) -> PluginBuffer {
+    let (plugin, client, server) = unsafe {
+        (
+            self::plugin(plugin),
+            self::client(client),
+            self::server(server),
+        )
+    };
+    guard(|| PluginBuffer::json(&plugin.on_request(&decode(request), client, server)))
+        .unwrap_or(PluginBuffer::NONE)
+}
+
+unsafe extern "C" fn on_plugin_message(
+    plugin: *const c_void,
+    data: PluginSlice,
+    client: *const c_void,
+    server: *const c_void,
+) -> PluginBuffer {
+    let (plugin, client, server) = unsafe {
+        (
+            self::plugin(plugin),
+            self::client(client),
+            self::server(server),
+        )
+    };
+    guard(|| PluginBuffer::json(&plugin.on_plugin_message(&decode(data), client, server)))
+        .unwrap_or(PluginBuffer::NONE)
+}
+
+macro_rules! client_hooks {
+    ($($hook:ident),*) => {$(
+        unsafe extern "C" fn $hook(plugin: *const c_void, client: *const c_void, server: *const c_void) {
+            let (plugin, client, server) = unsafe { (self::plugin(plugin), self::client(client), self::server(server)) };
+            guard(|| plugin.$hook(client, server));
+        }
+    )*};
+}
+
+macro_rules! message_hooks {
+    ($($hook:ident),*) => {$(
+        unsafe extern "C" fn $hook(plugin: *const c_void, message: PluginSlice, server: *const c_void) {
+            let (plugin, server) = unsafe { (self::plugin(plugin), self::server(server)) };
+            guard(|| plugin.$hook(&decode(message), server));
+        }
+    )*};
+}
+
+macro_rules! server_hooks {
+    ($($hook:ident),*) => {$(
+        unsafe extern "C" fn $hook(plugin: *const c_void, server: *const c_void) {
+            let (plugin, server) = unsafe { (self::plugin(plugin), self::server(server)) };
+            guard(|| plugin.$hook(server));
+        }
+    )*};
+}
+
+client_hooks!(on_connect, on_authenticated, on_disconnect);
+message_hooks!(on_message_created, on_message_edited, on_message_deleted);
+server_hooks!(on_tick, on_shutdown);
+
+extern "C" fn take_panic() -> PluginBuffer {
+    PANIC
+        .with_borrow_mut(Option::take)
+        .map_or(PluginBuffer::NONE, |msg| {
+            PluginBuffer::new(msg.into_bytes())
+        })
+}
+
+unsafe extern "C" fn free(buffer: PluginBuffer) {
+    if !buffer.ptr.is_null() {
+        drop(unsafe { Vec::from_raw_parts(buffer.ptr, buffer.len, buffer.cap) });
+    }
+}
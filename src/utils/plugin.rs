@@ -1,22 +1,158 @@
-use std::sync::Arc;
+use std::{
+    panic::{AssertUnwindSafe, catch_unwind},
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
 
 use crate::{
     Server,
-    types::message::{ClientMessage, WsMessage},
+    types::{
+        data::Message,
+        message::{ClientMessage, ResponseError, WsMessage},
+    },
     utils::client::Client,
 };
 
 pub type DynPlugin = Box<dyn Plugin + Send + Sync>;
 
+/// Contents of `plugins/<name>/config.json`
+pub type PluginConfig = serde_json::Map<String, serde_json::Value>;
+
+/// Describe the payload of a caught panic
+pub fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// Run `f`, returning the message of a panic instead of unwinding. Generic, so a native
+/// plugin passing its callbacks to the server catches their panics with its own std.
+pub fn catch_panic<R>(f: impl FnOnce() -> R) -> Result<R, String> {
+    catch_unwind(AssertUnwindSafe(f)).map_err(|p| panic_message(&*p))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PluginsConfig {
+    /// Seconds between `Plugin::on_tick` calls, 0 disables ticking
+    pub tick_interval: u64,
+    /// Only plugins with these names are loaded, every plugin if empty
+    pub enabled: Vec<String>,
+    /// Plugins with these names are never loaded
+    pub disabled: Vec<String>,
+    /// Load, reload and unload plugins when their library files change, for development
+    pub watch: bool,
+    /// Panics after which a plugin is unloaded, 0 never unloads it
+    pub max_panics: u32,
+    /// Limits of `.wasm` plugins, requires the `wasm` feature
+    pub wasm: crate::utils::wasm::WasmConfig,
+}
+
+impl Default for PluginsConfig {
+    fn default() -> Self {
+        Self {
+            tick_interval: 1,
+            enabled: Vec::new(),
+            disabled: Vec::new(),
+            watch: false,
+            max_panics: 3,
+            wasm: crate::utils::wasm::WasmConfig::default(),
+        }
+    }
+}
+
+impl PluginsConfig {
+    pub fn is_enabled(&self, name: &str) -> bool {
+        (self.enabled.is_empty() || self.enabled.iter().any(|n| n == name))
+            && !self.disabled.iter().any(|n| n == name)
+    }
+}
+
+/// What happens to a request after a plugin saw it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RequestAction {
+    /// Pass the request on unchanged
+    Continue,
+    /// Pass on this request instead, e.g. with censored contents
+    Modify(WsMessage<ClientMessage>),
+    /// The plugin handled the request, later plugins and the server don't see it
+    Handled,
+    /// Drop the request and send the error to the client
+    Reject(ResponseError),
+}
+
+#[allow(unused_variables)]
+/// Hooks run concurrently on the connection threads, plugins keep mutable state
+/// behind their own locks or atomics
 pub trait Plugin {
-    fn init(&mut self, server: &Arc<Server>);
-    #[allow(unused_variables)]
+    /// Unique name, used to route `ClientMessage::Plugin` requests
+    fn name(&self) -> &str;
+
+    /// Called once before the plugin receives any other hook, `config` holds its config file
+    fn init(&mut self, server: &Arc<Server>, config: &PluginConfig);
+
+    /// Plugins with a higher priority see requests first, equal priorities keep load order
+    fn priority(&self) -> i32 {
+        0
+    }
+
+    /// Inspect a request before the server handles it. `req` already includes
+    /// the modifications of plugins with a higher priority.
     fn on_request(
-        &mut self,
+        &self,
         req: &WsMessage<ClientMessage>,
         client: &Client,
         server: &Arc<Server>,
-    ) -> bool {
-        false
+    ) -> RequestAction {
+        RequestAction::Continue
+    }
+
+    /// A `ClientMessage::Plugin` request addressed to this plugin.
+    /// An error is sent back to the client.
+    fn on_plugin_message(
+        &self,
+        data: &serde_json::Map<String, serde_json::Value>,
+        client: &Client,
+        server: &Arc<Server>,
+    ) -> Result<(), ResponseError> {
+        Err(ResponseError::InvalidRequest(format!(
+            "Plugin {} does not accept messages",
+            self.name()
+        )))
+    }
+
+    /// A WebSocket connection was opened, the client is not authenticated yet
+    fn on_connect(&self, client: &Client, server: &Arc<Server>) {}
+
+    /// The client completed the handshake and authenticated
+    fn on_authenticated(&self, client: &Client, server: &Arc<Server>) {}
+
+    /// The connection was closed
+    fn on_disconnect(&self, client: &Client, server: &Arc<Server>) {}
+
+    /// A message was stored and broadcast
+    fn on_message_created(&self, message: &Message, server: &Arc<Server>) {}
+
+    /// A message was edited, `message` holds the new contents
+    fn on_message_edited(&self, message: &Message, server: &Arc<Server>) {}
+
+    /// A message was deleted
+    fn on_message_deleted(&self, message: &Message, server: &Arc<Server>) {}
+
+    /// Called every `PluginsConfig::tick_interval` seconds
+    fn on_tick(&self, server: &Arc<Server>) {}
+
+    /// Called once the server stopped accepting connections and disconnected its clients
+    fn on_shutdown(&self, server: &Arc<Server>) {}
+
+    /// Message of a panic caught inside a dynamic library by the last call on this thread, see `utils::native`
+    #[doc(hidden)]
+    fn take_panic(&self) -> Option<String> {
+        None
     }
 }
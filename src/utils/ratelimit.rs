@@ -0,0 +1,213 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    net::IpAddr,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// How often buckets that refilled completely are dropped
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    /// Bucket shared by every connection of the same user
+    pub per_user: BucketConfig,
+    /// Bucket shared by every connection from the same IP address
+    pub per_ip: BucketConfig,
+    /// Consecutive rate limited requests before the client gets disconnected
+    pub max_violations: u32,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct BucketConfig {
+    /// Maximum burst of requests
+    pub capacity: f64,
+    /// Tokens restored per second
+    pub refill_per_sec: f64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            per_user: BucketConfig {
+                capacity: 10.0,
+                refill_per_sec: 2.0,
+            },
+            per_ip: BucketConfig {
+                capacity: 30.0,
+                refill_per_sec: 6.0,
+            },
+            max_violations: 20,
+        }
+    }
+}
+
+/// Outcome of a rate limit check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimit {
+    Allowed,
+    /// The request must be rejected, tokens will be available after `retry_after`
+    Limited {
+        retry_after: Duration,
+    },
+    /// The client kept sending while limited and should be disconnected
+    Exceeded,
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, config: BucketConfig, now: Instant) {
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * config.refill_per_sec).min(config.capacity);
+        self.last = now;
+    }
+
+    /// Time until one token is available
+    fn wait(&self, config: BucketConfig) -> Option<Duration> {
+        if self.tokens >= 1.0 {
+            return None;
+        }
+
+        if config.refill_per_sec <= 0.0 {
+            return Some(Duration::MAX);
+        }
+
+        Some(Duration::from_secs_f64(
+            (1.0 - self.tokens) / config.refill_per_sec,
+        ))
+    }
+}
+
+struct Buckets<K> {
+    config: BucketConfig,
+    map: HashMap<K, Bucket>,
+    last_prune: Option<Instant>,
+}
+
+impl<K: Hash + Eq> Buckets<K> {
+    fn new(config: BucketConfig) -> Self {
+        Self {
+            config,
+            map: HashMap::new(),
+            last_prune: None,
+        }
+    }
+
+    fn get(&mut self, key: K, now: Instant) -> &mut Bucket {
+        let last_prune = *self.last_prune.get_or_insert(now);
+        if now.duration_since(last_prune) >= PRUNE_INTERVAL {
+            let config = self.config;
+            self.map.retain(|_, b| {
+                b.refill(config, now);
+                b.tokens < config.capacity
+            });
+            self.last_prune = Some(now);
+        }
+
+        let config = self.config;
+        let bucket = self.map.entry(key).or_insert(Bucket {
+            tokens: config.capacity,
+            last: now,
+        });
+        bucket.refill(config, now);
+        bucket
+    }
+}
+
+/// Token bucket rate limiter keyed by user and by IP address
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    users: Mutex<Buckets<String>>,
+    ips: Mutex<Buckets<IpAddr>>,
+    /// Consecutive violations per connection id
+    violations: Mutex<HashMap<u64, u32>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            users: Mutex::new(Buckets::new(config.per_user)),
+            ips: Mutex::new(Buckets::new(config.per_ip)),
+            violations: Mutex::new(HashMap::new()),
+            config,
+        }
+    }
+
+    /// Take a token for a request from connection `conn`, if both the user and IP buckets allow it
+    pub fn check(&self, conn: u64, user: Option<&str>, ip: Option<IpAddr>) -> RateLimit {
+        self.check_at(conn, user, ip, Instant::now())
+    }
+
+    /// `check` at a given time, which must not go backwards
+    pub fn check_at(
+        &self,
+        conn: u64,
+        user: Option<&str>,
+        ip: Option<IpAddr>,
+        now: Instant,
+    ) -> RateLimit {
+        if !self.config.enabled {
+            return RateLimit::Allowed;
+        }
+
+        let mut users = self.users.lock().unwrap();
+        let mut ips = self.ips.lock().unwrap();
+
+        let mut user_bucket = user.map(|u| users.get(u.to_string(), now));
+        let user_wait = user_bucket
+            .as_ref()
+            .and_then(|b| b.wait(self.config.per_user));
+        let mut ip_bucket = ip.map(|ip| ips.get(ip, now));
+        let ip_wait = ip_bucket.as_ref().and_then(|b| b.wait(self.config.per_ip));
+
+        let mut violations = self.violations.lock().unwrap();
+        match user_wait.max(ip_wait) {
+            None => {
+                if let Some(b) = user_bucket.as_mut() {
+                    b.tokens -= 1.0;
+                }
+                if let Some(b) = ip_bucket.as_mut() {
+                    b.tokens -= 1.0;
+                }
+                violations.remove(&conn);
+                RateLimit::Allowed
+            }
+            Some(retry_after) => {
+                let count = violations.entry(conn).or_insert(0);
+                *count += 1;
+                if *count > self.config.max_violations {
+                    violations.remove(&conn);
+                    RateLimit::Exceeded
+                } else {
+                    RateLimit::Limited { retry_after }
+                }
+            }
+        }
+    }
+
+    /// Forget the violations of a closed connection
+    pub fn disconnected(&self, conn: u64) {
+        self.violations
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&conn);
+    }
+
+    /// Connections with violations that are not cleared yet
+    pub fn tracked_connections(&self) -> usize {
+        self.violations
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+    }
+}
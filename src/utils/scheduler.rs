@@ -0,0 +1,185 @@
+//! Delayed and recurring tasks scheduled by plugins
+
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    sync::{Condvar, Mutex, MutexGuard, PoisonError},
+    thread::ThreadId,
+    time::{Duration, Instant},
+};
+
+use crate::handle::ServerHandle;
+
+/// Longest the scheduler sleeps before checking whether the server is stopping
+const IDLE_POLL: Duration = Duration::from_millis(100);
+
+/// Longer delays are clamped to this, such a task never runs in practice
+const MAX_DELAY: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+
+/// `delay` after `from`, clamped so huge delays can't overflow `Instant`
+fn after(from: Instant, delay: Duration) -> Instant {
+    from.checked_add(delay).unwrap_or(from + MAX_DELAY)
+}
+
+/// Identifies a scheduled task, see [`ServerHandle::cancel`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TaskId(u64);
+
+/// A task body, returning the message of a panic it caught
+pub(crate) type TaskFn = Box<dyn FnMut(&ServerHandle) -> Result<(), String> + Send>;
+
+struct Task {
+    owner: String,
+    interval: Option<Duration>,
+    f: TaskFn,
+}
+
+#[derive(Default)]
+struct State {
+    next_id: u64,
+    tasks: HashMap<TaskId, Task>,
+    queue: BinaryHeap<Reverse<(Instant, TaskId)>>,
+    /// Task taken out of `tasks` while it runs, and whether it was cancelled meanwhile
+    running: Option<(TaskId, String, bool)>,
+    thread: Option<ThreadId>,
+}
+
+#[derive(Default)]
+pub struct Scheduler {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+impl Scheduler {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Queue `f` to run after `delay`, then every `interval` if one is given.
+    /// Delays longer than `MAX_DELAY` are clamped.
+    pub(crate) fn add(
+        &self,
+        owner: &str,
+        delay: Duration,
+        interval: Option<Duration>,
+        f: TaskFn,
+    ) -> TaskId {
+        let mut state = self.state();
+        let id = TaskId(state.next_id);
+        state.next_id += 1;
+        state.tasks.insert(
+            id,
+            Task {
+                owner: owner.to_string(),
+                interval,
+                f,
+            },
+        );
+        state
+            .queue
+            .push(Reverse((after(Instant::now(), delay), id)));
+        drop(state);
+
+        self.changed.notify_all();
+        id
+    }
+
+    /// Cancel a task owned by `owner`, returns whether it was still scheduled
+    pub(crate) fn cancel(&self, owner: &str, id: TaskId) -> bool {
+        let mut state = self.state();
+        if state.tasks.get(&id).is_some_and(|t| t.owner == owner) {
+            state.tasks.remove(&id);
+            return true;
+        }
+
+        match &mut state.running {
+            Some((running, o, cancelled)) if *running == id && o == owner && !*cancelled => {
+                *cancelled = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Cancel every task of `owner`, waiting for a running one to return
+    pub(crate) fn cancel_owner(&self, owner: &str) {
+        let mut state = self.state();
+        state.tasks.retain(|_, t| t.owner != owner);
+
+        // A task unloading its own plugin can't wait for itself
+        if state.thread == Some(std::thread::current().id()) {
+            if let Some((_, o, cancelled)) = &mut state.running
+                && o == owner
+            {
+                *cancelled = true;
+            }
+            return;
+        }
+
+        while let Some((_, o, cancelled)) = &mut state.running
+            && o == owner
+        {
+            *cancelled = true;
+            state = self
+                .changed
+                .wait(state)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    /// Run due tasks on the current thread until `stopping` returns true
+    pub(crate) fn run(&self, handle: impl Fn(&str) -> ServerHandle, stopping: impl Fn() -> bool) {
+        self.state().thread = Some(std::thread::current().id());
+
+        while !stopping() {
+            let mut state = self.state();
+            let now = Instant::now();
+            let (due, id) = match state.queue.peek() {
+                Some(Reverse((due, id))) if *due <= now => (*due, *id),
+                next => {
+                    let wait = next.map_or(IDLE_POLL, |Reverse((due, _))| {
+                        due.duration_since(now).min(IDLE_POLL)
+                    });
+                    drop(
+                        self.changed
+                            .wait_timeout(state, wait)
+                            .unwrap_or_else(PoisonError::into_inner),
+                    );
+                    continue;
+                }
+            };
+            state.queue.pop();
+
+            // Cancelled tasks are left in the queue and skipped here
+            let Some(mut task) = state.tasks.remove(&id) else {
+                continue;
+            };
+            state.running = Some((id, task.owner.clone(), false));
+            drop(state);
+
+            let res = (task.f)(&handle(&task.owner));
+
+            let mut state = self.state();
+            let cancelled = state.running.take().is_some_and(|(_, _, c)| c);
+            match (res, task.interval) {
+                (Err(msg), _) => ServerHandle::LOGGER.error(format!(
+                    "Task of plugin {} panicked, cancelling it: {msg}",
+                    task.owner
+                )),
+                (Ok(()), Some(interval)) if !cancelled => {
+                    // Keep the schedule steady, but don't run missed ticks back to back
+                    let next = after(due, interval).max(Instant::now());
+                    state.queue.push(Reverse((next, id)));
+                    state.tasks.insert(id, task);
+                }
+                _ => {}
+            }
+            drop(state);
+            self.changed.notify_all();
+        }
+
+        let mut state = self.state();
+        state.tasks.clear();
+        state.queue.clear();
+    }
+}
@@ -0,0 +1,112 @@
+//! Persistent storage owned by a plugin
+
+use std::sync::{Mutex, PoisonError};
+
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::{handle::ServerHandle, utils::database::Database};
+
+/// A plugin's key-value entries and SQL tables, get one with [`ServerHandle::store`].
+///
+/// Keys are private to the plugin. Its tables must be named with [`PluginStore::table`],
+/// SQL run through the store can't touch any other table.
+pub struct PluginStore {
+    handle: ServerHandle,
+    prefix: String,
+    /// Opened on first use by `migrate`, `execute` or `query`
+    conn: Mutex<Option<Database>>,
+}
+
+/// Prefix of the tables of `plugin`, the hex encoded name ends at the first `__`,
+/// so no plugin's prefix starts with another's
+pub fn table_prefix(plugin: &str) -> String {
+    let hex: String = plugin.bytes().map(|b| format!("{b:02x}")).collect();
+    format!("plugin_{hex}__")
+}
+
+impl ServerHandle {
+    /// Get the storage of the plugin
+    pub fn store(&self) -> PluginStore {
+        PluginStore {
+            prefix: table_prefix(self.plugin()),
+            handle: self.clone(),
+            conn: Mutex::new(None),
+        }
+    }
+}
+
+impl PluginStore {
+    /// Maximum rows returned by `query`
+    pub const MAX_ROWS: usize = 10_000;
+
+    /// Name of the plugin's table `name`
+    pub fn table(&self, name: &str) -> String {
+        format!("{}{name}", self.prefix)
+    }
+
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> crate::Result<Option<T>> {
+        let db = &self.handle.server().db;
+        match db.kv_get(self.handle.plugin(), key)? {
+            Some(value) => Ok(Some(serde_json::from_str(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set<T: Serialize + ?Sized>(&self, key: &str, value: &T) -> crate::Result<()> {
+        let value = serde_json::to_string(value)?;
+        Ok(self
+            .handle
+            .server()
+            .db
+            .kv_set(self.handle.plugin(), key, &value)?)
+    }
+
+    /// Returns whether the key existed
+    pub fn delete(&self, key: &str) -> crate::Result<bool> {
+        Ok(self
+            .handle
+            .server()
+            .db
+            .kv_delete(self.handle.plugin(), key)?)
+    }
+
+    /// Entries whose key starts with `prefix`, ordered by key
+    pub fn list(&self, prefix: &str) -> crate::Result<Vec<(String, serde_json::Value)>> {
+        let db = &self.handle.server().db;
+        db.kv_list(self.handle.plugin(), prefix)?
+            .into_iter()
+            .map(|(k, v)| Ok((k, serde_json::from_str(&v)?)))
+            .collect()
+    }
+
+    /// Apply the migrations that were not applied yet, in order, each in a transaction.
+    ///
+    /// Migrations are only ever appended to, the server tracks the applied ones
+    /// outside of the plugin's tables. Returns how many were applied.
+    pub fn migrate(&self, migrations: &[impl AsRef<str>]) -> crate::Result<usize> {
+        self.with_conn(|db| db.migrate(self.handle.plugin(), migrations))
+    }
+
+    /// Run a statement on the plugin's tables, returns the number of changed rows
+    pub fn execute(&self, sql: &str, params: &[serde_json::Value]) -> crate::Result<usize> {
+        self.with_conn(|db| db.execute_json(sql, params))
+    }
+
+    /// Run a query on the plugin's tables, rows are JSON objects keyed by column name
+    pub fn query(
+        &self,
+        sql: &str,
+        params: &[serde_json::Value],
+    ) -> crate::Result<Vec<serde_json::Map<String, serde_json::Value>>> {
+        self.with_conn(|db| db.query_json(sql, params, Self::MAX_ROWS))
+    }
+
+    fn with_conn<R>(&self, f: impl FnOnce(&Database) -> rusqlite::Result<R>) -> crate::Result<R> {
+        let mut conn = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+        let db = match &mut *conn {
+            Some(db) => db,
+            None => conn.insert(self.handle.server().db.restricted(&self.prefix)?),
+        };
+        Ok(f(db)?)
+    }
+}
@@ -0,0 +1,280 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsConfig {
+    /// PEM encoded certificate chain, relative to the server root
+    pub cert_path: PathBuf,
+    /// PEM encoded private key, relative to the server root
+    pub key_path: PathBuf,
+    /// How often the certificate files are checked for changes, in seconds
+    #[serde(default = "default_reload_interval")]
+    pub reload_interval: u64,
+}
+
+fn default_reload_interval() -> u64 {
+    30
+}
+
+#[cfg(feature = "tls")]
+pub use imp::*;
+
+#[cfg(feature = "tls")]
+mod imp {
+    use std::{
+        io::{self, Read, Write},
+        net::{Shutdown, SocketAddr, TcpStream},
+        path::{Path, PathBuf},
+        sync::{Arc, Mutex, RwLock, Weak},
+        time::{Duration, SystemTime},
+    };
+
+    use anyhow::Context;
+    use rustls::{
+        crypto::CryptoProvider,
+        pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+        server::{ClientHello, ResolvesServerCert},
+        sign::CertifiedKey,
+    };
+
+    use super::TlsConfig;
+    use crate::{logger, utils::client::SecureStream};
+
+    logger!(LOGGER "TLS");
+
+    /// Serves the current certificate, swapped whenever the files on disk change
+    #[derive(Debug)]
+    pub struct CertResolver {
+        cert_path: PathBuf,
+        key_path: PathBuf,
+        provider: Arc<CryptoProvider>,
+        current: RwLock<Arc<CertifiedKey>>,
+        modified: Mutex<(Option<SystemTime>, Option<SystemTime>)>,
+    }
+
+    fn modified(path: &Path) -> Option<SystemTime> {
+        std::fs::metadata(path).and_then(|m| m.modified()).ok()
+    }
+
+    fn load_key(
+        cert_path: &Path,
+        key_path: &Path,
+        provider: &CryptoProvider,
+    ) -> crate::Result<CertifiedKey> {
+        let certs = CertificateDer::pem_file_iter(cert_path)
+            .with_context(|| format!("Failed to read certificate {cert_path:?}"))?
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("Failed to parse certificate {cert_path:?}"))?;
+        let key = PrivateKeyDer::from_pem_file(key_path)
+            .with_context(|| format!("Failed to read private key {key_path:?}"))?;
+        Ok(CertifiedKey::from_der(certs, key, provider)?)
+    }
+
+    impl CertResolver {
+        pub fn new(
+            cert_path: PathBuf,
+            key_path: PathBuf,
+            provider: Arc<CryptoProvider>,
+        ) -> crate::Result<Self> {
+            let modified_at = (modified(&cert_path), modified(&key_path));
+            let key = load_key(&cert_path, &key_path, &provider)?;
+            Ok(Self {
+                cert_path,
+                key_path,
+                provider,
+                current: RwLock::new(Arc::new(key)),
+                modified: Mutex::new(modified_at),
+            })
+        }
+
+        /// Reload the certificate if either file changed since the last load.
+        /// The previous certificate stays in use if the new one fails to load.
+        pub fn reload_if_changed(&self) -> crate::Result<bool> {
+            let now = (modified(&self.cert_path), modified(&self.key_path));
+            let mut last = self.modified.lock().unwrap();
+            if *last == now {
+                return Ok(false);
+            }
+
+            let key = load_key(&self.cert_path, &self.key_path, &self.provider)?;
+            *self.current.write().unwrap() = Arc::new(key);
+            *last = now;
+            Ok(true)
+        }
+    }
+
+    impl ResolvesServerCert for CertResolver {
+        fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+            Some(self.current.read().unwrap().clone())
+        }
+    }
+
+    /// Accepts TLS connections using certificates that are reloaded on change
+    #[derive(Clone)]
+    pub struct TlsAcceptor {
+        config: Arc<rustls::ServerConfig>,
+        resolver: Arc<CertResolver>,
+    }
+
+    impl TlsAcceptor {
+        pub fn new(root: &Path, config: &TlsConfig) -> crate::Result<Self> {
+            let provider = Arc::new(rustls::crypto::ring::default_provider());
+            let resolver = Arc::new(CertResolver::new(
+                root.join(&config.cert_path),
+                root.join(&config.key_path),
+                provider.clone(),
+            )?);
+
+            let mut server_config = rustls::ServerConfig::builder_with_provider(provider)
+                .with_safe_default_protocol_versions()?
+                .with_no_client_auth()
+                .with_cert_resolver(resolver.clone());
+            server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+            Ok(Self {
+                config: Arc::new(server_config),
+                resolver,
+            })
+        }
+
+        /// Poll the certificate files in the background until the acceptor is dropped
+        pub fn watch(&self, interval: Duration) {
+            let resolver = Arc::downgrade(&self.resolver);
+            std::thread::spawn(move || {
+                loop {
+                    std::thread::sleep(interval);
+                    let Some(resolver) = Weak::upgrade(&resolver) else {
+                        return;
+                    };
+
+                    match resolver.reload_if_changed() {
+                        Ok(true) => LOGGER.info("Reloaded TLS certificate"),
+                        Ok(false) => {}
+                        Err(e) => LOGGER.error(format!("Failed to reload TLS certificate: {e:#}")),
+                    }
+                }
+            });
+        }
+
+        pub fn accept(&self, tcp: TcpStream) -> crate::Result<TlsStream> {
+            let conn = rustls::ServerConnection::new(self.config.clone())?;
+            Ok(TlsStream {
+                tcp,
+                conn: Arc::new(Mutex::new(conn)),
+            })
+        }
+    }
+
+    /// A TLS session over a TCP stream.
+    ///
+    /// Clones share the session, so one thread can block on reads while others write.
+    /// The session is only locked while records are processed, never during a socket read.
+    pub struct TlsStream {
+        tcp: TcpStream,
+        conn: Arc<Mutex<rustls::ServerConnection>>,
+    }
+
+    fn tls_err(e: rustls::Error) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+
+    impl TlsStream {
+        pub fn try_clone(&self) -> io::Result<Self> {
+            Ok(Self {
+                tcp: self.tcp.try_clone()?,
+                conn: self.conn.clone(),
+            })
+        }
+
+        pub fn tcp(&self) -> &TcpStream {
+            &self.tcp
+        }
+
+        pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+            self.tcp.peer_addr()
+        }
+
+        pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+            {
+                let mut conn = self.conn.lock().unwrap();
+                conn.send_close_notify();
+                let mut tcp = &self.tcp;
+                while conn.wants_write() {
+                    if conn.write_tls(&mut tcp).is_err() {
+                        break;
+                    }
+                }
+            }
+            self.tcp.shutdown(how)
+        }
+
+        fn write_pending(&self, conn: &mut rustls::ServerConnection) -> io::Result<()> {
+            let mut tcp = &self.tcp;
+            while conn.wants_write() {
+                conn.write_tls(&mut tcp)?;
+            }
+            Ok(())
+        }
+    }
+
+    impl SecureStream for TlsStream {
+        fn try_clone(&self) -> io::Result<Box<dyn SecureStream>> {
+            Ok(Box::new(TlsStream::try_clone(self)?))
+        }
+
+        fn tcp(&self) -> &TcpStream {
+            &self.tcp
+        }
+
+        fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+            TlsStream::shutdown(self, how)
+        }
+    }
+
+    impl Read for TlsStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut raw = [0u8; 16 * 1024];
+            loop {
+                {
+                    let mut conn = self.conn.lock().unwrap();
+                    match conn.reader().read(buf) {
+                        Ok(n) => return Ok(n),
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                        Err(e) => return Err(e),
+                    }
+                    self.write_pending(&mut conn)?;
+                }
+
+                let n = self.tcp.read(&mut raw)?;
+                if n == 0 {
+                    return Ok(0);
+                }
+
+                let mut conn = self.conn.lock().unwrap();
+                let mut records = &raw[..n];
+                while !records.is_empty() {
+                    conn.read_tls(&mut records)?;
+                    conn.process_new_packets().map_err(tls_err)?;
+                }
+                self.write_pending(&mut conn)?;
+            }
+        }
+    }
+
+    impl Write for TlsStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut conn = self.conn.lock().unwrap();
+            let n = conn.writer().write(buf)?;
+            self.write_pending(&mut conn)?;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            let mut conn = self.conn.lock().unwrap();
+            conn.writer().flush()?;
+            self.write_pending(&mut conn)?;
+            (&self.tcp).flush()
+        }
+    }
+}
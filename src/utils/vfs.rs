@@ -28,7 +28,7 @@ pub fn read(path: &Path, default_content: &str) -> crate::Result<String> {
     Ok(fs::read_to_string(path)?)
 }
 
-pub fn read_bytes<'a>(path: &Path, default_content: Vec<u8>) -> crate::Result<Vec<u8>> {
+pub fn read_bytes(path: &Path, default_content: Vec<u8>) -> crate::Result<Vec<u8>> {
     if !path.exists() {
         LOGGER.info(format!(
             "File {path:?} does not exist, creating it with default contents"
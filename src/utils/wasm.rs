@@ -0,0 +1,700 @@
+//! Sandboxed WebAssembly plugins
+//!
+//! Every `.wasm` file in the `plugins` directory is a WASI preview 1 module, named after
+//! its file. Values cross the boundary as JSON written to the guest's memory, see the README.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WasmConfig {
+    /// Fuel a plugin may burn in a single hook, roughly one unit per instruction
+    pub fuel: u64,
+    /// Maximum size of a plugin's linear memory, in bytes
+    pub max_memory: usize,
+    /// Maximum number of elements in a plugin's function table
+    pub max_table_elements: usize,
+    /// Maximum number of rows a database query returns
+    pub max_rows: usize,
+    /// Time a database query may run before it is interrupted, in milliseconds
+    pub max_query_time: u64,
+}
+
+impl Default for WasmConfig {
+    fn default() -> Self {
+        Self {
+            fuel: 10_000_000,
+            max_memory: 64 * 1024 * 1024,
+            max_table_elements: 10_000,
+            max_rows: 1000,
+            max_query_time: 100,
+        }
+    }
+}
+
+#[cfg(feature = "wasm")]
+pub use imp::*;
+
+#[cfg(feature = "wasm")]
+mod imp {
+    use std::{
+        path::Path,
+        sync::{Arc, Mutex, MutexGuard, PoisonError, Weak},
+        time::{Duration, Instant},
+    };
+
+    use anyhow::Context;
+    use bytes::Bytes;
+    use serde::{Deserialize, Serialize, de::DeserializeOwned};
+    use wasmtime::{
+        AsContextMut, Caller, Config, Engine, Extern, Linker, Memory, Module, Store, StoreLimits,
+        StoreLimitsBuilder, TypedFunc,
+    };
+    use wasmtime_wasi::{
+        OutputStream, Pollable, StdoutStream, StreamResult, WasiCtxBuilder, async_trait,
+        preview1::{self, WasiP1Ctx},
+    };
+
+    use super::WasmConfig;
+    use crate::{
+        Server, logger,
+        types::{
+            data::Message,
+            message::{ClientMessage, ResponseError, ServerMessage, WsMessage},
+        },
+        utils::{
+            client::Client,
+            database::Database,
+            plugin::{Plugin, PluginConfig, RequestAction},
+            storage, vfs,
+        },
+    };
+
+    logger!(LOGGER "WASM");
+
+    /// State the host functions of a plugin work with
+    struct Host {
+        wasi: WasiP1Ctx,
+        limits: StoreLimits,
+        name: String,
+        server: Weak<Server>,
+        /// Connection reading `chat` and the plugin's tables, opened in `init`
+        db: Option<Database>,
+        max_rows: usize,
+        max_query_time: Duration,
+    }
+
+    /// Longest line of a plugin's stdout or stderr, longer ones are split
+    const MAX_LINE: usize = 4096;
+
+    /// The stdout or stderr of a plugin, written to the log line by line
+    #[derive(Clone)]
+    struct GuestOutput {
+        name: String,
+        stderr: bool,
+    }
+
+    struct GuestLines {
+        output: GuestOutput,
+        pending: Vec<u8>,
+    }
+
+    impl StdoutStream for GuestOutput {
+        fn stream(&self) -> Box<dyn OutputStream> {
+            Box::new(GuestLines {
+                output: self.clone(),
+                pending: Vec::new(),
+            })
+        }
+
+        fn isatty(&self) -> bool {
+            false
+        }
+    }
+
+    impl GuestLines {
+        fn log(&self, line: &[u8]) {
+            let text = format!(
+                "[{}] {}",
+                self.output.name,
+                String::from_utf8_lossy(line).trim_end()
+            );
+            if self.output.stderr {
+                LOGGER.warn(text)
+            } else {
+                LOGGER.info(text)
+            }
+        }
+    }
+
+    #[async_trait]
+    impl OutputStream for GuestLines {
+        fn write(&mut self, bytes: Bytes) -> StreamResult<()> {
+            self.pending.extend_from_slice(&bytes);
+            while let Some(end) = self.pending.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = self.pending.drain(..=end).collect();
+                self.log(&line);
+            }
+            while self.pending.len() >= MAX_LINE {
+                let line: Vec<u8> = self.pending.drain(..MAX_LINE).collect();
+                self.log(&line);
+            }
+            Ok(())
+        }
+
+        // An unfinished line waits for the rest of it
+        fn flush(&mut self) -> StreamResult<()> {
+            Ok(())
+        }
+
+        fn check_write(&mut self) -> StreamResult<usize> {
+            Ok(MAX_LINE)
+        }
+    }
+
+    #[async_trait]
+    impl Pollable for GuestLines {
+        async fn ready(&mut self) {}
+    }
+
+    impl Drop for GuestLines {
+        fn drop(&mut self) {
+            if !self.pending.is_empty() {
+                self.log(&self.pending);
+            }
+        }
+    }
+
+    /// A client as seen by a WASM plugin
+    #[derive(Serialize)]
+    struct ClientInfo {
+        id: u64,
+        user: Option<String>,
+    }
+
+    impl From<&Client> for ClientInfo {
+        fn from(client: &Client) -> Self {
+            Self {
+                id: client.id(),
+                user: client.get_uuid().ok(),
+            }
+        }
+    }
+
+    #[derive(Serialize)]
+    #[serde(tag = "event", rename_all = "snake_case")]
+    enum Event<'a> {
+        Connect { client: ClientInfo },
+        Authenticated { client: ClientInfo },
+        Disconnect { client: ClientInfo },
+        MessageCreated { message: &'a Message },
+        MessageEdited { message: &'a Message },
+        MessageDeleted { message: &'a Message },
+        Tick,
+        Shutdown,
+    }
+
+    #[derive(Serialize)]
+    struct Request<'a> {
+        client: ClientInfo,
+        request: &'a ClientMessage,
+    }
+
+    #[derive(Serialize)]
+    struct PluginMessage<'a> {
+        client: ClientInfo,
+        data: &'a serde_json::Map<String, serde_json::Value>,
+    }
+
+    /// Returned by `voxa_on_request`, an empty result continues
+    #[derive(Deserialize)]
+    #[serde(tag = "action", rename_all = "snake_case")]
+    enum Action {
+        Continue,
+        Modify { request: ClientMessage },
+        Handled,
+        Reject { error: ResponseError },
+    }
+
+    /// Sent by `voxa.send` and `voxa.broadcast`
+    #[derive(Deserialize)]
+    struct PluginEvent {
+        event: String,
+        #[serde(default)]
+        data: serde_json::Value,
+    }
+
+    #[derive(Deserialize)]
+    struct Query {
+        sql: String,
+        #[serde(default)]
+        params: Vec<serde_json::Value>,
+    }
+
+    /// Pointer and length of a guest buffer in one value
+    fn pack(ptr: i32, len: usize) -> i64 {
+        ((ptr as u32 as i64) << 32) | len as u32 as i64
+    }
+
+    fn unpack(v: i64) -> (usize, usize) {
+        ((v as u64 >> 32) as usize, v as u32 as usize)
+    }
+
+    fn read_guest(
+        ctx: impl AsContextMut<Data = Host>,
+        memory: Memory,
+        ptr: usize,
+        len: usize,
+    ) -> crate::Result<Vec<u8>> {
+        Ok(memory
+            .data(&ctx)
+            .get(ptr..ptr.saturating_add(len))
+            .context("Buffer outside of the plugin's memory")?
+            .to_vec())
+    }
+
+    /// Copy `data` into a buffer allocated with the guest's `voxa_alloc`
+    fn write_guest(
+        mut ctx: impl AsContextMut<Data = Host>,
+        memory: Memory,
+        alloc: &TypedFunc<i32, i32>,
+        data: &[u8],
+    ) -> crate::Result<i64> {
+        let len = i32::try_from(data.len()).context("Buffer too large for the plugin")?;
+        let ptr = alloc.call(&mut ctx, len)?;
+        memory.write(&mut ctx, ptr as u32 as usize, data)?;
+        Ok(pack(ptr, data.len()))
+    }
+
+    fn caller_exports(
+        caller: &mut Caller<'_, Host>,
+    ) -> crate::Result<(Memory, TypedFunc<i32, i32>)> {
+        let memory = caller
+            .get_export("memory")
+            .and_then(Extern::into_memory)
+            .context("Plugin exports no memory")?;
+        let alloc = caller
+            .get_export("voxa_alloc")
+            .and_then(Extern::into_func)
+            .context("Plugin exports no voxa_alloc")?
+            .typed::<i32, i32>(&caller)?;
+        Ok((memory, alloc))
+    }
+
+    fn read_json<T: DeserializeOwned>(
+        caller: &mut Caller<'_, Host>,
+        ptr: i32,
+        len: i32,
+    ) -> crate::Result<T> {
+        let (memory, _) = caller_exports(caller)?;
+        let bytes = read_guest(
+            &mut *caller,
+            memory,
+            ptr as u32 as usize,
+            len as u32 as usize,
+        )?;
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    fn server(caller: &Caller<'_, Host>) -> crate::Result<Arc<Server>> {
+        caller
+            .data()
+            .server
+            .upgrade()
+            .context("Plugin was not initialized")
+    }
+
+    /// Host functions imported from the `voxa` module
+    fn linker(engine: &Engine) -> crate::Result<Linker<Host>> {
+        let mut linker = Linker::new(engine);
+        preview1::add_to_linker_sync(&mut linker, |h: &mut Host| &mut h.wasi)?;
+
+        // log(level, ptr, len), level 0 is info, 1 warn and anything else error
+        linker.func_wrap(
+            "voxa",
+            "log",
+            |mut caller: Caller<'_, Host>, level: i32, ptr: i32, len: i32| -> crate::Result<()> {
+                let (memory, _) = caller_exports(&mut caller)?;
+                let bytes = read_guest(
+                    &mut caller,
+                    memory,
+                    ptr as u32 as usize,
+                    len as u32 as usize,
+                )?;
+                let text = format!(
+                    "[{}] {}",
+                    caller.data().name,
+                    String::from_utf8_lossy(&bytes)
+                );
+                match level {
+                    0 => LOGGER.info(text),
+                    1 => LOGGER.warn(text),
+                    _ => LOGGER.error(text),
+                }
+                Ok(())
+            },
+        )?;
+
+        // send(client_id, ptr, len) -> 0, or -1 if the client is gone
+        linker.func_wrap(
+            "voxa",
+            "send",
+            |mut caller: Caller<'_, Host>, id: i64, ptr: i32, len: i32| -> crate::Result<i32> {
+                let PluginEvent { event, data } = read_json(&mut caller, ptr, len)?;
+                let server = server(&caller)?;
+                let client = server
+                    .clients
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .find(|c| c.id() == id as u64)
+                    .cloned();
+
+                let sent = client.is_some_and(|c| {
+                    c.send(ServerMessage::Plugin {
+                        plugin: caller.data().name.clone(),
+                        event,
+                        data,
+                    })
+                    .is_ok()
+                });
+                Ok(if sent { 0 } else { -1 })
+            },
+        )?;
+
+        // broadcast(ptr, len)
+        linker.func_wrap(
+            "voxa",
+            "broadcast",
+            |mut caller: Caller<'_, Host>, ptr: i32, len: i32| -> crate::Result<()> {
+                let PluginEvent { event, data } = read_json(&mut caller, ptr, len)?;
+                server(&caller)?.broadcast_plugin_event(&caller.data().name, &event, data)
+            },
+        )?;
+
+        // query(ptr, len) -> packed buffer with `{ rows }` or `{ error }`
+        linker.func_wrap(
+            "voxa",
+            "query",
+            |mut caller: Caller<'_, Host>, ptr: i32, len: i32| -> crate::Result<i64> {
+                let query: Query = read_json(&mut caller, ptr, len)?;
+                let host = caller.data();
+                let res = match &host.db {
+                    Some(db) => {
+                        // Fuel doesn't run out while the host works
+                        db.set_deadline(Some(Instant::now() + host.max_query_time));
+                        let rows = db.query_json(&query.sql, &query.params, host.max_rows);
+                        db.set_deadline(None);
+                        rows.map_err(|e| e.to_string())
+                    }
+                    None => Err("The database is not available".to_string()),
+                };
+
+                let out = match res {
+                    Ok(rows) => serde_json::json!({ "rows": rows }),
+                    Err(e) => serde_json::json!({ "error": e }),
+                };
+                let (memory, alloc) = caller_exports(&mut caller)?;
+                write_guest(&mut caller, memory, &alloc, out.to_string().as_bytes())
+            },
+        )?;
+
+        Ok(linker)
+    }
+
+    /// A plugin running in a WASM sandbox. A trap, including running out of fuel or
+    /// memory, only fails the current hook.
+    pub struct WasmPlugin {
+        name: String,
+        priority: i32,
+        fuel: u64,
+        // Guest calls are serialized, a module runs on one thread at a time
+        store: Mutex<Store<Host>>,
+        memory: Memory,
+        alloc: TypedFunc<i32, i32>,
+        init: Option<TypedFunc<(i32, i32), ()>>,
+        on_request: Option<TypedFunc<(i32, i32), i64>>,
+        on_plugin_message: Option<TypedFunc<(i32, i32), i64>>,
+        on_event: Option<TypedFunc<(i32, i32), ()>>,
+    }
+
+    impl WasmPlugin {
+        pub fn load(engine: &Engine, path: &Path, config: &WasmConfig) -> crate::Result<Self> {
+            let name = path
+                .file_stem()
+                .context("Plugin file has no name")?
+                .to_string_lossy()
+                .into_owned();
+            let module = Module::from_file(engine, path)?;
+
+            let host = Host {
+                wasi: WasiCtxBuilder::new()
+                    .stdout(GuestOutput {
+                        name: name.clone(),
+                        stderr: false,
+                    })
+                    .stderr(GuestOutput {
+                        name: name.clone(),
+                        stderr: true,
+                    })
+                    .build_p1(),
+                limits: StoreLimitsBuilder::new()
+                    .memory_size(config.max_memory)
+                    .table_elements(config.max_table_elements)
+                    .tables(1)
+                    .instances(1)
+                    .build(),
+                name: name.clone(),
+                server: Weak::new(),
+                db: None,
+                max_rows: config.max_rows,
+                max_query_time: Duration::from_millis(config.max_query_time),
+            };
+            let mut store = Store::new(engine, host);
+            store.limiter(|h| &mut h.limits);
+            store.set_fuel(config.fuel)?;
+
+            let instance = linker(engine)?.instantiate(&mut store, &module)?;
+            // WASI reactors set themselves up in `_initialize`
+            if let Ok(initialize) = instance.get_typed_func::<(), ()>(&mut store, "_initialize") {
+                initialize.call(&mut store, ())?;
+            }
+
+            let memory = instance
+                .get_memory(&mut store, "memory")
+                .context("Plugin exports no memory")?;
+            let alloc = instance.get_typed_func(&mut store, "voxa_alloc")?;
+            let priority = match instance.get_typed_func::<(), i32>(&mut store, "voxa_priority") {
+                Ok(f) => f.call(&mut store, ())?,
+                Err(_) => 0,
+            };
+
+            Ok(Self {
+                name,
+                priority,
+                fuel: config.fuel,
+                memory,
+                alloc,
+                init: instance.get_typed_func(&mut store, "voxa_init").ok(),
+                on_request: instance.get_typed_func(&mut store, "voxa_on_request").ok(),
+                on_plugin_message: instance
+                    .get_typed_func(&mut store, "voxa_on_plugin_message")
+                    .ok(),
+                on_event: instance.get_typed_func(&mut store, "voxa_on_event").ok(),
+                store: Mutex::new(store),
+            })
+        }
+
+        fn store(&self) -> MutexGuard<'_, Store<Host>> {
+            self.store.lock().unwrap_or_else(PoisonError::into_inner)
+        }
+
+        /// Call an export with `input` as JSON, refilling the fuel first
+        fn call<R: wasmtime::WasmResults>(
+            &self,
+            store: &mut Store<Host>,
+            f: TypedFunc<(i32, i32), R>,
+            input: &impl Serialize,
+        ) -> crate::Result<R> {
+            let input = serde_json::to_vec(input)?;
+            store.set_fuel(self.fuel)?;
+
+            let packed = write_guest(&mut *store, self.memory, &self.alloc, &input)?;
+            let (ptr, len) = unpack(packed);
+            f.call(store, (ptr as i32, len as i32))
+        }
+
+        /// Call an export returning a JSON buffer, `None` if it is empty
+        fn call_json<T: DeserializeOwned>(
+            &self,
+            f: TypedFunc<(i32, i32), i64>,
+            input: &impl Serialize,
+        ) -> crate::Result<Option<T>> {
+            // The result only stays valid until the next call
+            let mut store = self.store();
+            let (ptr, len) = unpack(self.call(&mut store, f, input)?);
+            if len == 0 {
+                return Ok(None);
+            }
+
+            let bytes = read_guest(&mut *store, self.memory, ptr, len)?;
+            Ok(Some(serde_json::from_slice(&bytes)?))
+        }
+
+        fn event(&self, event: Event) {
+            if let Some(f) = self.on_event.clone()
+                && let Err(e) = self.call(&mut self.store(), f, &event)
+            {
+                LOGGER.error(format!(
+                    "Plugin {} failed to handle an event: {e:#}",
+                    self.name
+                ));
+            }
+        }
+    }
+
+    impl Plugin for WasmPlugin {
+        fn name(&self) -> &str {
+            &self.name
+        }
+
+        fn init(&mut self, server: &Arc<Server>, config: &PluginConfig) {
+            {
+                let host = self.store.get_mut().unwrap().data_mut();
+                host.server = Arc::downgrade(server);
+                host.db = LOGGER.extract(
+                    server.db.read_only(&storage::table_prefix(&self.name)),
+                    format!("Plugin {} can't query the database", self.name),
+                );
+            }
+
+            if let Some(f) = self.init.clone()
+                && let Err(e) = self.call(&mut self.store(), f, config)
+            {
+                LOGGER.error(format!("Plugin {} failed to initialize: {e:#}", self.name));
+            }
+        }
+
+        fn priority(&self) -> i32 {
+            self.priority
+        }
+
+        fn on_request(
+            &self,
+            req: &WsMessage<ClientMessage>,
+            client: &Client,
+            _server: &Arc<Server>,
+        ) -> RequestAction {
+            let (Some(f), WsMessage::Message(request)) = (self.on_request.clone(), req) else {
+                return RequestAction::Continue;
+            };
+
+            let input = Request {
+                client: client.into(),
+                request,
+            };
+            match self.call_json::<Action>(f, &input) {
+                Ok(None | Some(Action::Continue)) => RequestAction::Continue,
+                Ok(Some(Action::Modify { request })) => {
+                    RequestAction::Modify(WsMessage::Message(request))
+                }
+                Ok(Some(Action::Handled)) => RequestAction::Handled,
+                Ok(Some(Action::Reject { error })) => RequestAction::Reject(error),
+                Err(e) => {
+                    LOGGER.error(format!(
+                        "Plugin {} failed to handle a request: {e:#}",
+                        self.name
+                    ));
+                    RequestAction::Continue
+                }
+            }
+        }
+
+        fn on_plugin_message(
+            &self,
+            data: &serde_json::Map<String, serde_json::Value>,
+            client: &Client,
+            _server: &Arc<Server>,
+        ) -> Result<(), ResponseError> {
+            let Some(f) = self.on_plugin_message.clone() else {
+                return Err(ResponseError::InvalidRequest(format!(
+                    "Plugin {} does not accept messages",
+                    self.name
+                )));
+            };
+
+            let input = PluginMessage {
+                client: client.into(),
+                data,
+            };
+            match self.call_json::<ResponseError>(f, &input) {
+                Ok(None) => Ok(()),
+                Ok(Some(e)) => Err(e),
+                Err(e) => {
+                    LOGGER.error(format!(
+                        "Plugin {} failed to handle a message: {e:#}",
+                        self.name
+                    ));
+                    Err(ResponseError::InternalError(format!(
+                        "Plugin {} failed",
+                        self.name
+                    )))
+                }
+            }
+        }
+
+        fn on_connect(&self, client: &Client, _server: &Arc<Server>) {
+            self.event(Event::Connect {
+                client: client.into(),
+            });
+        }
+
+        fn on_authenticated(&self, client: &Client, _server: &Arc<Server>) {
+            self.event(Event::Authenticated {
+                client: client.into(),
+            });
+        }
+
+        fn on_disconnect(&self, client: &Client, _server: &Arc<Server>) {
+            self.event(Event::Disconnect {
+                client: client.into(),
+            });
+        }
+
+        fn on_message_created(&self, message: &Message, _server: &Arc<Server>) {
+            self.event(Event::MessageCreated { message });
+        }
+
+        fn on_message_edited(&self, message: &Message, _server: &Arc<Server>) {
+            self.event(Event::MessageEdited { message });
+        }
+
+        fn on_message_deleted(&self, message: &Message, _server: &Arc<Server>) {
+            self.event(Event::MessageDeleted { message });
+        }
+
+        fn on_tick(&self, _server: &Arc<Server>) {
+            self.event(Event::Tick);
+        }
+
+        fn on_shutdown(&self, _server: &Arc<Server>) {
+            self.event(Event::Shutdown);
+        }
+    }
+
+    impl Server {
+        /// Load every `.wasm` file in the `plugins` directory, they are initialized by `run`
+        pub(crate) fn load_wasm_dir(&self) -> crate::Result<()> {
+            let dir = self.root.join("plugins");
+            vfs::dir(&dir)?;
+
+            let config = &self.config.plugins.wasm;
+            let mut engine_config = Config::new();
+            engine_config.consume_fuel(true);
+            let engine = Engine::new(&engine_config)?;
+
+            for entry in std::fs::read_dir(&dir)? {
+                let path = entry?.path();
+                if !path.is_file() || path.extension().and_then(|s| s.to_str()) != Some("wasm") {
+                    continue;
+                }
+
+                let name = path.file_stem().unwrap_or_default().to_string_lossy();
+                if !self.config.plugins.is_enabled(&name) {
+                    LOGGER.info(format!("Skipping disabled plugin {name}"));
+                    continue;
+                }
+
+                match WasmPlugin::load(&engine, &path, config) {
+                    Ok(plugin) => {
+                        LOGGER.info(format!("Loaded plugin {name}"));
+                        self.plugins_mut().push(Arc::new(Box::new(plugin)));
+                    }
+                    Err(e) => LOGGER.error(format!("Failed to load plugin {path:?}: {e:#}")),
+                }
+            }
+
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,306 @@
+//! Outgoing webhooks, chat events are queued in the database and POSTed to each target
+
+use std::{
+    sync::{Condvar, Mutex, PoisonError},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Server,
+    utils::{database::Database, logger},
+};
+
+crate::logger!(LOGGER "Webhooks");
+
+/// Deliveries sent per pass of the worker
+const BATCH: usize = 100;
+
+/// Longest the worker sleeps before looking for due retries
+const IDLE_POLL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebhooksConfig {
+    pub targets: Vec<WebhookTarget>,
+    /// Attempts before a delivery is dropped
+    pub max_attempts: u32,
+    /// Seconds before the first retry, doubled after every failed attempt
+    pub retry_delay: u64,
+    /// Upper bound of the retry delay in seconds
+    pub max_retry_delay: u64,
+    /// Seconds a request may take
+    pub timeout: u64,
+}
+
+impl Default for WebhooksConfig {
+    fn default() -> Self {
+        Self {
+            targets: Vec::new(),
+            max_attempts: 10,
+            retry_delay: 5,
+            max_retry_delay: 3600,
+            timeout: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookTarget {
+    pub url: String,
+    /// Key of the `X-Voxa-Signature` HMAC-SHA256 of the body
+    pub secret: String,
+    /// Events sent to the target, all of them if empty
+    #[serde(default)]
+    pub events: Vec<WebhookEvent>,
+}
+
+impl WebhookTarget {
+    /// Identifies the target in the queue, targets sharing a url may have different secrets
+    pub fn id(&self) -> String {
+        let key = format!("{}\n{}", self.url, self.secret);
+        ring::digest::digest(&ring::digest::SHA256, key.as_bytes())
+            .as_ref()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    MessageCreate,
+    MessageUpdate,
+    MessageDelete,
+    /// A user or bot authenticated
+    MemberJoin,
+    /// An authenticated connection closed
+    MemberLeave,
+}
+
+impl WebhookEvent {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::MessageCreate => "message_create",
+            Self::MessageUpdate => "message_update",
+            Self::MessageDelete => "message_delete",
+            Self::MemberJoin => "member_join",
+            Self::MemberLeave => "member_leave",
+        }
+    }
+}
+
+/// Body of every delivery
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookPayload<T> {
+    pub event: WebhookEvent,
+    /// Unix timestamp of the event
+    pub timestamp: i64,
+    pub data: T,
+}
+
+/// Data of `member_join` and `member_leave`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MemberEvent {
+    pub user_id: String,
+    pub bot: bool,
+}
+
+/// A delivery waiting in the queue
+#[derive(Debug, Clone)]
+pub struct QueuedWebhook {
+    pub id: i64,
+    /// `WebhookTarget::id` of the target, empty for deliveries queued before target ids
+    pub target: String,
+    pub url: String,
+    pub event: String,
+    pub body: String,
+    pub attempts: u32,
+}
+
+/// Hex encoded HMAC-SHA256 of `body`, sent as `X-Voxa-Signature: sha256=<hex>`
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, secret.as_bytes());
+    ring::hmac::sign(&key, body)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Current time in unix milliseconds, the unit of the queue
+pub fn now_ms() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+pub struct Webhooks {
+    config: WebhooksConfig,
+    agent: ureq::Agent,
+    /// Set when deliveries were queued, wakes the worker
+    queued: Mutex<bool>,
+    wake: Condvar,
+}
+
+impl Webhooks {
+    pub fn new(config: WebhooksConfig) -> Self {
+        let agent = ureq::Agent::config_builder()
+            .timeout_global(Some(Duration::from_secs(config.timeout)))
+            .build()
+            .into();
+
+        Self {
+            config,
+            agent,
+            queued: Mutex::new(false),
+            wake: Condvar::new(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.config.targets.is_empty()
+    }
+
+    /// Queue `data` for every target subscribed to `event`
+    pub fn emit<T: Serialize>(
+        &self,
+        db: &Database,
+        event: WebhookEvent,
+        data: &T,
+        now: i64,
+    ) -> crate::Result<()> {
+        let targets: Vec<&WebhookTarget> = self
+            .config
+            .targets
+            .iter()
+            .filter(|t| t.events.is_empty() || t.events.contains(&event))
+            .collect();
+        if targets.is_empty() {
+            return Ok(());
+        }
+
+        let body = serde_json::to_string(&WebhookPayload {
+            event,
+            timestamp: now / 1000,
+            data,
+        })?;
+        for target in targets {
+            db.enqueue_webhook(&target.id(), &target.url, event.name(), &body, now)?;
+        }
+
+        *self.queued.lock().unwrap_or_else(PoisonError::into_inner) = true;
+        self.wake.notify_all();
+        Ok(())
+    }
+
+    /// Block until deliveries are queued or `timeout` passed
+    pub fn wait(&self, timeout: Duration) {
+        let queued = self.queued.lock().unwrap_or_else(PoisonError::into_inner);
+        let (mut queued, _) = self
+            .wake
+            .wait_timeout_while(queued, timeout, |queued| !*queued)
+            .unwrap_or_else(PoisonError::into_inner);
+        *queued = false;
+    }
+
+    /// Send the deliveries due at `now`, returns how many succeeded
+    pub fn deliver_due(&self, db: &Database, now: i64) -> crate::Result<usize> {
+        let mut delivered = 0;
+        for delivery in db.due_webhooks(now, BATCH)? {
+            // Targets removed from the config are dropped with their deliveries
+            let target = self
+                .config
+                .targets
+                .iter()
+                .find(|t| match delivery.target.as_str() {
+                    "" => t.url == delivery.url,
+                    id => t.id() == id,
+                });
+            let Some(target) = target else {
+                db.delete_webhook(delivery.id)?;
+                continue;
+            };
+
+            match self.send(target, &delivery) {
+                Ok(()) => {
+                    db.delete_webhook(delivery.id)?;
+                    delivered += 1;
+                }
+                Err(e) => {
+                    let attempts = delivery.attempts + 1;
+                    if attempts >= self.config.max_attempts {
+                        LOGGER.error(format!(
+                            "Dropping {} delivery {} to {} after {attempts} attempts: {e}",
+                            delivery.event,
+                            delivery.id,
+                            logger::redact_url(&delivery.url)
+                        ));
+                        db.delete_webhook(delivery.id)?;
+                    } else {
+                        let delay = self.retry_delay(attempts);
+                        LOGGER.warn(format!(
+                            "{} delivery {} to {} failed, retrying in {}s: {e}",
+                            delivery.event,
+                            delivery.id,
+                            logger::redact_url(&delivery.url),
+                            delay.as_secs()
+                        ));
+                        db.retry_webhook(delivery.id, attempts, now + delay.as_millis() as i64)?;
+                    }
+                }
+            }
+        }
+
+        Ok(delivered)
+    }
+
+    /// Delay after the `attempts`th failed attempt
+    pub fn retry_delay(&self, attempts: u32) -> Duration {
+        let factor = 1u64
+            .checked_shl(attempts.saturating_sub(1))
+            .unwrap_or(u64::MAX);
+        Duration::from_secs(
+            self.config
+                .retry_delay
+                .saturating_mul(factor)
+                .min(self.config.max_retry_delay),
+        )
+    }
+
+    fn send(&self, target: &WebhookTarget, delivery: &QueuedWebhook) -> crate::Result<()> {
+        self.agent
+            .post(&target.url)
+            .header("Content-Type", "application/json")
+            .header("User-Agent", "voxa-server")
+            .header("X-Voxa-Event", &delivery.event)
+            .header("X-Voxa-Delivery", delivery.id.to_string())
+            .header(
+                "X-Voxa-Signature",
+                format!("sha256={}", sign(&target.secret, delivery.body.as_bytes())),
+            )
+            .send(&delivery.body)?;
+        Ok(())
+    }
+}
+
+impl Server {
+    /// Queue a webhook delivery of `event`, errors are only logged
+    pub(crate) fn emit_webhook<T: Serialize>(&self, event: WebhookEvent, data: &T) {
+        LOGGER.extract(
+            self.webhooks.emit(&self.db, event, data, now_ms()),
+            format!("Failed to queue a {} webhook", event.name()),
+        );
+    }
+
+    /// Deliver queued webhooks on the current thread until the server stops
+    pub(crate) fn run_webhooks(&self) {
+        while !self.is_stopping() {
+            LOGGER.extract(
+                self.webhooks.deliver_due(&self.db, now_ms()),
+                "Failed to deliver webhooks",
+            );
+            self.webhooks.wait(IDLE_POLL);
+        }
+    }
+}
@@ -1,5 +1,11 @@
 use std::sync::Arc;
-use voxa_server::{Server, export_plugin, logger, utils::plugin::Plugin};
+use voxa_server::{
+    Server, export_plugin, logger,
+    utils::{
+        commands::Command,
+        plugin::{Plugin, PluginConfig, RequestAction},
+    },
+};
 
 logger! {
     const LOGGER "My Plugin"
@@ -9,40 +15,30 @@ logger! {
 pub struct MyPlugin;
 
 impl Plugin for MyPlugin {
-    fn init(&mut self, _server: &Arc<Server>) {
+    fn name(&self) -> &str {
+        "test-plugin"
+    }
+
+    fn init(&mut self, server: &Arc<Server>, _config: &PluginConfig) {
+        let ping = Command::new("ping", "Answer with pong");
+        LOGGER.extract(
+            server.handle(self.name()).register_command(ping, |_| {
+                LOGGER.info("Pong!");
+                Ok("pong".to_string())
+            }),
+            "Failed to register /ping",
+        );
         LOGGER.info("MyPlugin initialized!");
     }
 
     fn on_request(
-        &mut self,
+        &self,
         msg: &voxa_server::types::message::WsMessage<voxa_server::types::message::ClientMessage>,
-        client: &voxa_server::utils::client::Client,
+        _client: &voxa_server::utils::client::Client,
         _server: &Arc<Server>,
-    ) -> bool {
-        LOGGER.info(&format!("Received message: {:?}", msg));
-        match msg {
-            voxa_server::types::message::WsMessage::Message(
-                voxa_server::types::message::ClientMessage::SendMessage { contents, .. },
-            ) => {
-                if contents == "ping" {
-                    LOGGER.info("Pong!");
-                    // client
-                    //     .send(voxa_server::types::message::ServerMessage::TempMessage {
-                    //         message: "pong".to_string(),
-                    //     })
-                    //     .unwrap();
-                    client
-                        .send(voxa_server::types::message::ServerMessage::TempMessage {
-                            message: "pong".to_string(),
-                        })
-                        .unwrap();
-                    true
-                } else {
-                    false
-                }
-            }
-            _ => false,
-        }
+    ) -> RequestAction {
+        LOGGER.payload("Received message", format!("{msg:?}"));
+        RequestAction::Continue
     }
 }
 
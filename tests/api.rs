@@ -0,0 +1,138 @@
+//! The read-only REST API under `/api`.
+
+mod common;
+
+use common::{TestServer, http};
+use serde_json::Value;
+use voxa_server::{
+    ServerConfig,
+    types::data::{Channel, ChannelKind},
+    utils::ratelimit::BucketConfig,
+};
+
+fn config() -> ServerConfig {
+    ServerConfig {
+        channels: vec![Channel {
+            id: "general".to_string(),
+            name: "General".to_string(),
+            kind: ChannelKind::Text,
+        }],
+        ..Default::default()
+    }
+}
+
+fn get(port: u16, path: &str, token: Option<&str>) -> (u16, Value) {
+    let auth = token.map(|t| format!("Bearer {t}"));
+    let headers: Vec<_> = auth.iter().map(|a| ("Authorization", a.as_str())).collect();
+    let (status, body) = http(port, "GET", path, &headers, "");
+    (status, serde_json::from_str(&body).unwrap())
+}
+
+/// The contents of the messages of a response
+fn contents(messages: &Value) -> Vec<&str> {
+    messages
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|m| m["contents"].as_str().unwrap())
+        .collect()
+}
+
+#[test]
+fn requires_a_valid_token() {
+    let test = TestServer::new("api-auth", config()).start();
+
+    let (status, body) = get(test.port, "/api/server", None);
+    assert_eq!(status, 401);
+    assert_eq!(body["error"], "Missing bearer token");
+
+    let (status, _) = get(test.port, "/api/server", Some("voxa_bot_nope"));
+    assert_eq!(status, 401);
+}
+
+#[test]
+fn serves_the_server_details() {
+    let test = TestServer::new("api-server", config());
+    let (_, token) = test.server.create_bot("reader", Vec::new()).unwrap();
+    let test = test.start();
+
+    let (status, details) = get(test.port, "/api/server", Some(&token));
+    assert_eq!(status, 200, "{details}");
+    assert_eq!(details["channels"][0]["id"], "general");
+}
+
+#[test]
+fn rate_limits_before_authenticating() {
+    let mut config = config();
+    config.rate_limit.per_ip = BucketConfig {
+        capacity: 3.0,
+        refill_per_sec: 0.01,
+    };
+    let test = TestServer::new("api-limited", config).start();
+
+    for _ in 0..3 {
+        let (status, _) = get(test.port, "/api/server", Some("voxa_bot_nope"));
+        assert_eq!(status, 401);
+    }
+    let (status, _) = get(test.port, "/api/server", Some("voxa_bot_nope"));
+    assert_eq!(status, 429);
+}
+
+#[test]
+fn pages_through_messages() {
+    let test = TestServer::new("api-messages", config());
+    let (bot, token) = test.server.create_bot("reader", Vec::new()).unwrap();
+    let ids: Vec<_> = (0..5)
+        .map(|i| {
+            test.server
+                .db
+                .insert_message("general", &bot.id, &format!("m{i}"), i, true)
+                .unwrap()
+                .id
+        })
+        .collect();
+    let test = test.start();
+    let token = Some(token.as_str());
+
+    let (status, all) = get(test.port, "/api/channels/general/messages", token);
+    assert_eq!(status, 200, "{all}");
+    assert_eq!(contents(&all), ["m0", "m1", "m2", "m3", "m4"]);
+
+    // The newest ones, oldest first
+    let (_, last) = get(test.port, "/api/channels/general/messages?limit=2", token);
+    assert_eq!(contents(&last), ["m3", "m4"]);
+
+    let path = format!("/api/channels/general/messages?before={}&limit=2", ids[3]);
+    let (_, page) = get(test.port, &path, token);
+    assert_eq!(contents(&page), ["m1", "m2"]);
+
+    for query in ["limit=0", "limit=101", "limit=ten", "before=-1"] {
+        let path = format!("/api/channels/general/messages?{query}");
+        assert_eq!(get(test.port, &path, token).0, 400, "{query}");
+    }
+}
+
+#[test]
+fn unknown_channels_are_not_found() {
+    let test = TestServer::new("api-unknown", config());
+    let (_, token) = test.server.create_bot("reader", Vec::new()).unwrap();
+    let test = test.start();
+
+    let (status, body) = get(test.port, "/api/channels/missing/messages", Some(&token));
+    assert_eq!(status, 404);
+    assert_eq!(body["error"], "Channel missing not found");
+}
+
+#[test]
+fn reports_health_without_a_token() {
+    let test = TestServer::new("api-health", config()).start();
+
+    let (status, body) = get(test.port, "/api/health", None);
+    assert_eq!(status, 200);
+    assert_eq!(body["status"], "ok");
+
+    std::fs::write(test.root.path().join("main.db"), vec![0xff; 4096]).unwrap();
+    let (status, body) = get(test.port, "/api/health", None);
+    assert_eq!(status, 503);
+    assert_eq!(body["status"], "degraded");
+}
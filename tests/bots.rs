@@ -0,0 +1,145 @@
+//! Bot accounts authenticating with local tokens.
+
+mod common;
+
+use common::{TestClient, TestServer};
+use serde_json::{Value, json};
+use voxa_server::{
+    ServerConfig,
+    types::data::{Channel, ChannelKind, OnlineUser, Permission},
+    utils::database::Database,
+};
+
+fn config() -> ServerConfig {
+    ServerConfig {
+        channels: vec![Channel {
+            id: "general".to_string(),
+            name: "General".to_string(),
+            kind: ChannelKind::Text,
+        }],
+        ..Default::default()
+    }
+}
+
+fn send_message(c: &mut TestClient, contents: &str) {
+    c.send_json(&json!({
+        "type": "send_message",
+        "params": { "channel_id": "general", "contents": contents },
+    }));
+}
+
+/// The next presence update about `user_id`
+fn presence_of(c: &mut TestClient, user_id: &str) -> Value {
+    loop {
+        let msg = c.recv_type("presence_update");
+        if msg["params"]["user_id"] == user_id {
+            return msg["params"].clone();
+        }
+    }
+}
+
+#[test]
+fn bots_authenticate_locally_and_only_their_hash_is_stored() {
+    let test = TestServer::new("bots-auth", config());
+    let (bot, token) = test.server.create_bot("helper", Vec::new()).unwrap();
+    assert!(token.starts_with("voxa_bot_"));
+    let test = test.start();
+
+    // The server has no key, any call to the cloud would fail
+    let (_c, uuid) = TestClient::login(test.port, &token);
+    assert_eq!(uuid, bot.id);
+
+    let db = Database::open(test.root.path().join("main.db")).unwrap();
+    let rows = db.query_json("SELECT * FROM bots", &[], 10).unwrap();
+    assert_eq!(rows.len(), 1);
+    let hash: String = ring::digest::digest(&ring::digest::SHA256, token.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    assert_eq!(rows[0]["token_hash"], hash);
+    assert!(rows[0].values().all(|v| v != &json!(token)));
+
+    let file = std::fs::read(test.root.path().join("main.db")).unwrap();
+    assert!(!file.windows(token.len()).any(|w| w == token.as_bytes()));
+}
+
+#[test]
+fn unknown_bot_tokens_are_rejected() {
+    let test = TestServer::new("bots-unknown", config()).start();
+    let mut c = TestClient::connect(test.port);
+    c.recv_json();
+    c.send_json(
+        &json!({ "version": "0.0.1", "auth_token": "voxa_bot_nope", "last_message": null }),
+    );
+    assert!(c.recv_json().get("error").is_some());
+    assert!(c.closed());
+}
+
+#[test]
+fn bots_only_get_their_permissions() {
+    let test = TestServer::new("bots-permissions", config());
+    let (_, muted) = test.server.create_bot("muted", Vec::new()).unwrap();
+    let (_, talker) = test
+        .server
+        .create_bot("talker", vec![Permission::SendMessages])
+        .unwrap();
+    let test = test.start();
+
+    // Not even the permissions every user gets by default
+    let (mut c, _) = TestClient::login(test.port, &muted);
+    send_message(&mut c, "hello");
+    assert_eq!(c.recv_response()["error"], "unauthorized");
+
+    let (mut c, uuid) = TestClient::login(test.port, &talker);
+    send_message(&mut c, "hello");
+    let created = c.recv_type("message_create");
+    assert_eq!(created["params"]["from"], uuid);
+    assert_eq!(created["params"]["bot"], true);
+
+    c.send_json(&json!({ "type": "list_plugins" }));
+    assert_eq!(c.recv_response()["error"], "unauthorized");
+}
+
+#[test]
+fn presence_and_online_users_flag_bots() {
+    let test = TestServer::new("bots-presence", config());
+    let (_, watcher) = test.server.create_bot("watcher", Vec::new()).unwrap();
+    let (bot, token) = test.server.create_bot("visitor", Vec::new()).unwrap();
+    let test = test.start();
+
+    let (mut w, _) = TestClient::login(test.port, &watcher);
+    let (_v, _) = TestClient::login(test.port, &token);
+    let presence = presence_of(&mut w, &bot.id);
+    assert_eq!(presence["status"], "online");
+    assert_eq!(presence["bot"], true);
+
+    let online = test.server.handle("test").online_users();
+    assert!(online.contains(&OnlineUser {
+        id: bot.id.clone(),
+        bot: true
+    }));
+}
+
+#[test]
+fn deleting_a_bot_disconnects_it() {
+    let test = TestServer::new("bots-delete", config());
+    let (_, watcher) = test.server.create_bot("watcher", Vec::new()).unwrap();
+    let (bot, token) = test.server.create_bot("doomed", Vec::new()).unwrap();
+    let test = test.start();
+
+    let (mut w, _) = TestClient::login(test.port, &watcher);
+    let (mut c, _) = TestClient::login(test.port, &token);
+    assert_eq!(presence_of(&mut w, &bot.id)["status"], "online");
+
+    test.server.delete_bot("doomed").unwrap();
+    assert_eq!(c.recv_close().close_code(), 1008);
+    assert!(c.closed());
+    assert_eq!(presence_of(&mut w, &bot.id)["status"], "offline");
+
+    // The token is gone with the account
+    let mut c = TestClient::connect(test.port);
+    c.recv_json();
+    c.send_json(&json!({ "version": "0.0.1", "auth_token": token, "last_message": null }));
+    assert!(c.recv_json().get("error").is_some());
+}
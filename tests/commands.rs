@@ -0,0 +1,135 @@
+//! Splitting and parsing the arguments of chat commands.
+
+use serde_json::{Value, json};
+use voxa_server::{
+    types::data::{ArgKind, Channel, ChannelKind, CommandArg, CommandInfo},
+    utils::commands::{next_word, parse_args, split_name},
+};
+
+/// Every word of `input`
+fn words(mut input: &str) -> Vec<String> {
+    std::iter::from_fn(|| next_word(&mut input)).collect()
+}
+
+fn command(args: &[(&str, ArgKind, bool)]) -> CommandInfo {
+    CommandInfo {
+        name: "test".to_string(),
+        description: String::new(),
+        args: args
+            .iter()
+            .map(|&(name, kind, required)| CommandArg {
+                name: name.to_string(),
+                description: String::new(),
+                kind,
+                required,
+            })
+            .collect(),
+        permission: None,
+    }
+}
+
+fn channels() -> Vec<Channel> {
+    vec![Channel {
+        id: "general".to_string(),
+        name: "General".to_string(),
+        kind: ChannelKind::Text,
+    }]
+}
+
+fn parse(args: &[(&str, ArgKind, bool)], input: &str) -> Result<Value, String> {
+    parse_args(&channels(), &command(args), input).map(Value::Object)
+}
+
+#[test]
+fn splits_words_on_whitespace() {
+    assert_eq!(words("  one two\tthree\n"), ["one", "two", "three"]);
+    assert!(words("   ").is_empty());
+}
+
+#[test]
+fn quoted_words_keep_their_spaces() {
+    assert_eq!(
+        words(r#""hello world" next "" end"#),
+        ["hello world", "next", "", "end"]
+    );
+    // Quotes only group at the start of a word
+    assert_eq!(words(r#"it's a"b c""#), ["it's", "a\"b", "c\""]);
+}
+
+#[test]
+fn escapes_in_quoted_words() {
+    assert_eq!(
+        words(r#""say \"hi\"" "back\\slash""#),
+        [r#"say "hi""#, r"back\slash"]
+    );
+    // A trailing backslash is dropped
+    assert_eq!(words(r#""end\"#), ["end"]);
+}
+
+#[test]
+fn unterminated_quotes_take_the_rest() {
+    assert_eq!(words(r#"a "b c  d"#), ["a", "b c  d"]);
+
+    let mut input = r#""open"#;
+    assert_eq!(next_word(&mut input).as_deref(), Some("open"));
+    assert_eq!(input, "");
+}
+
+#[test]
+fn command_names_start_right_after_the_slash() {
+    assert_eq!(split_name("roll 2d6"), ("roll", "2d6"));
+    assert_eq!(split_name("help"), ("help", ""));
+    assert_eq!(split_name(" foo"), ("", "foo"));
+    assert_eq!(split_name(""), ("", ""));
+}
+
+#[test]
+fn parses_typed_arguments() {
+    let args = [
+        ("who", ArgKind::User, true),
+        ("count", ArgKind::Integer, true),
+        ("ratio", ArgKind::Number, true),
+        ("loud", ArgKind::Boolean, true),
+        ("where", ArgKind::Channel, true),
+    ];
+    assert_eq!(
+        parse(&args, "@alice 3 0.5 yes #general").unwrap(),
+        json!({ "who": "alice", "count": 3, "ratio": 0.5, "loud": true, "where": "general" })
+    );
+
+    assert!(parse(&args, "alice three 0.5 yes general").is_err());
+    assert!(parse(&args, "alice 3 NaN yes general").is_err());
+    assert!(parse(&args, "alice 3 0.5 maybe general").is_err());
+    assert!(parse(&args, "alice 3 0.5 yes #random").is_err());
+}
+
+#[test]
+fn checks_the_number_of_arguments() {
+    let args = [
+        ("name", ArgKind::String, true),
+        ("title", ArgKind::String, false),
+    ];
+    assert_eq!(
+        parse(&args, r#"bob "the builder""#).unwrap(),
+        json!({ "name": "bob", "title": "the builder" })
+    );
+    assert_eq!(parse(&args, "bob").unwrap(), json!({ "name": "bob" }));
+    assert_eq!(parse(&args, "").unwrap_err(), "Missing argument name");
+    assert_eq!(parse(&args, "a b c").unwrap_err(), "Too many arguments");
+}
+
+#[test]
+fn rest_arguments_take_the_remaining_input() {
+    let args = [
+        ("who", ArgKind::User, true),
+        ("message", ArgKind::Rest, true),
+    ];
+    assert_eq!(
+        parse(&args, r#"bob  hi "there"  you "#).unwrap(),
+        json!({ "who": "bob", "message": r#"hi "there"  you"# })
+    );
+    assert_eq!(
+        parse(&args, "bob   ").unwrap_err(),
+        "Missing argument message"
+    );
+}
@@ -0,0 +1,318 @@
+//! Helpers shared by the integration tests: a server in a temp root and a raw WebSocket client.
+#![allow(dead_code)]
+
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use serde_json::{Value, json};
+use voxa_server::{Server, ServerConfig};
+
+/// A directory in the temp directory, removed on drop
+pub struct TempRoot(PathBuf);
+
+impl TempRoot {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("voxa-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempRoot {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A port nothing listens on
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// A server running on its own thread, shut down on drop
+pub struct TestServer {
+    pub server: Arc<Server>,
+    pub port: u16,
+    pub root: TempRoot,
+    run: Option<JoinHandle<voxa_server::Result<()>>>,
+}
+
+impl TestServer {
+    /// Build a server in a fresh root named after `name`
+    pub fn new(name: &str, mut config: ServerConfig) -> Self {
+        let root = TempRoot::new(name);
+        let port = free_port();
+        config.port = port;
+        config.logging.file = false;
+        Self {
+            server: config.build(root.path()),
+            port,
+            root,
+            run: None,
+        }
+    }
+
+    /// Run the server, returns once it accepts connections
+    pub fn start(mut self) -> Self {
+        let server = self.server.clone();
+        self.run = Some(std::thread::spawn(move || server.run()));
+
+        let start = Instant::now();
+        while TcpStream::connect(("127.0.0.1", self.port)).is_err() {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "server never started"
+            );
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        self
+    }
+
+    /// Request a shutdown unless one already was, and wait for `run` to return
+    pub fn stop(&mut self) -> voxa_server::Result<()> {
+        if !self.server.is_stopping() {
+            self.server.shutdown(None);
+        }
+        match self.run.take() {
+            Some(run) => run.join().unwrap(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+pub struct TestClient {
+    pub stream: TcpStream,
+    pub response: String,
+}
+
+pub struct Frame {
+    pub first: u8,
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn close_code(&self) -> u16 {
+        assert_eq!(self.opcode, 0x8, "expected a close frame");
+        u16::from_be_bytes([self.payload[0], self.payload[1]])
+    }
+}
+
+impl TestClient {
+    pub fn connect(port: u16) -> Self {
+        Self::connect_with(port, "")
+    }
+
+    pub fn connect_with(port: u16, extra_headers: &str) -> Self {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        write!(
+            stream,
+            "GET / HTTP/1.1\r\n\
+             Host: localhost\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+             Sec-WebSocket-Version: 13\r\n\
+             {extra_headers}\r\n"
+        )
+        .unwrap();
+
+        // Read byte by byte, frames may directly follow the response
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            let mut byte = [0u8; 1];
+            stream.read_exact(&mut byte).unwrap();
+            response.push(byte[0]);
+        }
+        let response = String::from_utf8(response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 101"), "{response}");
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+        Self { stream, response }
+    }
+
+    pub fn send_raw(&mut self, first: u8, payload: &[u8], masked: bool) {
+        let mut frame = vec![first];
+        let mask_bit = if masked { 0x80 } else { 0 };
+        let len = payload.len();
+        if len < 126 {
+            frame.push(mask_bit | len as u8);
+        } else if len <= 65535 {
+            frame.push(mask_bit | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        } else {
+            frame.push(mask_bit | 127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+
+        if masked {
+            let mask = [0x12, 0x34, 0x56, 0x78];
+            frame.extend_from_slice(&mask);
+            frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        } else {
+            frame.extend_from_slice(payload);
+        }
+
+        // The peer may already have failed the connection
+        let _ = self.stream.write_all(&frame);
+    }
+
+    pub fn send(&mut self, first: u8, payload: &[u8]) {
+        self.send_raw(first, payload, true);
+    }
+
+    pub fn recv(&mut self) -> Frame {
+        let mut header = [0u8; 2];
+        self.stream.read_exact(&mut header).unwrap();
+        assert_eq!(header[1] & 0x80, 0, "server frames must not be masked");
+
+        let mut len = (header[1] & 0x7F) as u64;
+        if len == 126 {
+            let mut ext = [0u8; 2];
+            self.stream.read_exact(&mut ext).unwrap();
+            len = u16::from_be_bytes(ext) as u64;
+        } else if len == 127 {
+            let mut ext = [0u8; 8];
+            self.stream.read_exact(&mut ext).unwrap();
+            len = u64::from_be_bytes(ext);
+        }
+
+        let mut payload = vec![0u8; len as usize];
+        self.stream.read_exact(&mut payload).unwrap();
+        Frame {
+            first: header[0],
+            opcode: header[0] & 0x0F,
+            payload,
+        }
+    }
+
+    /// Whether the server closed the TCP connection
+    pub fn closed(&mut self) -> bool {
+        let mut buf = [0u8; 1];
+        matches!(self.stream.read(&mut buf), Ok(0) | Err(_))
+    }
+
+    pub fn expect_close(&mut self, code: u16) {
+        assert_eq!(self.recv().close_code(), code);
+        assert!(self.closed());
+    }
+}
+
+// The Voxa protocol on top of raw frames
+impl TestClient {
+    pub fn send_json(&mut self, value: &Value) {
+        self.send(0x81, value.to_string().as_bytes());
+    }
+
+    /// The next text message, skipping pings
+    pub fn recv_json(&mut self) -> Value {
+        loop {
+            let frame = self.recv();
+            match frame.opcode {
+                0x1 => return serde_json::from_slice(&frame.payload).unwrap(),
+                0x9 | 0xA => {}
+                _ => panic!("expected a text frame, got opcode {}", frame.opcode),
+            }
+        }
+    }
+
+    /// The next message of type `kind`, skipping the others
+    pub fn recv_type(&mut self, kind: &str) -> Value {
+        loop {
+            let msg = self.recv_json();
+            if msg["type"] == kind {
+                return msg;
+            }
+        }
+    }
+
+    /// The next close frame, skipping everything before it
+    pub fn recv_close(&mut self) -> Frame {
+        loop {
+            let frame = self.recv();
+            if frame.opcode == 0x8 {
+                return frame;
+            }
+        }
+    }
+
+    /// The next message other than a presence update, which arrive at any time
+    pub fn recv_response(&mut self) -> Value {
+        loop {
+            let msg = self.recv_json();
+            if msg["type"] != "presence_update" {
+                return msg;
+            }
+        }
+    }
+
+    /// Connect and authenticate with `token`, returns the client and its user id
+    pub fn login(port: u16, token: &str) -> (Self, String) {
+        let mut c = Self::connect(port);
+        // Server details
+        c.recv_json();
+        c.send_json(&json!({ "version": "0.0.1", "auth_token": token, "last_message": null }));
+        let authenticated = c.recv_json();
+        assert_eq!(authenticated["type"], "authenticated", "{authenticated}");
+        let uuid = authenticated["params"]["uuid"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        (c, uuid)
+    }
+}
+
+/// Send a plain HTTP request, returns the status and body of the response
+pub fn http(
+    port: u16,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: &str,
+) -> (u16, String) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut request = format!(
+        "{method} {path} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n",
+        body.len()
+    );
+    for (name, value) in headers {
+        request.push_str(&format!("{name}: {value}\r\n"));
+    }
+    request.push_str("\r\n");
+    request.push_str(body);
+    stream.write_all(request.as_bytes()).unwrap();
+
+    // The connection is closed after the response
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+    (status, body.to_string())
+}
@@ -0,0 +1,387 @@
+//! RFC 6455 conformance cases in the spirit of the Autobahn test suite,
+//! run against an echo server built on `Client`.
+
+mod common;
+
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    time::Duration,
+};
+
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+
+use common::TestClient;
+use voxa_server::{
+    ServerConfig,
+    types::message::WsMessage,
+    utils::client::{Client, CloseState},
+};
+
+/// Start a server echoing every message back, returns its port
+fn echo_server(config: ServerConfig) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = stream.unwrap();
+            let Ok(client) = Client::new(stream, &config) else {
+                continue;
+            };
+
+            std::thread::spawn(move || {
+                while let Ok(Some(msg)) = client.read_t::<serde_json::Value>() {
+                    let res = match msg {
+                        WsMessage::Message(v) => client.send(v),
+                        WsMessage::String(s) => client.send_text(&s),
+                        WsMessage::Binary(b) => client.send_binary(&b),
+                    };
+                    if res.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+
+    port
+}
+
+#[test]
+fn echoes_text_and_binary_of_all_lengths() {
+    let port = echo_server(ServerConfig::default());
+    let mut c = TestClient::connect(port);
+
+    for len in [0, 1, 125, 126, 127, 65535, 65536] {
+        let text = "*".repeat(len);
+        c.send(0x81, text.as_bytes());
+        let frame = c.recv();
+        assert_eq!(frame.opcode, 0x1);
+        assert_eq!(frame.payload, text.as_bytes());
+
+        let bin = vec![0xFE; len];
+        c.send(0x82, &bin);
+        let frame = c.recv();
+        assert_eq!(frame.opcode, 0x2);
+        assert_eq!(frame.payload, bin);
+    }
+}
+
+#[test]
+fn reassembles_fragments_with_interleaved_ping() {
+    let port = echo_server(ServerConfig::default());
+    let mut c = TestClient::connect(port);
+
+    c.send(0x01, b"frag");
+    c.send(0x89, b"ping payload");
+    c.send(0x00, b"men");
+    c.send(0x80, b"ted");
+
+    let pong = c.recv();
+    assert_eq!(pong.opcode, 0xA);
+    assert_eq!(pong.payload, b"ping payload");
+
+    let frame = c.recv();
+    assert_eq!(frame.opcode, 0x1);
+    assert_eq!(frame.payload, b"fragmented");
+}
+
+#[test]
+fn pong_echoes_ping_payload() {
+    let port = echo_server(ServerConfig::default());
+    let mut c = TestClient::connect(port);
+
+    let payload = [7u8; 125];
+    c.send(0x89, &payload);
+    let pong = c.recv();
+    assert_eq!(pong.opcode, 0xA);
+    assert_eq!(pong.payload, payload);
+}
+
+#[test]
+fn unsolicited_pong_is_ignored() {
+    let port = echo_server(ServerConfig::default());
+    let mut c = TestClient::connect(port);
+
+    c.send(0x8A, b"unsolicited");
+    c.send(0x81, b"still open");
+    assert_eq!(c.recv().payload, b"still open");
+}
+
+type Case<'a> = (&'a str, &'a dyn Fn(&mut TestClient));
+
+#[test]
+fn protocol_errors_fail_with_1002() {
+    let cases: [Case; 7] = [
+        ("unmasked frame", &|c| c.send_raw(0x81, b"hi", false)),
+        ("oversized control frame", &|c| c.send(0x89, &[0; 126])),
+        ("fragmented control frame", &|c| c.send(0x09, b"")),
+        ("reserved bits", &|c| c.send(0xB1, b"hi")),
+        ("unknown opcode", &|c| c.send(0x83, b"hi")),
+        ("continuation without start", &|c| c.send(0x80, b"hi")),
+        ("new message inside fragments", &|c| {
+            c.send(0x01, b"a");
+            c.send(0x81, b"b");
+        }),
+    ];
+
+    let port = echo_server(ServerConfig::default());
+    for (name, case) in cases {
+        let mut c = TestClient::connect(port);
+        case(&mut c);
+        assert_eq!(c.recv().close_code(), 1002, "{name}");
+        assert!(c.closed(), "{name}");
+    }
+}
+
+#[test]
+fn invalid_utf8_fails_with_1007() {
+    let port = echo_server(ServerConfig::default());
+
+    let mut c = TestClient::connect(port);
+    c.send(
+        0x81,
+        &[
+            0xCE, 0xBA, 0xE1, 0xBD, 0xB9, 0xCF, 0x83, 0xCE, 0xBC, 0xCE, 0xB5, 0xED, 0xA0, 0x80,
+        ],
+    );
+    c.expect_close(1007);
+
+    // Invalid sequences split across fragments
+    let mut c = TestClient::connect(port);
+    c.send(0x01, &[0xF4]);
+    c.send(0x80, &[0x90, 0x80, 0x80]);
+    c.expect_close(1007);
+}
+
+#[test]
+fn size_limits_fail_with_1009() {
+    let mut config = ServerConfig::default();
+    config.limits.max_frame_size = 1024;
+    config.limits.max_message_size = 2048;
+    let port = echo_server(config);
+
+    let mut c = TestClient::connect(port);
+    c.send(0x81, &[b'a'; 1025]);
+    c.expect_close(1009);
+
+    let mut c = TestClient::connect(port);
+    c.send(0x01, &[b'a'; 1024]);
+    c.send(0x00, &[b'a'; 1024]);
+    c.send(0x80, &[b'a'; 1]);
+    c.expect_close(1009);
+
+    // A length header alone must not cause an allocation
+    let mut c = TestClient::connect(port);
+    let _ = c.stream.write_all(&[
+        0x82, 0xFF, 0x7F, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x01, 0x02, 0x03, 0x04,
+    ]);
+    c.expect_close(1009);
+}
+
+#[test]
+fn client_initiated_close_is_echoed() {
+    let port = echo_server(ServerConfig::default());
+
+    let mut c = TestClient::connect(port);
+    let mut payload = 1000u16.to_be_bytes().to_vec();
+    payload.extend_from_slice(b"bye");
+    c.send(0x88, &payload);
+    let frame = c.recv();
+    assert_eq!(frame.close_code(), 1000);
+    assert_eq!(&frame.payload[2..], b"bye");
+    assert!(c.closed());
+
+    // An empty close frame is answered with 1000
+    let mut c = TestClient::connect(port);
+    c.send(0x88, &[]);
+    c.expect_close(1000);
+}
+
+#[test]
+fn invalid_close_frames_fail() {
+    let port = echo_server(ServerConfig::default());
+
+    for code in [0u16, 999, 1004, 1005, 1006, 1015, 2000, 5000] {
+        let mut c = TestClient::connect(port);
+        c.send(0x88, &code.to_be_bytes());
+        c.expect_close(1002);
+    }
+
+    let mut c = TestClient::connect(port);
+    c.send(0x88, &[0x03]);
+    c.expect_close(1002);
+
+    let mut c = TestClient::connect(port);
+    c.send(0x88, &[0x03, 0xE8, 0xFF]);
+    c.expect_close(1007);
+}
+
+#[test]
+fn server_initiated_close_handshake() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let server = std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let client = Client::new(stream, &ServerConfig::default()).unwrap();
+        client.send_close(1001, "going away").unwrap();
+        assert_eq!(client.close_state(), CloseState::Closing);
+        assert!(client.send_text("too late").is_err());
+
+        // Data sent by the peer before it saw our close frame is discarded
+        assert!(client.read_t::<serde_json::Value>().unwrap().is_none());
+        client.close_state()
+    });
+
+    let mut c = TestClient::connect(port);
+    assert_eq!(c.recv().close_code(), 1001);
+    c.send(0x81, b"in flight");
+    c.send(0x88, &1001u16.to_be_bytes());
+    assert!(c.closed());
+
+    assert_eq!(server.join().unwrap(), CloseState::Closed);
+}
+
+#[test]
+fn heartbeat_pings_and_drops_dead_peers() {
+    let mut config = ServerConfig::default();
+    config.heartbeat.interval = 1;
+    config.heartbeat.timeout = 2;
+    let port = echo_server(config);
+
+    let mut c = TestClient::connect(port);
+    let ping = c.recv();
+    assert_eq!(ping.opcode, 0x9);
+
+    // Never answering the pings gets the connection dropped
+    let start = std::time::Instant::now();
+    while !c.closed() {
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
+
+#[test]
+fn waits_for_frames_stalled_past_the_heartbeat_interval() {
+    let mut config = ServerConfig::default();
+    config.heartbeat.interval = 1;
+    config.heartbeat.timeout = 4;
+    let port = echo_server(config);
+
+    let mut c = TestClient::connect(port);
+    let mask = [0x12, 0x34, 0x56, 0x78];
+    let mut frame = vec![0x81, 0x80 | 5];
+    frame.extend_from_slice(&mask);
+    frame.extend(b"hello".iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+
+    // Header, mask and part of the payload, then a pause longer than the interval
+    c.stream.write_all(&frame[..8]).unwrap();
+    std::thread::sleep(Duration::from_millis(1500));
+    c.stream.write_all(&frame[8..]).unwrap();
+
+    let echo = c.recv();
+    assert_eq!(echo.opcode, 0x1);
+    assert_eq!(echo.payload, b"hello");
+}
+
+#[test]
+fn drops_peers_stalled_mid_frame() {
+    let mut config = ServerConfig::default();
+    config.heartbeat.interval = 1;
+    config.heartbeat.timeout = 2;
+    let port = echo_server(config);
+
+    let mut c = TestClient::connect(port);
+    c.stream.write_all(&[0x81, 0x85, 0x12]).unwrap();
+
+    // No ping or error frame, the connection is just dropped
+    let start = std::time::Instant::now();
+    assert!(c.closed());
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn permessage_deflate_round_trip() {
+    let port = echo_server(ServerConfig::default());
+    let mut c = TestClient::connect_with(
+        port,
+        "Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits\r\n",
+    );
+    assert!(
+        c.response
+            .contains("Sec-WebSocket-Extensions: permessage-deflate")
+    );
+
+    let text = "voxa ".repeat(1000);
+    let mut compress = Compress::new(Compression::default(), false);
+    let mut compressed = Vec::with_capacity(text.len());
+    compress
+        .compress_vec(text.as_bytes(), &mut compressed, FlushCompress::Sync)
+        .unwrap();
+    compressed.truncate(compressed.len() - 4);
+
+    // FIN=1, RSV1=1, opcode=0x1
+    c.send(0xC1, &compressed);
+    let frame = c.recv();
+    assert_eq!(frame.first & 0x40, 0x40, "large messages are compressed");
+
+    let mut data = frame.payload;
+    data.extend_from_slice(&[0x00, 0x00, 0xFF, 0xFF]);
+    let mut out = Vec::with_capacity(text.len() + 64);
+    Decompress::new(false)
+        .decompress_vec(&data, &mut out, FlushDecompress::Sync)
+        .unwrap();
+    assert_eq!(out, text.as_bytes());
+
+    // Small messages stay uncompressed
+    c.send(0x81, b"small");
+    let frame = c.recv();
+    assert_eq!(frame.first & 0x40, 0);
+    assert_eq!(frame.payload, b"small");
+}
+
+#[test]
+fn compressed_frames_without_negotiation_fail() {
+    let port = echo_server(ServerConfig::default());
+    let mut c = TestClient::connect(port);
+    c.send(0xC1, b"not negotiated");
+    c.expect_close(1002);
+}
+
+/// Send a raw handshake request, returns the status line of the response
+fn handshake_status(port: u16, request: &[u8]) -> String {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    // The server may answer before it read everything
+    let _ = stream.write_all(request);
+
+    let mut response = String::new();
+    let _ = stream.read_to_string(&mut response);
+    response.lines().next().unwrap_or_default().to_string()
+}
+
+#[test]
+fn oversized_request_headers_fail_with_431() {
+    let port = echo_server(ServerConfig::default());
+
+    let long = format!(
+        "GET / HTTP/1.1\r\nX-Filler: {}\r\n\r\n",
+        "a".repeat(64 * 1024)
+    );
+    assert!(handshake_status(port, long.as_bytes()).starts_with("HTTP/1.1 431"));
+
+    let long_line = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(64 * 1024));
+    assert!(handshake_status(port, long_line.as_bytes()).starts_with("HTTP/1.1 431"));
+
+    let many = format!("GET / HTTP/1.1\r\n{}\r\n", "X-A: b\r\n".repeat(200));
+    assert!(handshake_status(port, many.as_bytes()).starts_with("HTTP/1.1 431"));
+}
+
+#[test]
+fn malformed_request_lines_fail_with_400() {
+    let port = echo_server(ServerConfig::default());
+    assert!(handshake_status(port, b"GARBAGE\r\n\r\n").starts_with("HTTP/1.1 400"));
+}
@@ -0,0 +1,44 @@
+//! The shared database connection under concurrent use.
+
+use std::{collections::HashSet, sync::Arc};
+
+use voxa_server::utils::database::Database;
+
+#[test]
+fn concurrent_inserts_get_their_own_ids() {
+    let path = std::env::temp_dir().join(format!("voxa-database-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let db = Arc::new(Database::open(&path).unwrap());
+
+    let threads: Vec<_> = (0..8)
+        .map(|t| {
+            let db = db.clone();
+            std::thread::spawn(move || {
+                (0..50)
+                    .map(|i| {
+                        let contents = format!("{t}-{i}");
+                        let msg = db
+                            .insert_message("general", "alice", &contents, 0, false)
+                            .unwrap();
+                        (msg.id, contents)
+                    })
+                    .collect::<Vec<_>>()
+            })
+        })
+        .collect();
+
+    let mut ids = HashSet::new();
+    for thread in threads {
+        for (id, contents) in thread.join().unwrap() {
+            assert!(ids.insert(id));
+            assert_eq!(
+                db.get_message_by_id(id as usize).unwrap().unwrap().contents,
+                contents
+            );
+        }
+    }
+    assert_eq!(ids.len(), 400);
+
+    drop(db);
+    let _ = std::fs::remove_file(&path);
+}
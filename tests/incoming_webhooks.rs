@@ -0,0 +1,124 @@
+//! Messages posted through `POST /webhooks/<id>/<token>`.
+
+mod common;
+
+use common::{TestClient, TestServer, http};
+use serde_json::Value;
+use voxa_server::{
+    ServerConfig,
+    http::webhook::IncomingWebhook,
+    types::data::{Channel, ChannelKind},
+    utils::ratelimit::BucketConfig,
+};
+
+const JSON: &[(&str, &str)] = &[("Content-Type", "application/json")];
+
+fn hook(id: &str, channel_id: &str) -> IncomingWebhook {
+    IncomingWebhook {
+        id: id.to_string(),
+        token: "s3cret".to_string(),
+        channel_id: channel_id.to_string(),
+        name: format!("{id}-bot"),
+    }
+}
+
+fn config() -> ServerConfig {
+    ServerConfig {
+        channels: vec![Channel {
+            id: "general".to_string(),
+            name: "General".to_string(),
+            kind: ChannelKind::Text,
+        }],
+        incoming_webhooks: vec![hook("deploys", "general"), hook("lost", "missing")],
+        ..Default::default()
+    }
+}
+
+fn post(port: u16, path: &str, headers: &[(&str, &str)], body: &str) -> (u16, Value) {
+    let (status, body) = http(port, "POST", path, headers, body);
+    (status, serde_json::from_str(&body).unwrap())
+}
+
+#[test]
+fn posts_and_broadcasts_messages() {
+    let test = TestServer::new("incoming", config());
+    let (_, token) = test.server.create_bot("reader", Vec::new()).unwrap();
+    let test = test.start();
+    let (mut c, _) = TestClient::login(test.port, &token);
+
+    let (status, msg) = post(
+        test.port,
+        "/webhooks/deploys/s3cret",
+        JSON,
+        r#"{ "contents": "deployed" }"#,
+    );
+    assert_eq!(status, 200, "{msg}");
+    assert_eq!(msg["from"], "webhook:deploys-bot");
+    assert_eq!(msg["bot"], true);
+
+    let created = c.recv_type("message_create");
+    assert_eq!(created["params"], msg);
+}
+
+#[test]
+fn unknown_ids_and_wrong_tokens_look_the_same() {
+    let test = TestServer::new("incoming-auth", config()).start();
+    let body = r#"{ "contents": "hi" }"#;
+
+    let wrong_token = post(test.port, "/webhooks/deploys/guess", JSON, body);
+    let unknown_id = post(test.port, "/webhooks/nope/s3cret", JSON, body);
+    assert_eq!(wrong_token.0, 404);
+    assert_eq!(wrong_token, unknown_id);
+}
+
+#[test]
+fn rejects_invalid_requests() {
+    let test = TestServer::new("incoming-invalid", config()).start();
+    let body = r#"{ "contents": "hi" }"#;
+
+    let text = [("Content-Type", "text/plain")];
+    assert_eq!(
+        post(test.port, "/webhooks/deploys/s3cret", &text, body).0,
+        415
+    );
+    assert_eq!(
+        post(test.port, "/webhooks/deploys/s3cret", JSON, "{ oops").0,
+        400
+    );
+    assert_eq!(
+        post(
+            test.port,
+            "/webhooks/deploys/s3cret",
+            JSON,
+            r#"{ "contents": "" }"#
+        )
+        .0,
+        400
+    );
+
+    // A webhook of a channel that doesn't exist can't post
+    assert_eq!(post(test.port, "/webhooks/lost/s3cret", JSON, body).0, 400);
+
+    let (status, _) = http(test.port, "GET", "/webhooks/deploys/s3cret", &[], "");
+    assert_eq!(status, 405);
+}
+
+#[test]
+fn webhooks_are_rate_limited() {
+    let mut config = config();
+    config.rate_limit.per_user = BucketConfig {
+        capacity: 2.0,
+        refill_per_sec: 0.01,
+    };
+    let test = TestServer::new("incoming-limited", config).start();
+    let body = r#"{ "contents": "spam" }"#;
+
+    for _ in 0..2 {
+        assert_eq!(
+            post(test.port, "/webhooks/deploys/s3cret", JSON, body).0,
+            200
+        );
+    }
+    let (status, _) = post(test.port, "/webhooks/deploys/s3cret", JSON, body);
+    assert_eq!(status, 429);
+}
@@ -0,0 +1,85 @@
+//! Checks of native plugin descriptors before any plugin code runs.
+#![cfg(feature = "loader")]
+
+mod common;
+
+use std::ffi::c_void;
+
+use common::TempRoot;
+use voxa_server::utils::{
+    loader::{load_plugin, verify},
+    native::{DESCRIPTOR_VERSION, PluginDescriptor, PluginManifest, create},
+    plugin::PluginsConfig,
+};
+
+extern "C" fn never_created() -> *mut c_void {
+    unreachable!("a descriptor is verified without creating its plugin")
+}
+
+fn descriptor() -> PluginDescriptor {
+    PluginDescriptor::new(PluginManifest::new("probe\0", "1.2.3\0"), never_created)
+}
+
+fn rejection(desc: &PluginDescriptor) -> String {
+    format!("{:#}", unsafe { verify(desc) }.unwrap_err())
+}
+
+#[test]
+fn accepts_a_descriptor_built_against_this_server() {
+    let (name, version) = unsafe { verify(&descriptor()) }.unwrap();
+    assert_eq!((name.as_str(), version.as_str()), ("probe", "1.2.3"));
+}
+
+#[test]
+fn rejects_mismatched_descriptors() {
+    let mut desc = descriptor();
+    desc.abi_version = DESCRIPTOR_VERSION + 1;
+    assert!(rejection(&desc).contains("descriptor version"));
+
+    let mut desc = descriptor();
+    desc.manifest.api_version += 1;
+    assert!(rejection(&desc).contains("requires plugin API"));
+
+    let mut desc = descriptor();
+    desc.manifest.crate_version = c"0.0.0".as_ptr();
+    assert!(rejection(&desc).contains("built against voxa-server 0.0.0"));
+
+    let mut desc = descriptor();
+    desc.manifest.rustc_version = c"rustc 0.0.0".as_ptr();
+    assert!(rejection(&desc).contains("built with rustc 0.0.0"));
+
+    let mut desc = descriptor();
+    desc.manifest.features = c"tls".as_ptr();
+    assert!(rejection(&desc).contains("features [tls]"));
+
+    let mut desc = descriptor();
+    desc.manifest.name = std::ptr::null();
+    assert!(rejection(&desc).contains("missing its name"));
+}
+
+#[test]
+fn catches_panics_while_creating() {
+    extern "C" fn panics() -> *mut c_void {
+        create(|| panic!("no plugin today"))
+    }
+
+    let desc = PluginDescriptor::new(PluginManifest::new("probe\0", "1.2.3\0"), panics);
+    assert!((desc.vtable.create)().is_null());
+
+    let panic = (desc.vtable.take_panic)();
+    let msg = unsafe { std::slice::from_raw_parts(panic.ptr, panic.len) }.to_vec();
+    unsafe { (desc.vtable.free)(panic) };
+    assert_eq!(msg, b"no plugin today");
+    assert!((desc.vtable.take_panic)().ptr.is_null());
+}
+
+#[test]
+fn rejects_files_that_are_not_plugins() {
+    let root = TempRoot::new("loader");
+    let path = root
+        .path()
+        .join(format!("fake.{}", std::env::consts::DLL_EXTENSION));
+    std::fs::write(&path, "not a library").unwrap();
+
+    assert!(load_plugin(&path, &PluginsConfig::default()).is_err());
+}
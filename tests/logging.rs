@@ -0,0 +1,188 @@
+//! Filtering, formatting and rotation of the log pipeline, and the `log`/`tracing` bridges.
+
+mod common;
+
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+use common::TempRoot;
+use serde_json::{Value, json};
+use voxa_server::utils::logger::{Level, LogFormat, LoggingConfig, Pipeline, Record, install};
+
+fn config() -> LoggingConfig {
+    LoggingConfig {
+        format: LogFormat::Json,
+        color: false,
+        ..Default::default()
+    }
+}
+
+fn record<'a>(level: Level, logger: &'a str, message: &'a str) -> Record<'a> {
+    Record {
+        level,
+        logger,
+        message,
+        fields: &[],
+    }
+}
+
+/// The JSON lines of a log file
+fn lines(path: &Path) -> Vec<Value> {
+    std::fs::read_to_string(path)
+        .unwrap()
+        .lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect()
+}
+
+fn messages(path: &Path) -> Vec<String> {
+    lines(path)
+        .iter()
+        .map(|l| l["message"].as_str().unwrap().to_string())
+        .collect()
+}
+
+#[test]
+fn filters_by_level_and_target() {
+    let root = TempRoot::new("log-levels");
+    let pipeline = Pipeline::new(
+        LoggingConfig {
+            level: Level::Warn,
+            levels: HashMap::from([
+                ("Auth".to_string(), Level::Debug),
+                ("rustls".to_string(), Level::Error),
+            ]),
+            ..config()
+        },
+        Some(root.path()),
+    )
+    .unwrap();
+
+    assert_eq!(pipeline.level("Server"), Level::Warn);
+    assert_eq!(pipeline.level("Auth"), Level::Debug);
+    // Targets fall back to their closest parent module
+    assert_eq!(pipeline.level("rustls::client::hs"), Level::Error);
+
+    pipeline.write(&record(Level::Info, "Server", "server info"));
+    pipeline.write(&record(Level::Warn, "Server", "server warning"));
+    pipeline.write(&record(Level::Debug, "Auth", "auth debug"));
+    pipeline.write(&record(Level::Trace, "Auth", "auth trace"));
+    pipeline.write(&record(Level::Warn, "rustls::conn", "rustls warning"));
+    pipeline.write(&record(Level::Error, "rustls::conn", "rustls error"));
+
+    assert_eq!(
+        messages(&root.path().join("logs/voxa.log")),
+        ["server warning", "auth debug", "rustls error"]
+    );
+}
+
+#[test]
+fn writes_one_json_object_per_line() {
+    let root = TempRoot::new("log-json");
+    let pipeline = Pipeline::new(config(), Some(root.path())).unwrap();
+
+    pipeline.write(&record(Level::Info, "Server", "plain"));
+    pipeline.write(&Record {
+        level: Level::Error,
+        logger: "hyper::proto",
+        message: "with fields",
+        fields: &[("peer".to_string(), "1.2.3.4".to_string())],
+    });
+
+    let lines = lines(&root.path().join("logs/voxa.log"));
+    assert_eq!(lines.len(), 2);
+
+    let timestamp = lines[0]["timestamp"].as_str().unwrap();
+    assert!(chrono::DateTime::parse_from_rfc3339(timestamp).is_ok());
+    assert!(timestamp.ends_with('Z'), "{timestamp}");
+    let mut plain = lines[0].clone();
+    plain.as_object_mut().unwrap().remove("timestamp");
+    assert_eq!(
+        plain,
+        json!({ "level": "info", "logger": "Server", "message": "plain" })
+    );
+
+    assert_eq!(lines[1]["level"], "error");
+    assert_eq!(lines[1]["fields"], json!({ "peer": "1.2.3.4" }));
+}
+
+#[test]
+fn rotates_by_size_keeping_the_newest_files() {
+    let root = TempRoot::new("log-rotation");
+    let pipeline = Pipeline::new(
+        LoggingConfig {
+            // Every line fills a file
+            max_file_size: 50,
+            max_files: 2,
+            ..config()
+        },
+        Some(root.path()),
+    )
+    .unwrap();
+
+    for i in 0..5 {
+        pipeline.write(&record(Level::Info, "Server", &format!("line {i}")));
+    }
+
+    let logs = root.path().join("logs");
+    assert_eq!(messages(&logs.join("voxa.log")), ["line 4"]);
+    assert_eq!(messages(&logs.join("voxa.log.1")), ["line 3"]);
+    assert_eq!(messages(&logs.join("voxa.log.2")), ["line 2"]);
+    assert!(!logs.join("voxa.log.3").exists());
+}
+
+#[test]
+fn appends_until_the_size_is_reached() {
+    let root = TempRoot::new("log-append");
+    let pipeline = Pipeline::new(config(), Some(root.path())).unwrap();
+
+    for i in 0..3 {
+        pipeline.write(&record(Level::Info, "Server", &format!("line {i}")));
+    }
+
+    let logs = root.path().join("logs");
+    assert_eq!(
+        messages(&logs.join("voxa.log")),
+        ["line 0", "line 1", "line 2"]
+    );
+    assert!(!logs.join("voxa.log.1").exists());
+}
+
+// The only test installing a pipeline, the facades keep the first logger of the process
+#[test]
+fn forwards_the_log_and_tracing_facades() {
+    let root = TempRoot::new("log-bridges");
+    let pipeline = Pipeline::new(
+        LoggingConfig {
+            levels: HashMap::from([("bridge::quiet".to_string(), Level::Error)]),
+            ..config()
+        },
+        Some(root.path()),
+    )
+    .unwrap();
+    install(Arc::new(pipeline));
+
+    voxa_server::log::info!(target: "bridge::log", "from log {}", 1);
+    voxa_server::log::warn!(target: "bridge::quiet::inner", "filtered");
+    voxa_server::log::debug!(target: "bridge::log", "below the level");
+    tracing::info!(target: "bridge::tracing", user = "alice", "from tracing");
+    tracing::warn!(target: "bridge::quiet", "filtered");
+
+    let lines = lines(&root.path().join("logs/voxa.log"));
+    let bridged: Vec<_> = lines
+        .iter()
+        .map(|l| {
+            (
+                l["logger"].as_str().unwrap(),
+                l["message"].as_str().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        bridged,
+        [
+            ("bridge::log", "from log 1"),
+            ("bridge::tracing", "from tracing")
+        ]
+    );
+    assert_eq!(lines[1]["fields"], json!({ "user": "alice" }));
+}
@@ -0,0 +1,48 @@
+//! Labels of the exported metrics.
+
+use voxa_server::{
+    types::data::{Channel, ChannelKind},
+    utils::metrics::{Metrics, MetricsConfig},
+};
+
+fn channel(id: &str) -> Channel {
+    Channel {
+        id: id.to_string(),
+        name: id.to_string(),
+        kind: ChannelKind::Text,
+    }
+}
+
+/// The `voxa_messages_total` samples of `metrics`
+fn message_counts(metrics: &Metrics) -> Vec<String> {
+    metrics
+        .render(0)
+        .lines()
+        .filter(|l| l.starts_with("voxa_messages_total{"))
+        .map(str::to_string)
+        .collect()
+}
+
+#[test]
+fn messages_are_labelled_with_configured_channels_only() {
+    let metrics = Metrics::new(&[channel("general"), channel("random")]);
+    metrics.message_posted("general");
+    metrics.message_posted("general");
+    for i in 0..100 {
+        metrics.message_posted(&format!("made-up-{i}"));
+    }
+
+    assert_eq!(
+        message_counts(&metrics),
+        [
+            r#"voxa_messages_total{channel="general"} 2"#,
+            r#"voxa_messages_total{channel="other"} 100"#,
+            r#"voxa_messages_total{channel="random"} 0"#,
+        ]
+    );
+}
+
+#[test]
+fn metrics_are_disabled_by_default() {
+    assert!(!MetricsConfig::default().enabled);
+}
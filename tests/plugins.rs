@@ -0,0 +1,177 @@
+//! Isolation of in-process plugins from each other and from the server.
+
+mod common;
+
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use common::{TestClient, TestServer};
+use serde_json::json;
+use voxa_server::{
+    Server, ServerConfig,
+    types::{
+        data::{Channel, ChannelKind, Message, Permission},
+        message::{ClientMessage, WsMessage},
+    },
+    utils::{
+        client::Client,
+        plugin::{Plugin, PluginConfig, RequestAction},
+    },
+};
+
+fn config() -> ServerConfig {
+    ServerConfig {
+        channels: vec![Channel {
+            id: "general".to_string(),
+            name: "General".to_string(),
+            kind: ChannelKind::Text,
+        }],
+        ..Default::default()
+    }
+}
+
+/// Panics on every request
+struct Panicky;
+
+impl Plugin for Panicky {
+    fn name(&self) -> &str {
+        "panicky"
+    }
+
+    fn init(&mut self, _server: &Arc<Server>, _config: &PluginConfig) {}
+
+    fn on_request(
+        &self,
+        _req: &WsMessage<ClientMessage>,
+        _client: &Client,
+        _server: &Arc<Server>,
+    ) -> RequestAction {
+        panic!("can't handle requests")
+    }
+}
+
+fn loaded(server: &Server) -> Vec<String> {
+    server.plugin_list().into_iter().map(|p| p.name).collect()
+}
+
+#[test]
+fn panicking_plugins_are_skipped_then_unloaded() {
+    let mut config = config();
+    config.plugins.max_panics = 3;
+    let test = TestServer::new("plugins-panics", config);
+    test.server.add_plugin(Box::new(Panicky));
+    let (_, token) = test
+        .server
+        .create_bot("sender", vec![Permission::SendMessages])
+        .unwrap();
+    let test = test.start();
+    let (mut c, _) = TestClient::login(test.port, &token);
+
+    for i in 1..=4 {
+        c.send_json(&json!({
+            "type": "send_message",
+            "params": { "channel_id": "general", "contents": format!("message {i}") },
+        }));
+        // The request goes through whether the plugin panicked or not
+        let created = c.recv_type("message_create");
+        assert_eq!(created["params"]["contents"], format!("message {i}"));
+
+        if i < 3 {
+            assert_eq!(test.server.plugin_panics("panicky"), i);
+            assert_eq!(loaded(&test.server), ["panicky"]);
+        } else {
+            assert!(loaded(&test.server).is_empty());
+            assert_eq!(test.server.plugin_panics("panicky"), 0);
+        }
+    }
+}
+
+/// Takes a while in `on_message_created`, recording what overlaps with `on_shutdown`
+#[derive(Default)]
+struct Slow {
+    running: AtomicUsize,
+    calls: AtomicUsize,
+    shut_down: AtomicBool,
+    /// Hooks still running when `on_shutdown` was called
+    running_at_shutdown: AtomicUsize,
+    called_after_shutdown: AtomicBool,
+}
+
+struct SlowPlugin(Arc<Slow>);
+
+impl Plugin for SlowPlugin {
+    fn name(&self) -> &str {
+        "slow"
+    }
+
+    fn init(&mut self, _server: &Arc<Server>, _config: &PluginConfig) {}
+
+    fn on_message_created(&self, _message: &Message, _server: &Arc<Server>) {
+        let s = &self.0;
+        if s.shut_down.load(Ordering::SeqCst) {
+            s.called_after_shutdown.store(true, Ordering::SeqCst);
+        }
+        s.running.fetch_add(1, Ordering::SeqCst);
+        std::thread::sleep(Duration::from_millis(20));
+        s.calls.fetch_add(1, Ordering::SeqCst);
+        s.running.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    fn on_shutdown(&self, _server: &Arc<Server>) {
+        let s = &self.0;
+        s.running_at_shutdown
+            .store(s.running.load(Ordering::SeqCst), Ordering::SeqCst);
+        s.shut_down.store(true, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn unloading_waits_for_hooks_running_on_other_threads() {
+    let slow = Arc::new(Slow::default());
+    let test = TestServer::new("plugins-unload", config());
+    test.server.add_plugin(Box::new(SlowPlugin(slow.clone())));
+    let test = test.start();
+
+    let posters: Vec<_> = (0..8)
+        .map(|t| {
+            let handle = test.server.handle("poster");
+            std::thread::spawn(move || {
+                for i in 0..10 {
+                    handle
+                        .post_message("general", "bot", &format!("{t}/{i}"))
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+
+    // Unload while every thread is in the middle of its hooks
+    std::thread::sleep(Duration::from_millis(50));
+    test.server.unload_plugin("slow").unwrap();
+    assert!(loaded(&test.server).is_empty());
+    for poster in posters {
+        poster.join().unwrap();
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !slow.shut_down.load(Ordering::SeqCst) {
+        assert!(Instant::now() < deadline, "on_shutdown was never called");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert!(slow.calls.load(Ordering::SeqCst) > 0);
+    assert_eq!(slow.running_at_shutdown.load(Ordering::SeqCst), 0);
+    assert!(!slow.called_after_shutdown.load(Ordering::SeqCst));
+    assert_eq!(
+        test.server
+            .db
+            .get_channel_messages("general", None, 100)
+            .unwrap()
+            .len(),
+        80
+    );
+}
@@ -0,0 +1,180 @@
+//! Token bucket rate limiting, driven with explicit timestamps.
+
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    time::{Duration, Instant},
+};
+
+use voxa_server::utils::ratelimit::{BucketConfig, RateLimit, RateLimitConfig, RateLimiter};
+
+const IP: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+/// A limiter with a user bucket of 3 tokens refilled at 1/s and a roomy IP bucket
+fn limiter(max_violations: u32) -> RateLimiter {
+    RateLimiter::new(RateLimitConfig {
+        enabled: true,
+        per_user: BucketConfig {
+            capacity: 3.0,
+            refill_per_sec: 1.0,
+        },
+        per_ip: BucketConfig {
+            capacity: 100.0,
+            refill_per_sec: 100.0,
+        },
+        max_violations,
+    })
+}
+
+fn secs(s: f64) -> Duration {
+    Duration::from_secs_f64(s)
+}
+
+#[test]
+fn allows_a_burst_up_to_capacity() {
+    let limiter = limiter(10);
+    let t = Instant::now();
+
+    for _ in 0..3 {
+        assert_eq!(
+            limiter.check_at(1, Some("alice"), Some(IP), t),
+            RateLimit::Allowed
+        );
+    }
+    assert_eq!(
+        limiter.check_at(1, Some("alice"), Some(IP), t),
+        RateLimit::Limited {
+            retry_after: secs(1.0)
+        }
+    );
+
+    // Other users have their own bucket
+    assert_eq!(
+        limiter.check_at(2, Some("bob"), Some(IP), t),
+        RateLimit::Allowed
+    );
+}
+
+#[test]
+fn refills_over_time_up_to_capacity() {
+    let limiter = limiter(10);
+    let t = Instant::now();
+    for _ in 0..3 {
+        limiter.check_at(1, Some("alice"), None, t);
+    }
+
+    // Half a token is not enough
+    assert_eq!(
+        limiter.check_at(1, Some("alice"), None, t + secs(0.5)),
+        RateLimit::Limited {
+            retry_after: secs(0.5)
+        }
+    );
+    assert_eq!(
+        limiter.check_at(1, Some("alice"), None, t + secs(1.0)),
+        RateLimit::Allowed
+    );
+
+    // A long pause only restores the capacity
+    let later = t + secs(60.0);
+    for _ in 0..3 {
+        assert_eq!(
+            limiter.check_at(1, Some("alice"), None, later),
+            RateLimit::Allowed
+        );
+    }
+    assert!(matches!(
+        limiter.check_at(1, Some("alice"), None, later),
+        RateLimit::Limited { .. }
+    ));
+}
+
+#[test]
+fn shares_the_ip_bucket_between_connections() {
+    let limiter = RateLimiter::new(RateLimitConfig {
+        per_ip: BucketConfig {
+            capacity: 2.0,
+            refill_per_sec: 1.0,
+        },
+        ..RateLimitConfig::default()
+    });
+    let t = Instant::now();
+
+    assert_eq!(limiter.check_at(1, None, Some(IP), t), RateLimit::Allowed);
+    assert_eq!(limiter.check_at(2, None, Some(IP), t), RateLimit::Allowed);
+    assert!(matches!(
+        limiter.check_at(3, None, Some(IP), t),
+        RateLimit::Limited { .. }
+    ));
+}
+
+#[test]
+fn disconnects_after_max_violations() {
+    let limiter = limiter(2);
+    let t = Instant::now();
+    for _ in 0..3 {
+        limiter.check_at(1, Some("alice"), None, t);
+    }
+
+    assert!(matches!(
+        limiter.check_at(1, Some("alice"), None, t),
+        RateLimit::Limited { .. }
+    ));
+    assert!(matches!(
+        limiter.check_at(1, Some("alice"), None, t),
+        RateLimit::Limited { .. }
+    ));
+    assert_eq!(
+        limiter.check_at(1, Some("alice"), None, t),
+        RateLimit::Exceeded
+    );
+    assert_eq!(limiter.tracked_connections(), 0);
+}
+
+#[test]
+fn allowed_requests_reset_violations() {
+    let limiter = limiter(2);
+    let t = Instant::now();
+    for _ in 0..3 {
+        limiter.check_at(1, Some("alice"), None, t);
+    }
+
+    for _ in 0..2 {
+        limiter.check_at(1, Some("alice"), None, t);
+    }
+    assert_eq!(
+        limiter.check_at(1, Some("alice"), None, t + secs(1.0)),
+        RateLimit::Allowed
+    );
+    assert!(matches!(
+        limiter.check_at(1, Some("alice"), None, t + secs(1.0)),
+        RateLimit::Limited { .. }
+    ));
+}
+
+#[test]
+fn forgets_violations_of_closed_connections() {
+    let limiter = limiter(10);
+    let t = Instant::now();
+    for conn in 1..=4 {
+        limiter.check_at(conn, Some("alice"), None, t);
+    }
+    assert_eq!(limiter.tracked_connections(), 1);
+
+    limiter.disconnected(4);
+    assert_eq!(limiter.tracked_connections(), 0);
+}
+
+#[test]
+fn disabled_allows_everything() {
+    let limiter = RateLimiter::new(RateLimitConfig {
+        enabled: false,
+        ..RateLimitConfig::default()
+    });
+    let t = Instant::now();
+    for _ in 0..1000 {
+        assert_eq!(
+            limiter.check_at(1, Some("alice"), Some(IP), t),
+            RateLimit::Allowed
+        );
+    }
+}
@@ -0,0 +1,29 @@
+//! Tasks scheduled by plugins.
+
+mod common;
+
+use std::{sync::mpsc, time::Duration};
+
+use common::TestServer;
+use voxa_server::ServerConfig;
+
+#[test]
+fn huge_delays_never_run_and_dont_stall_the_others() {
+    let test = TestServer::new("scheduler", ServerConfig::default()).start();
+    let handle = test.server.handle("tasks");
+
+    let (tx, rx) = mpsc::channel();
+    let never = handle.schedule(Duration::MAX, {
+        let tx = tx.clone();
+        move |_| tx.send("never").unwrap()
+    });
+    let every = handle.schedule_every(Duration::MAX, {
+        let tx = tx.clone();
+        move |_| tx.send("every").unwrap()
+    });
+    handle.schedule(Duration::from_millis(10), move |_| tx.send("soon").unwrap());
+
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok("soon"));
+    assert!(handle.cancel(never));
+    assert!(handle.cancel(every));
+}
@@ -0,0 +1,109 @@
+//! Graceful shutdown of a running server.
+
+mod common;
+
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use common::{TestClient, TestServer};
+use serde_json::json;
+use voxa_server::{
+    Server, ServerConfig,
+    types::{
+        data::{Channel, ChannelKind, Permission},
+        message::ServerMessage,
+    },
+    utils::{
+        database::Database,
+        plugin::{Plugin, PluginConfig},
+    },
+};
+
+/// Counts its `on_shutdown` calls
+struct Recorder(Arc<AtomicUsize>);
+
+impl Plugin for Recorder {
+    fn name(&self) -> &str {
+        "recorder"
+    }
+
+    fn init(&mut self, _server: &Arc<Server>, _config: &PluginConfig) {}
+
+    fn on_shutdown(&self, _server: &Arc<Server>) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+fn config() -> ServerConfig {
+    ServerConfig {
+        channels: vec![Channel {
+            id: "general".to_string(),
+            name: "General".to_string(),
+            kind: ChannelKind::Text,
+        }],
+        ..Default::default()
+    }
+}
+
+#[test]
+fn shutdown_closes_clients_and_cleans_up() {
+    let shutdowns = Arc::new(AtomicUsize::new(0));
+    let test = TestServer::new("shutdown", config());
+    test.server
+        .add_plugin(Box::new(Recorder(shutdowns.clone())));
+    let (_, token) = test
+        .server
+        .create_bot("tester", vec![Permission::SendMessages])
+        .unwrap();
+    let mut test = test.start();
+
+    let (mut c, _) = TestClient::login(test.port, &token);
+    c.send_json(&json!({
+        "type": "send_message",
+        "params": { "channel_id": "general", "contents": "last words" },
+    }));
+    c.recv_type("message_create");
+
+    test.server.shutdown(Some(ServerMessage::TempMessage {
+        message: "Going down".to_string(),
+    }));
+    assert_eq!(
+        c.recv_type("temp_message")["params"]["message"],
+        "Going down"
+    );
+    assert_eq!(c.recv_close().close_code(), 1001);
+    c.send(0x88, &1001u16.to_be_bytes());
+    assert!(c.closed());
+
+    // Answering the close frame lets `run` return before the grace period ends
+    let start = Instant::now();
+    test.stop().unwrap();
+    assert!(start.elapsed() < Duration::from_secs(4));
+    assert_eq!(shutdowns.load(Ordering::SeqCst), 1);
+
+    // Everything is on disk for the next start
+    let db = Database::open(test.root.path().join("main.db")).unwrap();
+    let messages = db.get_messages_after_id(0).unwrap();
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].contents, "last words");
+}
+
+#[test]
+fn shutdown_disconnects_clients_that_never_answer() {
+    let test = TestServer::new("shutdown-silent", config());
+    let (_, token) = test.server.create_bot("silent", Vec::new()).unwrap();
+    let mut test = test.start();
+    let (mut c, _) = TestClient::login(test.port, &token);
+
+    test.server.shutdown(None);
+    assert_eq!(c.recv_close().close_code(), 1001);
+
+    // The close frame is never answered, the grace period runs out instead
+    test.stop().unwrap();
+    assert!(c.closed());
+}
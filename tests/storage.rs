@@ -0,0 +1,317 @@
+//! Isolation of plugin tables by the authorizers of plugin connections.
+
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use voxa_server::utils::{database::Database, storage::table_prefix};
+
+/// A fresh database in the temp directory, removed on drop
+struct TempDb {
+    path: PathBuf,
+    db: Database,
+}
+
+impl TempDb {
+    fn new(name: &str) -> Self {
+        let path =
+            std::env::temp_dir().join(format!("voxa-storage-{name}-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let db = Database::open(&path).unwrap();
+        Self { path, db }
+    }
+
+    /// A connection restricted to the tables of `plugin`
+    fn plugin(&self, plugin: &str) -> Database {
+        self.db.restricted(&table_prefix(plugin)).unwrap()
+    }
+}
+
+impl Drop for TempDb {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn table(plugin: &str, name: &str) -> String {
+    format!("{}{name}", table_prefix(plugin))
+}
+
+fn create(db: &Database, table: &str) -> rusqlite::Result<usize> {
+    db.execute_json(
+        &format!("CREATE TABLE {table} (id INTEGER PRIMARY KEY AUTOINCREMENT, value TEXT)"),
+        &[],
+    )
+}
+
+fn count(db: &Database, table: &str) -> rusqlite::Result<usize> {
+    db.query_json(&format!("SELECT * FROM {table}"), &[], 100)
+        .map(|rows| rows.len())
+}
+
+#[test]
+fn plugins_use_their_own_tables() {
+    let temp = TempDb::new("own");
+    let a = temp.plugin("a");
+    let notes = table("a", "notes");
+
+    create(&a, &notes).unwrap();
+    a.execute_json(
+        &format!("INSERT INTO {notes} (value) VALUES (?1)"),
+        &["hi".into()],
+    )
+    .unwrap();
+    assert_eq!(count(&a, &notes).unwrap(), 1);
+    a.execute_json(&format!("DROP TABLE {notes}"), &[]).unwrap();
+
+    // Dropping cleaned up its own sequence without opening the others
+    assert!(a.execute_json("DELETE FROM sqlite_sequence", &[]).is_err());
+    create(&a, &notes).unwrap();
+    a.execute_json(&format!("DROP TABLE {notes}"), &[]).unwrap();
+    assert!(
+        a.execute_json("DELETE FROM sqlite_sequence WHERE name = 'chat'", &[])
+            .is_err()
+    );
+}
+
+#[test]
+fn plugins_cant_use_tables_of_other_plugins() {
+    let temp = TempDb::new("other");
+    let b = temp.plugin("b");
+    let notes = table("b", "notes");
+    create(&b, &notes).unwrap();
+
+    let a = temp.plugin("a");
+    assert!(count(&a, &notes).is_err());
+    assert!(
+        a.execute_json(&format!("INSERT INTO {notes} (value) VALUES ('x')"), &[])
+            .is_err()
+    );
+    assert!(a.execute_json(&format!("DROP TABLE {notes}"), &[]).is_err());
+    assert!(create(&a, &table("b", "other")).is_err());
+}
+
+#[test]
+fn names_that_are_prefixes_of_others_are_isolated() {
+    let temp = TempDb::new("prefix");
+    let a_b = temp.plugin("a_b");
+    let tables = table("a_b", "x");
+    create(&a_b, &tables).unwrap();
+
+    // `a`'s prefix is not a prefix of `a_b`'s
+    assert!(!table_prefix("a_b").starts_with(&table_prefix("a")));
+    let a = temp.plugin("a");
+    assert!(count(&a, &tables).is_err());
+    assert!(
+        a.execute_json(&format!("DROP TABLE {tables}"), &[])
+            .is_err()
+    );
+}
+
+#[test]
+fn names_differing_in_punctuation_get_distinct_prefixes() {
+    assert_ne!(table_prefix("foo-bar"), table_prefix("foo_bar"));
+    assert_ne!(table_prefix("Foo"), table_prefix("foo"));
+
+    let temp = TempDb::new("punctuation");
+    create(&temp.plugin("foo-bar"), &table("foo-bar", "x")).unwrap();
+    assert!(count(&temp.plugin("foo_bar"), &table("foo-bar", "x")).is_err());
+}
+
+#[test]
+fn plugins_cant_use_server_tables() {
+    let temp = TempDb::new("server");
+    let a = temp.plugin("a");
+
+    for table in [
+        "chat",
+        "bots",
+        "plugin_kv",
+        "plugin_migrations",
+        "webhook_queue",
+    ] {
+        assert!(count(&a, table).is_err(), "{table} is readable");
+        assert!(
+            a.execute_json(&format!("DELETE FROM {table}"), &[])
+                .is_err(),
+            "{table} is writable"
+        );
+    }
+    assert!(
+        a.execute_json("UPDATE sqlite_sequence SET seq = 0", &[])
+            .is_err()
+    );
+    assert!(
+        a.execute_json("ATTACH DATABASE ':memory:' AS other", &[])
+            .is_err()
+    );
+}
+
+#[test]
+fn migrations_are_tracked_outside_plugin_tables() {
+    let temp = TempDb::new("migrations");
+    let a = temp.plugin("a");
+
+    // A plugin table named `migrations` doesn't clash with the bookkeeping
+    let migrations = [
+        format!("CREATE TABLE {} (version TEXT)", table("a", "migrations")),
+        format!(
+            "INSERT INTO {} (version) VALUES ('mine')",
+            table("a", "migrations")
+        ),
+    ];
+    assert_eq!(a.migrate("a", &migrations).unwrap(), 2);
+    assert_eq!(a.migrate("a", &migrations).unwrap(), 0);
+    assert_eq!(count(&a, &table("a", "migrations")).unwrap(), 1);
+
+    // Each plugin has its own list
+    let b = temp.plugin("b");
+    let more = [format!("CREATE TABLE {} (x)", table("b", "t"))];
+    assert_eq!(b.migrate("b", &more).unwrap(), 1);
+
+    // The bookkeeping stays out of reach afterwards
+    assert!(count(&a, "plugin_migrations").is_err());
+}
+
+#[test]
+fn failed_migrations_are_not_recorded() {
+    let temp = TempDb::new("failed");
+    let a = temp.plugin("a");
+
+    let migrations = [
+        format!("CREATE TABLE {} (x)", table("a", "t")),
+        "CREATE TABLE chat_copy (x)".to_string(),
+    ];
+    assert!(a.migrate("a", &migrations).is_err());
+
+    let fixed = [
+        migrations[0].clone(),
+        format!("CREATE TABLE {} (x)", table("a", "u")),
+    ];
+    assert_eq!(a.migrate("a", &fixed).unwrap(), 1);
+}
+
+#[test]
+fn read_only_connections_read_chat_and_their_own_tables() {
+    let temp = TempDb::new("read-only");
+    temp.db
+        .insert_message("general", "alice", "hi", 0, false)
+        .unwrap();
+    create(&temp.plugin("a"), &table("a", "notes")).unwrap();
+    create(&temp.plugin("b"), &table("b", "notes")).unwrap();
+
+    let a = temp.db.read_only(&table_prefix("a")).unwrap();
+    assert_eq!(count(&a, "chat").unwrap(), 1);
+    assert_eq!(count(&a, &table("a", "notes")).unwrap(), 0);
+    assert!(count(&a, &table("b", "notes")).is_err());
+    for table in ["bots", "plugin_kv", "webhook_queue", "sqlite_master"] {
+        assert!(count(&a, table).is_err(), "{table} is readable");
+    }
+    assert!(
+        a.query_json(
+            &format!("INSERT INTO {} (value) VALUES ('x')", table("a", "notes")),
+            &[],
+            1
+        )
+        .is_err()
+    );
+}
+
+#[test]
+fn queries_stop_at_the_deadline() {
+    let temp = TempDb::new("deadline");
+    let db = temp.db.read_only(&table_prefix("a")).unwrap();
+    let endless = "WITH RECURSIVE n(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM n) \
+                   SELECT count(*) FROM n";
+
+    db.set_deadline(Some(Instant::now() + Duration::from_millis(50)));
+    let start = Instant::now();
+    assert!(db.query_json(endless, &[], 1).is_err());
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    // Lifting the deadline lets queries run again
+    db.set_deadline(None);
+    assert_eq!(db.query_json("SELECT 1 AS one", &[], 1).unwrap().len(), 1);
+}
+
+#[test]
+fn plugins_cant_read_the_schema() {
+    let temp = TempDb::new("schema");
+    create(&temp.plugin("b"), &table("b", "secrets")).unwrap();
+    let a = temp.plugin("a");
+
+    for schema in ["sqlite_master", "sqlite_schema", "sqlite_temp_master"] {
+        assert!(count(&a, schema).is_err(), "{schema} is readable");
+    }
+    assert!(count(&a, "pragma_table_info('chat')").is_err());
+    assert!(
+        a.execute_json(
+            &format!(
+                "CREATE TABLE {} AS SELECT sql FROM sqlite_master",
+                table("a", "copy")
+            ),
+            &[]
+        )
+        .is_err()
+    );
+
+    // Changing its own tables still works
+    let notes = table("a", "notes");
+    create(&a, &notes).unwrap();
+    a.execute_json(
+        &format!("CREATE INDEX {notes}_value ON {notes} (value)"),
+        &[],
+    )
+    .unwrap();
+    a.execute_json(&format!("DROP INDEX {notes}_value"), &[])
+        .unwrap();
+    assert!(count(&a, "sqlite_master").is_err());
+}
+
+#[test]
+fn plugins_rename_tables_within_their_prefix_only() {
+    let temp = TempDb::new("rename");
+    let a = temp.plugin("a");
+    let notes = table("a", "notes");
+    create(&a, &notes).unwrap();
+
+    for alter in [
+        "ADD COLUMN extra TEXT",
+        "RENAME COLUMN extra TO other",
+        "DROP COLUMN other",
+    ] {
+        a.execute_json(&format!("ALTER TABLE {notes} {alter}"), &[])
+            .unwrap();
+    }
+
+    let renamed = table("a", "renamed");
+    a.execute_json(&format!("ALTER TABLE {notes} RENAME TO {renamed}"), &[])
+        .unwrap();
+    assert_eq!(count(&a, &renamed).unwrap(), 0);
+
+    // Out of the prefix, the rename is undone
+    for target in ["chat_copy", "\"chat copy\"", &table("b", "notes")] {
+        let err = a
+            .execute_json(&format!("ALTER TABLE {renamed} RENAME TO {target}"), &[])
+            .unwrap_err();
+        assert!(err.to_string().contains("can only be renamed"), "{err}");
+        assert_eq!(count(&a, &renamed).unwrap(), 0);
+    }
+
+    // Also inside migrations, where it rolls back the whole migration
+    let migrations = [format!(
+        "INSERT INTO {renamed} (value) VALUES ('x'); ALTER TABLE {renamed} RENAME TO escaped"
+    )];
+    assert!(a.migrate("a", &migrations).is_err());
+    assert_eq!(count(&a, &renamed).unwrap(), 0);
+    let reads = temp
+        .db
+        .query_json(
+            "SELECT name FROM sqlite_master WHERE name = 'escaped'",
+            &[],
+            1,
+        )
+        .unwrap();
+    assert!(reads.is_empty());
+}
@@ -0,0 +1,197 @@
+//! Reloading the TLS certificate while the server runs.
+#![cfg(feature = "tls")]
+
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use rustls::{
+    ClientConfig, ClientConnection, DigitallySignedStruct, SignatureScheme, StreamOwned,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    pki_types::{CertificateDer, ServerName, UnixTime, pem::PemObject},
+};
+use voxa_server::utils::tls::{TlsAcceptor, TlsConfig};
+
+const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tls");
+
+/// Accepts whatever certificate the server presents, the test checks it afterwards
+#[derive(Debug)]
+struct AcceptAny;
+
+impl ServerCertVerifier for AcceptAny {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        rustls::crypto::ring::default_provider()
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// A server root in the temp directory, removed on drop
+struct TempRoot(PathBuf);
+
+impl TempRoot {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("voxa-tls-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    /// Install the fixture certificate `name` as `cert.pem`/`key.pem`, modified at `at`
+    fn install(&self, name: &str, at: SystemTime) {
+        for (from, to) in [
+            (format!("{name}.pem"), "cert.pem"),
+            (format!("{name}.key"), "key.pem"),
+        ] {
+            let path = self.0.join(to);
+            std::fs::copy(Path::new(FIXTURES).join(from), &path).unwrap();
+            std::fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(at)
+                .unwrap();
+        }
+    }
+}
+
+impl Drop for TempRoot {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+fn fixture_cert(name: &str) -> CertificateDer<'static> {
+    CertificateDer::from_pem_file(Path::new(FIXTURES).join(format!("{name}.pem"))).unwrap()
+}
+
+/// Accept TLS connections on a local port, echoing one byte per connection
+fn serve(acceptor: TlsAcceptor) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    std::thread::spawn(move || {
+        for tcp in listener.incoming() {
+            let Ok(tcp) = tcp else { continue };
+            let Ok(mut stream) = acceptor.accept(tcp) else {
+                continue;
+            };
+            std::thread::spawn(move || {
+                let mut byte = [0u8];
+                if stream.read_exact(&mut byte).is_ok() {
+                    let _ = stream.write_all(&byte);
+                }
+            });
+        }
+    });
+    port
+}
+
+/// The certificate the server on `port` presents
+fn served_cert(port: u16) -> CertificateDer<'static> {
+    let config =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AcceptAny))
+            .with_no_client_auth();
+    let conn = ClientConnection::new(Arc::new(config), "localhost".try_into().unwrap()).unwrap();
+    let tcp = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    tcp.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    let mut stream = StreamOwned::new(conn, tcp);
+    stream.write_all(b"x").unwrap();
+    let mut byte = [0u8];
+    stream.read_exact(&mut byte).unwrap();
+    assert_eq!(&byte, b"x");
+
+    stream.conn.peer_certificates().unwrap()[0]
+        .clone()
+        .into_owned()
+}
+
+#[test]
+fn serves_the_new_certificate_after_the_files_change() {
+    let root = TempRoot::new("reload");
+    let start = SystemTime::now() - Duration::from_secs(60);
+    root.install("first", start);
+
+    let acceptor = TlsAcceptor::new(
+        &root.0,
+        &TlsConfig {
+            cert_path: "cert.pem".into(),
+            key_path: "key.pem".into(),
+            reload_interval: 1,
+        },
+    )
+    .unwrap();
+    acceptor.watch(Duration::from_millis(20));
+    let port = serve(acceptor);
+    assert_eq!(served_cert(port), fixture_cert("first"));
+
+    root.install("second", start + Duration::from_secs(30));
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while served_cert(port) != fixture_cert("second") {
+        assert!(
+            Instant::now() < deadline,
+            "the new certificate is never served"
+        );
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn keeps_the_old_certificate_when_the_new_one_is_broken() {
+    let root = TempRoot::new("broken");
+    let start = SystemTime::now() - Duration::from_secs(60);
+    root.install("first", start);
+
+    let acceptor = TlsAcceptor::new(
+        &root.0,
+        &TlsConfig {
+            cert_path: "cert.pem".into(),
+            key_path: "key.pem".into(),
+            reload_interval: 1,
+        },
+    )
+    .unwrap();
+    acceptor.watch(Duration::from_millis(20));
+    let port = serve(acceptor);
+
+    std::fs::write(root.0.join("cert.pem"), "not a certificate").unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(served_cert(port), fixture_cert("first"));
+}
@@ -0,0 +1,256 @@
+//! Sandboxed WebAssembly plugins, written in WAT.
+#![cfg(feature = "wasm")]
+
+mod common;
+
+use common::{TestClient, TestServer};
+use serde_json::{Value, json};
+use voxa_server::{ServerConfig, types::data::Permission};
+
+const PAGE: usize = 64 * 1024;
+
+/// A module with `pages` pages of memory and a bump allocator above 8 KiB,
+/// its own data lives below
+fn module(pages: u32, imports: &str, items: &str) -> Vec<u8> {
+    wat::parse_str(format!(
+        r#"(module
+            {imports}
+            (memory (export "memory") {pages})
+            (global $next (mut i32) (i32.const 8192))
+            (func (export "voxa_alloc") (param $len i32) (result i32)
+                (global.get $next)
+                (global.set $next (i32.add (global.get $next) (local.get $len))))
+            {items})"#
+    ))
+    .unwrap()
+}
+
+/// A data segment holding `text` at `offset`
+fn data(offset: u32, text: &str) -> String {
+    format!(
+        r#"(data (i32.const {offset}) "{}")"#,
+        text.replace('"', "\\\"")
+    )
+}
+
+/// The result pointing to `text` at `offset`
+fn packed(offset: u32, text: &str) -> i64 {
+    ((offset as i64) << 32) | text.len() as i64
+}
+
+/// The error a plugin answers `message` with
+fn reply(message: &str) -> String {
+    json!({ "error": "invalid_request", "message": message }).to_string()
+}
+
+/// A plugin answering every message with the error `message`
+fn answers(message: &str) -> Vec<u8> {
+    let reply = reply(message);
+    module(
+        1,
+        "",
+        &format!(
+            r#"(func (export "voxa_on_plugin_message") (param i32 i32) (result i64)
+                (i64.const {}))
+            {}"#,
+            packed(0, &reply),
+            data(0, &reply)
+        ),
+    )
+}
+
+fn start(name: &str, mut config: ServerConfig, plugins: &[(&str, Vec<u8>)]) -> TestServer {
+    config.plugins.wasm.fuel = 1_000_000;
+    let test = TestServer::new(name, config);
+    let dir = test.root.path().join("plugins");
+    std::fs::create_dir_all(&dir).unwrap();
+    for (name, wasm) in plugins {
+        std::fs::write(dir.join(format!("{name}.wasm")), wasm).unwrap();
+    }
+    test.start()
+}
+
+fn login(test: &TestServer) -> TestClient {
+    let (_, token) = test
+        .server
+        .create_bot("tester", vec![Permission::PluginMessages])
+        .unwrap();
+    TestClient::login(test.port, &token).0
+}
+
+/// Send a plugin message, returns the error the client is answered with
+fn ask(c: &mut TestClient, plugin: &str) -> Value {
+    c.send_json(&json!({ "type": "plugin", "params": { "plugin": plugin } }));
+    c.recv_response()
+}
+
+#[test]
+fn running_out_of_fuel_only_fails_that_hook() {
+    let pong = reply("pong");
+    // Spins on the first message only, answers the next ones
+    let spin = module(
+        1,
+        "",
+        &format!(
+            r#"(global $spun (mut i32) (i32.const 0))
+            (func (export "voxa_on_plugin_message") (param i32 i32) (result i64)
+                (if (i32.eqz (global.get $spun))
+                    (then
+                        (global.set $spun (i32.const 1))
+                        (loop $forever (br $forever))))
+                (i64.const {}))
+            {}"#,
+            packed(0, &pong),
+            data(0, &pong)
+        ),
+    );
+    let test = start("wasm-fuel", ServerConfig::default(), &[("spin", spin)]);
+    let mut c = login(&test);
+
+    assert_eq!(ask(&mut c, "spin")["error"], "internal_error");
+    // The fuel is refilled for the next hook
+    assert_eq!(ask(&mut c, "spin")["message"], "pong");
+}
+
+#[test]
+fn memory_is_capped() {
+    let (denied, granted) = (reply("denied"), reply("granted"));
+    // Grows by 16 pages on the first message, then by 1
+    let grow = module(
+        1,
+        "",
+        &format!(
+            r#"(global $calls (mut i32) (i32.const 0))
+            (func (export "voxa_on_plugin_message") (param i32 i32) (result i64)
+                (local $pages i32)
+                (local.set $pages
+                    (select (i32.const 16) (i32.const 1) (i32.eqz (global.get $calls))))
+                (global.set $calls (i32.add (global.get $calls) (i32.const 1)))
+                (if (result i64) (i32.eq (memory.grow (local.get $pages)) (i32.const -1))
+                    (then (i64.const {}))
+                    (else (i64.const {}))))
+            {}
+            {}"#,
+            packed(0, &denied),
+            packed(256, &granted),
+            data(0, &denied),
+            data(256, &granted)
+        ),
+    );
+
+    let mut config = ServerConfig::default();
+    config.plugins.wasm.max_memory = 4 * PAGE;
+    let test = start(
+        "wasm-memory",
+        config,
+        &[("grow", grow), ("large", module(8, "", ""))],
+    );
+    let mut c = login(&test);
+
+    assert_eq!(ask(&mut c, "grow")["message"], "denied");
+    assert_eq!(ask(&mut c, "grow")["message"], "granted");
+
+    // A module starting with more memory than allowed isn't loaded
+    assert_eq!(ask(&mut c, "large")["error"], "not_found");
+}
+
+#[test]
+fn traps_only_fail_the_plugin_that_trapped() {
+    let trap = module(
+        1,
+        "",
+        r#"(func (export "voxa_on_plugin_message") (param i32 i32) (result i64)
+            unreachable)"#,
+    );
+    let test = start(
+        "wasm-trap",
+        ServerConfig::default(),
+        &[("trap", trap), ("pong", answers("pong"))],
+    );
+    let mut c = login(&test);
+
+    for _ in 0..5 {
+        let error = ask(&mut c, "trap");
+        assert_eq!(error["error"], "internal_error", "{error}");
+        assert_eq!(ask(&mut c, "pong")["message"], "pong");
+    }
+
+    // Traps are not panics, the plugin stays loaded
+    let names: Vec<_> = test
+        .server
+        .plugin_list()
+        .into_iter()
+        .map(|p| p.name)
+        .collect();
+    assert!(names.contains(&"trap".to_string()), "{names:?}");
+}
+
+/// A plugin broadcasting the result of `sql` as its `rows` event
+fn reader(sql: &str) -> Vec<u8> {
+    let prefix = r#"{"event":"rows","data":"#;
+    let query = json!({ "sql": sql }).to_string();
+    let out = 1024 + prefix.len();
+    module(
+        1,
+        r#"(import "voxa" "query" (func $query (param i32 i32) (result i64)))
+        (import "voxa" "broadcast" (func $broadcast (param i32 i32)))"#,
+        &format!(
+            r#"(func (export "voxa_on_plugin_message") (param i32 i32) (result i64)
+                (local $res i64)
+                (local $len i32)
+                (local.set $res (call $query (i32.const 256) (i32.const {query_len})))
+                (local.set $len (i32.wrap_i64 (local.get $res)))
+                (memory.copy (i32.const 1024) (i32.const 0) (i32.const {prefix_len}))
+                (memory.copy
+                    (i32.const {out})
+                    (i32.wrap_i64 (i64.shr_u (local.get $res) (i64.const 32)))
+                    (local.get $len))
+                (i32.store8 (i32.add (i32.const {out}) (local.get $len)) (i32.const 125))
+                (call $broadcast (i32.const 1024) (i32.add (local.get $len) (i32.const {total})))
+                (i64.const 0))
+            {}
+            {}"#,
+            data(0, prefix),
+            data(256, &query),
+            query_len = query.len(),
+            prefix_len = prefix.len(),
+            total = prefix.len() + 1,
+        ),
+    )
+}
+
+#[test]
+fn queries_read_chat_only() {
+    let test = TestServer::new("wasm-query", ServerConfig::default());
+    test.server
+        .db
+        .insert_message("general", "someone", "hello", 1, false)
+        .unwrap();
+    let dir = test.root.path().join("plugins");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("chat.wasm"),
+        reader("SELECT contents FROM chat ORDER BY id"),
+    )
+    .unwrap();
+    std::fs::write(dir.join("snoop.wasm"), reader("SELECT name FROM bots")).unwrap();
+    let test = test.start();
+    let mut c = login(&test);
+
+    c.send_json(&json!({ "type": "plugin", "params": { "plugin": "chat" } }));
+    let event = c.recv_type("plugin");
+    assert_eq!(event["params"]["plugin"], "chat");
+    assert_eq!(
+        event["params"]["data"],
+        json!({ "rows": [{ "contents": "hello" }] })
+    );
+
+    c.send_json(&json!({ "type": "plugin", "params": { "plugin": "snoop" } }));
+    let event = c.recv_type("plugin");
+    assert_eq!(event["params"]["plugin"], "snoop");
+    assert!(
+        event["params"]["data"]["error"].is_string(),
+        "{}",
+        event["params"]
+    );
+}
@@ -0,0 +1,284 @@
+//! Outgoing webhook deliveries against a local HTTP stand-in.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use voxa_server::utils::{
+    database::Database,
+    webhooks::{WebhookEvent, WebhookTarget, Webhooks, WebhooksConfig, sign},
+};
+
+const SECRET: &str = "hunter2";
+
+struct Request {
+    headers: HashMap<String, String>,
+    body: String,
+}
+
+/// An HTTP server answering with `statuses` in order, then 200
+struct StandIn {
+    url: String,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl StandIn {
+    fn start(statuses: &[u16]) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let mut statuses: VecDeque<u16> = statuses.iter().copied().collect();
+
+        std::thread::spawn({
+            let requests = requests.clone();
+            move || {
+                for stream in listener.incoming() {
+                    let mut stream = stream.unwrap();
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+                    let mut headers = HashMap::new();
+                    loop {
+                        let mut line = String::new();
+                        reader.read_line(&mut line).unwrap();
+                        let line = line.trim_end();
+                        if line.is_empty() {
+                            break;
+                        }
+                        if let Some((k, v)) = line.split_once(':') {
+                            headers.insert(k.trim().to_lowercase(), v.trim().to_string());
+                        }
+                    }
+
+                    let len = headers["content-length"].parse().unwrap();
+                    let mut body = vec![0; len];
+                    reader.read_exact(&mut body).unwrap();
+                    requests.lock().unwrap().push(Request {
+                        headers,
+                        body: String::from_utf8(body).unwrap(),
+                    });
+
+                    let status = statuses.pop_front().unwrap_or(200);
+                    let _ = write!(
+                        stream,
+                        "HTTP/1.1 {status} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    );
+                }
+            }
+        });
+
+        Self { url, requests }
+    }
+
+    fn count(&self) -> usize {
+        self.requests.lock().unwrap().len()
+    }
+}
+
+/// A fresh database in the temp directory, removed on drop
+struct TempDb {
+    path: PathBuf,
+    db: Database,
+}
+
+impl TempDb {
+    fn new(name: &str) -> Self {
+        let path =
+            std::env::temp_dir().join(format!("voxa-webhooks-{name}-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let db = Database::open(&path).unwrap();
+        Self { path, db }
+    }
+}
+
+impl Drop for TempDb {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn webhooks(url: &str, events: Vec<WebhookEvent>) -> Webhooks {
+    Webhooks::new(WebhooksConfig {
+        targets: vec![WebhookTarget {
+            url: url.to_string(),
+            secret: SECRET.to_string(),
+            events,
+        }],
+        max_attempts: 3,
+        retry_delay: 5,
+        max_retry_delay: 60,
+        timeout: 5,
+    })
+}
+
+#[test]
+fn delivers_signed_payload() {
+    let stand_in = StandIn::start(&[]);
+    let temp = TempDb::new("signed");
+    let hooks = webhooks(&stand_in.url, Vec::new());
+
+    let data = serde_json::json!({ "user_id": "alice", "bot": false });
+    hooks
+        .emit(&temp.db, WebhookEvent::MemberJoin, &data, 1_000_000)
+        .unwrap();
+    assert_eq!(hooks.deliver_due(&temp.db, 1_000_000).unwrap(), 1);
+    assert_eq!(temp.db.webhook_queue_len().unwrap(), 0);
+
+    let requests = stand_in.requests.lock().unwrap();
+    let req = &requests[0];
+    assert_eq!(req.headers["x-voxa-event"], "member_join");
+    assert_eq!(req.headers["content-type"], "application/json");
+    assert_eq!(
+        req.headers["x-voxa-signature"],
+        format!("sha256={}", sign(SECRET, req.body.as_bytes()))
+    );
+
+    // Checked independently of `sign`
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, SECRET.as_bytes());
+    let hex = req.headers["x-voxa-signature"]
+        .strip_prefix("sha256=")
+        .unwrap();
+    let tag: Vec<u8> = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect();
+    ring::hmac::verify(&key, req.body.as_bytes(), &tag).unwrap();
+
+    let body: serde_json::Value = serde_json::from_str(&req.body).unwrap();
+    assert_eq!(body["event"], "member_join");
+    assert_eq!(body["timestamp"], 1000);
+    assert_eq!(body["data"], data);
+}
+
+#[test]
+fn filters_events() {
+    let stand_in = StandIn::start(&[]);
+    let temp = TempDb::new("filter");
+    let hooks = webhooks(&stand_in.url, vec![WebhookEvent::MessageCreate]);
+
+    hooks
+        .emit(&temp.db, WebhookEvent::MessageDelete, &(), 0)
+        .unwrap();
+    assert_eq!(temp.db.webhook_queue_len().unwrap(), 0);
+
+    hooks
+        .emit(&temp.db, WebhookEvent::MessageCreate, &(), 0)
+        .unwrap();
+    assert_eq!(hooks.deliver_due(&temp.db, 0).unwrap(), 1);
+    assert_eq!(stand_in.count(), 1);
+}
+
+#[test]
+fn retries_with_backoff() {
+    let stand_in = StandIn::start(&[500, 503]);
+    let temp = TempDb::new("retry");
+    let hooks = webhooks(&stand_in.url, Vec::new());
+
+    hooks
+        .emit(&temp.db, WebhookEvent::MessageCreate, &"hi", 0)
+        .unwrap();
+
+    // First attempt fails, the retry is due 5s later
+    assert_eq!(hooks.deliver_due(&temp.db, 0).unwrap(), 0);
+    assert_eq!(hooks.deliver_due(&temp.db, 4_999).unwrap(), 0);
+    assert_eq!(stand_in.count(), 1);
+
+    // Second attempt fails, the delay doubles
+    assert_eq!(hooks.deliver_due(&temp.db, 5_000).unwrap(), 0);
+    assert_eq!(stand_in.count(), 2);
+    assert_eq!(hooks.deliver_due(&temp.db, 14_999).unwrap(), 0);
+    assert_eq!(stand_in.count(), 2);
+
+    // The queue survives reopening the database
+    let hooks = webhooks(&stand_in.url, Vec::new());
+    let db = Database::open(&temp.path).unwrap();
+    assert_eq!(hooks.deliver_due(&db, 15_000).unwrap(), 1);
+    assert_eq!(stand_in.count(), 3);
+    assert_eq!(db.webhook_queue_len().unwrap(), 0);
+
+    // Every attempt carries the same signed body
+    let requests = stand_in.requests.lock().unwrap();
+    assert!(requests.iter().all(|r| r.body == requests[0].body));
+}
+
+#[test]
+fn backoff_is_capped() {
+    let hooks = webhooks("http://127.0.0.1:9/hook", Vec::new());
+    assert_eq!(hooks.retry_delay(1).as_secs(), 5);
+    assert_eq!(hooks.retry_delay(2).as_secs(), 10);
+    assert_eq!(hooks.retry_delay(4).as_secs(), 40);
+    assert_eq!(hooks.retry_delay(5).as_secs(), 60);
+    assert_eq!(hooks.retry_delay(100).as_secs(), 60);
+}
+
+#[test]
+fn drops_after_max_attempts() {
+    let stand_in = StandIn::start(&[500, 500, 500, 500]);
+    let temp = TempDb::new("drop");
+    let hooks = webhooks(&stand_in.url, Vec::new());
+
+    hooks
+        .emit(&temp.db, WebhookEvent::MessageUpdate, &"edit", 0)
+        .unwrap();
+    for now in [0, 5_000, 15_000] {
+        assert_eq!(hooks.deliver_due(&temp.db, now).unwrap(), 0);
+    }
+    assert_eq!(stand_in.count(), 3);
+    assert_eq!(temp.db.webhook_queue_len().unwrap(), 0);
+}
+
+#[test]
+fn drops_deliveries_of_removed_targets() {
+    let temp = TempDb::new("removed");
+    let old = webhooks("http://127.0.0.1:9/old", Vec::new());
+    old.emit(&temp.db, WebhookEvent::MemberLeave, &(), 0)
+        .unwrap();
+
+    let stand_in = StandIn::start(&[]);
+    let hooks = webhooks(&stand_in.url, Vec::new());
+    assert_eq!(hooks.deliver_due(&temp.db, 0).unwrap(), 0);
+    assert_eq!(temp.db.webhook_queue_len().unwrap(), 0);
+    assert_eq!(stand_in.count(), 0);
+}
+
+#[test]
+fn signs_with_the_secret_of_each_target() {
+    let stand_in = StandIn::start(&[]);
+    let temp = TempDb::new("secrets");
+    let target = |secret: &str, events| WebhookTarget {
+        url: stand_in.url.clone(),
+        secret: secret.to_string(),
+        events,
+    };
+    let hooks = Webhooks::new(WebhooksConfig {
+        targets: vec![
+            target("first", vec![WebhookEvent::MemberJoin]),
+            target("second", Vec::new()),
+        ],
+        ..WebhooksConfig::default()
+    });
+
+    hooks
+        .emit(&temp.db, WebhookEvent::MemberJoin, &"join", 0)
+        .unwrap();
+    hooks
+        .emit(&temp.db, WebhookEvent::MemberLeave, &"leave", 0)
+        .unwrap();
+    assert_eq!(hooks.deliver_due(&temp.db, 0).unwrap(), 3);
+
+    let requests = stand_in.requests.lock().unwrap();
+    let signed_with = |secret: &str| {
+        requests
+            .iter()
+            .filter(|r| {
+                r.headers["x-voxa-signature"]
+                    == format!("sha256={}", sign(secret, r.body.as_bytes()))
+            })
+            .count()
+    };
+    assert_eq!(signed_with("first"), 1);
+    assert_eq!(signed_with("second"), 2);
+}